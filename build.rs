@@ -31,7 +31,7 @@ fn main() {
                 let path = format!("{}/{}", manifest_dir, path);
                 contents.push_str(&format!("let data = include_bytes!({:?});\n", path));
                 contents.push_str(&format!(
-                    "set_binary(system, cpu, {:?}, {:?},Some(data));\n",
+                    "set_binary(system, &mut cpu.cpu, {:?}, {:?},Some(data));\n",
                     key, path
                 ))
             }
@@ -1,7 +1,8 @@
 use crate::gtia;
+use crate::state::{SaveState, StateReader, StateWriter};
 use crate::system::AtariSystem;
 use bevy_atari_antic::CollisionsData;
-use bevy_atari_antic::{AnticData, ModeLineDescr};
+use bevy_atari_antic::{AnticData, GTIARegs, ModeLineDescr};
 use emulator_6502::{Interface6502, MOS6502};
 
 mod consts {
@@ -15,17 +16,16 @@ mod consts {
     pub const CHBASE: usize = 0x09;
     pub const WSYNC: usize = 0x0A;
     pub const VCOUNT: usize = 0x0B;
+    pub const PENH: usize = 0x0C;
+    pub const PENV: usize = 0x0D;
     pub const NMIEN: usize = 0x0E;
     pub const NMIST: usize = 0x0f;
     pub const NMIRES: usize = 0x0f;
 }
 
-#[allow(dead_code)]
 const PAL_SCAN_LINES: usize = 312;
-#[allow(dead_code)]
 const NTSC_SCAN_LINES: usize = 262;
 
-pub const MAX_SCAN_LINES: usize = PAL_SCAN_LINES;
 pub const SCAN_LINE_CYCLES: usize = 114;
 bitflags! {
     #[derive(Default)]
@@ -71,6 +71,113 @@ bitflags! {
     }
 }
 
+/// Consumer of display data produced by ANTIC during the frame.
+///
+/// `AnticData` feeds the GPU renderer, other implementations allow running
+/// the emulation without any rendering backend.
+pub trait AnticDisplay {
+    /// called on the first visible scanline of the frame
+    fn clear(&mut self);
+    /// GTIA registers latched for given visible scanline (0..240)
+    fn set_gtia_regs(&mut self, index: usize, regs: &GTIARegs);
+    /// GTIA registers changed later on the scanline, from color clock `hpos`
+    fn change_gtia_regs(&mut self, _index: usize, _hpos: usize, _regs: &GTIARegs) {}
+    /// whether `change_gtia_regs` is used, GTIA writes are not recorded otherwise
+    fn gtia_changes(&self) -> bool {
+        false
+    }
+    /// reserves `len` bytes of ANTIC memory filled by `f`, returns its offset
+    fn reserve_antic_memory(&mut self, len: usize, f: &mut dyn FnMut(&mut [u8])) -> usize;
+    fn insert_mode_line(&mut self, mode_line: &ModeLineDescr);
+    fn collisions_data(&self) -> Option<&CollisionsData> {
+        None
+    }
+}
+
+impl AnticDisplay for AnticData {
+    #[inline(always)]
+    fn clear(&mut self) {
+        AnticData::clear(self)
+    }
+    #[inline(always)]
+    fn set_gtia_regs(&mut self, index: usize, regs: &GTIARegs) {
+        AnticData::set_gtia_regs(self, index, regs)
+    }
+    #[inline(always)]
+    fn reserve_antic_memory(&mut self, len: usize, f: &mut dyn FnMut(&mut [u8])) -> usize {
+        AnticData::reserve_antic_memory(self, len, &mut |data: &mut [u8]| f(data))
+    }
+    #[inline(always)]
+    fn insert_mode_line(&mut self, mode_line: &ModeLineDescr) {
+        AnticData::insert_mode_line(self, mode_line)
+    }
+    #[inline(always)]
+    fn collisions_data(&self) -> Option<&CollisionsData> {
+        self.collisions_data.as_ref()
+    }
+}
+
+/// Display discarding all data, for running without any video output.
+#[derive(Default)]
+pub struct NullDisplay;
+
+impl AnticDisplay for NullDisplay {
+    fn clear(&mut self) {}
+    fn set_gtia_regs(&mut self, _index: usize, _regs: &GTIARegs) {}
+    fn reserve_antic_memory(&mut self, _len: usize, _f: &mut dyn FnMut(&mut [u8])) -> usize {
+        0
+    }
+    fn insert_mode_line(&mut self, _mode_line: &ModeLineDescr) {}
+}
+
+/// Charsets already passed to the display in the current frame.
+///
+/// Mode lines using unchanged charset reuse its display memory, any write
+/// to a page of cached charset or memory bank switch invalidates the cache.
+#[derive(Default)]
+pub struct CharsetCache {
+    // (address, size, offset in display memory)
+    entries: Vec<(usize, usize, usize)>,
+    // bitmap of pages covered by entries
+    pages: [u64; 4],
+}
+
+impl CharsetCache {
+    pub fn get(&self, addr: usize, size: usize) -> Option<usize> {
+        self.entries
+            .iter()
+            .find(|(a, s, _)| (*a, *s) == (addr, size))
+            .map(|(_, _, offset)| *offset)
+    }
+
+    pub fn insert(&mut self, addr: usize, size: usize, offset: usize) {
+        if size == 0 {
+            return;
+        }
+        for page in addr >> 8..=(addr + size - 1) >> 8 {
+            // charset at the end of memory wraps to page 0, as in `antic_copy_to_slice`
+            let page = page & 0xff;
+            self.pages[page >> 6] |= 1 << (page & 63);
+        }
+        self.entries.push((addr, size, offset));
+    }
+
+    pub fn clear(&mut self) {
+        if !self.entries.is_empty() {
+            self.entries.clear();
+            self.pages = [0; 4];
+        }
+    }
+
+    #[inline(always)]
+    pub fn write(&mut self, addr: usize) {
+        let page = addr >> 8;
+        if self.pages[page >> 6] & (1 << (page & 63)) > 0 {
+            self.clear();
+        }
+    }
+}
+
 #[derive(Default)]
 pub struct Antic {
     ir: u8,
@@ -88,17 +195,25 @@ pub struct Antic {
     pub vscrol: u8,
     pub pmbase: u8,
     pub dlist: u16,
-    nmireq: bool,
+    // status of NMI raised on the current scanline
+    nmi_pending: NMIST,
+    nmi_asserted: bool,
     pub cycle: usize,
     pub total_cycles: u64,
     visible_cycle: usize,
-    dma_cycles: usize,
+    // bit n set if cycle n of the current scanline is used by ANTIC DMA
+    dma_slots: u128,
     pub scan_line: usize,
     pub vcount: u8,
     pub video_memory: usize,
     wsync: bool,
     is_visible: bool,
     is_vscroll: bool,
+    pub ntsc: bool,
+    /// light pen position (color clock, scanline), latched when the beam passes it
+    pub light_pen: Option<(u8, usize)>,
+    penh: u8,
+    penv: u8,
 }
 
 #[derive(Default, Debug, Copy, Clone)]
@@ -174,54 +289,26 @@ const ANTIC_MODES: [AnticModeDescr; 16] = [
     },
 ];
 
-const MODE_25_STEALED_CYCLES_FIRST_LINE: [(usize, &[usize; 8]); 4] = [
-    (29, &[9, 9, 9, 9, 9, 9, 9, 9]),
-    (25, &[66, 66, 66, 66, 66, 66, 66, 66]),
-    (18 - 2, &[81, 81, 81, 81, 81, 81, 82, 81]), // TODO investigate this -2 correction required for last squadron
-    (10, &[96, 95, 94, 93, 92, 91, 90, 89]),
-];
-
-const MODE_25_STEALED_CYCLES: [(usize, &[usize; 8]); 4] = [
-    (29, &[9, 9, 9, 9, 9, 9, 9, 9]),
-    (29, &[41, 41, 41, 41, 41, 41, 41, 41]),
-    (21, &[49, 49, 49, 49, 49, 49, 49, 48]),
-    (13, &[56, 55, 55, 54, 54, 53, 53, 53]),
-];
-
-const MODE_67_STEALED_CYCLES_FIRST_LINE: [(usize, &[usize; 8]); 4] = [
-    (29, &[9, 9, 9, 9, 9, 9, 9, 9]),
-    (25, &[41, 41, 41, 41, 41, 41, 41, 41]),
-    (18, &[49, 49, 49, 49, 49, 49, 49, 48]),
-    (10, &[57, 56, 56, 56, 55, 54, 54, 54]),
-];
-
-const MODE_67_STEALED_CYCLES: [(usize, &[usize; 8]); 4] = [
-    (29, &[9, 9, 9, 9, 9, 9, 9, 9]),
-    (29, &[25, 25, 25, 25, 25, 25, 25, 25]),
-    (21, &[29, 29, 29, 29, 29, 29, 29, 29]),
-    (13, &[33, 32, 32, 32, 32, 31, 31, 31]),
-];
-
-const MODE_89_STEALED_CYCLES: [(usize, &[usize; 8]); 4] = [
-    (29, &[9, 9, 9, 9, 9, 9, 9, 9]),
-    (29, &[17, 17, 17, 17, 17, 17, 17, 17]),
-    (21, &[19, 19, 19, 19, 19, 19, 19, 19]),
-    (13, &[21, 21, 21, 21, 21, 21, 20, 20]),
-];
-
-const MODE_AC_STEALED_CYCLES: [(usize, &[usize; 8]); 4] = [
-    (29, &[9, 9, 9, 9, 9, 9, 9, 9]),
-    (29, &[25, 25, 25, 25, 25, 25, 25, 25]),
-    (21, &[29, 29, 29, 29, 29, 29, 29, 29]),
-    (13, &[33, 33, 32, 32, 32, 32, 31, 31]),
-];
-
-const MODE_DF_STEALED_CYCLES: [(usize, &[usize; 8]); 4] = [
-    (29, &[9, 9, 9, 9, 9, 9, 9, 9]),
-    (29, &[41, 41, 41, 41, 41, 41, 41, 41]),
-    (21, &[49, 49, 49, 49, 49, 49, 49, 49]),
-    (13, &[56, 56, 55, 55, 54, 54, 53, 53]),
-];
+// ANTIC DMA cycles of the scanline
+const MISSILE_DMA_CYCLE: usize = 0;
+const DLIST_DMA_CYCLE: usize = 1;
+const PLAYER_DMA_CYCLES: std::ops::Range<usize> = 2..6;
+// LMS / JMP address
+const DLIST_ADDR_DMA_CYCLES: std::ops::Range<usize> = 6..8;
+// nine memory refresh cycles, each one delayed by playfield DMA at most
+// until the next one is due
+const REFRESH_FIRST_CYCLE: usize = 25;
+const REFRESH_PERIOD: usize = 4;
+const REFRESH_COUNT: usize = 9;
+// playfield DMA window (first cycle, length) by playfield width index
+const PLAYFIELD_DMA: [(usize, usize); 4] = [(18, 0), (26, 64), (18, 80), (10, 96)];
+// playfield DMA shifted by HSCROL can't go past this cycle
+const PLAYFIELD_DMA_END: usize = 106;
+/// cycle when NMIST is updated, NMI is asserted on the next one
+const NMIST_CYCLE: usize = 7;
+const NMI_CYCLE: usize = 8;
+/// first cycle CPU may run after WSYNC write
+pub const WSYNC_RELEASE_CYCLE: usize = 105;
 
 impl Antic {
     #[inline]
@@ -244,20 +331,40 @@ impl Antic {
         self.total_cycles = self.total_cycles.wrapping_add(value);
     }
 
+    #[inline(always)]
+    pub fn scan_lines(&self) -> usize {
+        if self.ntsc {
+            NTSC_SCAN_LINES
+        } else {
+            PAL_SCAN_LINES
+        }
+    }
+
     #[inline(always)]
     pub fn inc_cycle(&mut self) {
         self.inc_total_cycles(1);
         self.cycle = (self.cycle + 1) % SCAN_LINE_CYCLES;
         if self.cycle == 0 {
-            self.scan_line = (self.scan_line + 1) % MAX_SCAN_LINES;
+            if let Some((hpos, scan_line)) = self.light_pen {
+                if scan_line == self.scan_line {
+                    self.penh = hpos;
+                    self.penv = (scan_line / 2) as u8;
+                }
+            }
+            self.scan_line = self.get_next_scanline();
             self.vcount = (self.scan_line / 2) as u8;
         } else if self.cycle >= 110 {
-            self.vcount = (((self.scan_line + 1) % MAX_SCAN_LINES) / 2) as u8;
+            self.vcount = (self.get_next_scanline() / 2) as u8;
         }
     }
 
     pub fn get_next_scanline(&self) -> usize {
-        return (self.scan_line + 1) % MAX_SCAN_LINES;
+        // state saved in PAL mode may be past the last NTSC scanline
+        if self.scan_line + 1 >= self.scan_lines() {
+            0
+        } else {
+            self.scan_line + 1
+        }
     }
 
     fn playfield_width_index(&self, hscroll: bool) -> usize {
@@ -294,16 +401,9 @@ impl Antic {
             _ => 0,
         }
     }
-    #[inline(always)]
-    pub fn set_vbi(&mut self) {
-        self.nmist.insert(NMIST::VBI);
-        self.nmist.remove(NMIST::DLI);
-    }
-
-    #[inline(always)]
-    pub fn set_dli(&mut self) {
-        self.nmist.insert(NMIST::DLI);
-        self.nmist.remove(NMIST::VBI);
+    /// RESET key of 400/800, handled by OS as NMI
+    pub fn press_reset_key(&mut self) {
+        self.nmi_pending = NMIST::SYSTEM_RESET;
     }
 
     #[inline(always)]
@@ -331,32 +431,40 @@ impl Antic {
         ret
     }
 
+    /// Called on cycle 0 of scanline, NMI is raised later on the line
     #[inline(always)]
     pub fn check_nmi(&mut self) {
-        self.nmireq |= self.is_vbi() || self.is_dli()
+        if self.is_vbi() {
+            self.nmi_pending = NMIST::VBI;
+        } else if self.is_dli() {
+            self.nmi_pending = NMIST::DLI;
+        }
     }
 
+    /// Sets NMIST on NMIST_CYCLE, returns true if NMI is asserted to CPU
     #[inline(always)]
     pub fn fire_nmi(&mut self) -> bool {
-        if self.nmireq && self.cycle >= 5 {
-            self.nmireq = false;
-            if self.is_vbi() {
-                self.set_vbi();
-                self.nmien.contains(NMIEN::VBI)
-            } else {
-                self.set_dli();
-                self.nmien.contains(NMIEN::DLI)
-            }
+        if !self.nmi_pending.is_empty() && self.cycle >= NMIST_CYCLE {
+            // NMIST has status of the last NMI only, set even if it is disabled
+            self.nmist = self.nmi_pending | NMIST::UNUSED;
+            self.nmi_asserted = self.nmi_pending == NMIST::SYSTEM_RESET
+                || self.nmien.bits() & self.nmi_pending.bits() > 0;
+            self.nmi_pending = NMIST::empty();
+        }
+        if self.nmi_asserted && self.cycle >= NMI_CYCLE {
+            self.nmi_asserted = false;
+            true
         } else {
             false
         }
     }
 
+    /// Skips cycles used by ANTIC DMA, CPU is halted during them
     #[inline(always)]
     pub fn steal_cycles(&mut self) {
-        if self.cycle == self.visible_cycle {
-            self.cycle += self.dma_cycles;
-            self.inc_total_cycles(self.dma_cycles as u64);
+        while self.dma_slots & (1 << self.cycle) > 0 {
+            self.cycle += 1;
+            self.inc_total_cycles(1);
         }
     }
 
@@ -364,68 +472,85 @@ impl Antic {
     pub fn update_dma_cycles(&mut self) {
         assert!(self.cycle == 0);
         self.is_visible = false;
-        if self.scan_line < 8 || self.scan_line >= 248 {
-            self.dma_cycles = 0;
-            self.visible_cycle = 0;
-            return;
-        }
-        // TODO - take hscroll into account for steal start value
-        let is_first_mode_line = self.scan_line == self.start_scan_line;
-        let mode = self.mode();
+        self.dma_slots = self.dma_slots();
+        self.visible_cycle = if self.scan_line < 8 || self.scan_line >= 248 {
+            0
+        } else {
+            self.playfield_dma().0
+        };
+    }
 
-        let (line_start_cycle, dma_cycles) =
-            if (self.dmactl & DMACTL::PLAYFIELD_WIDTH_MASK).bits() > 0 {
-                let opts: MODE_OPTS = self.opts();
-
-                let is_hscrol = mode > 1 && opts.contains(MODE_OPTS::HSCROL);
-                let hscrol = if is_hscrol {
-                    self.hscrol as usize / 2
-                } else {
-                    0
-                };
-                let playfield_width_index = self.playfield_width_index(is_hscrol);
-                let (line_start_cycle, dma_cycles_arr) = match mode {
-                    0x2..=0x5 => {
-                        if is_first_mode_line {
-                            MODE_25_STEALED_CYCLES_FIRST_LINE[playfield_width_index]
-                        } else {
-                            MODE_25_STEALED_CYCLES[playfield_width_index]
-                        }
-                    }
-                    0x6..=0x7 => {
-                        if is_first_mode_line {
-                            MODE_67_STEALED_CYCLES_FIRST_LINE[playfield_width_index]
-                        } else {
-                            MODE_67_STEALED_CYCLES[playfield_width_index]
-                        }
-                    }
-                    0x8..=0x9 => MODE_89_STEALED_CYCLES[playfield_width_index],
-                    0xa..=0xc => MODE_AC_STEALED_CYCLES[playfield_width_index],
-                    0xd..=0xf => MODE_DF_STEALED_CYCLES[playfield_width_index],
-
-                    _ => (29, &[9, 9, 9, 9, 9, 9, 9, 9]),
-                };
-                (line_start_cycle, dma_cycles_arr[hscrol])
+    /// Playfield DMA window of the current mode line: (first cycle, end cycle)
+    fn playfield_dma(&self) -> (usize, usize) {
+        let mode = self.mode();
+        let is_hscrol = mode > 1 && self.opts().contains(MODE_OPTS::HSCROL);
+        let (start, len) = PLAYFIELD_DMA[self.playfield_width_index(is_hscrol)];
+        let start = start
+            + if is_hscrol {
+                self.hscrol as usize / 2
             } else {
-                (25, 9)
+                0
             };
+        let len = if mode > 1 { len } else { 0 };
+        (start, (start + len).min(PLAYFIELD_DMA_END))
+    }
 
-        let mut start_dma_cycles = 0;
-        if self.dmactl.contains(DMACTL::PLAYER_DMA) {
-            start_dma_cycles += 5;
-        }
-        if is_first_mode_line && self.dmactl.contains(DMACTL::DLIST_DMA) {
-            if mode == 1 {
-                start_dma_cycles += 3; // DL with ADDR
+    /// Computes DMA cycles of the current scanline
+    fn dma_slots(&self) -> u128 {
+        let mut slots = 0u128;
+        let mut set = |cycle: usize| slots |= 1 << cycle;
+        if self.scan_line >= 8 && self.scan_line < 248 {
+            let mode = self.mode();
+            let opts = self.opts();
+            let is_first_mode_line = self.scan_line == self.start_scan_line;
+            if self
+                .dmactl
+                .intersects(DMACTL::PLAYER_DMA | DMACTL::MISSILE_DMA)
+            {
+                set(MISSILE_DMA_CYCLE);
+            }
+            if self.dmactl.contains(DMACTL::PLAYER_DMA) {
+                PLAYER_DMA_CYCLES.for_each(&mut set);
+            }
+            if is_first_mode_line && self.dmactl.contains(DMACTL::DLIST_DMA) {
+                set(DLIST_DMA_CYCLE);
+                if mode == 1 || (mode > 1 && opts.contains(MODE_OPTS::LMS)) {
+                    DLIST_ADDR_DMA_CYCLES.for_each(&mut set);
+                }
+            }
+            // (cycles per fetched byte, character name fetch)
+            let (period, name_fetch) = match mode {
+                0x2..=0x5 => (2, is_first_mode_line),
+                0x6..=0x7 => (4, is_first_mode_line),
+                0x8..=0x9 => (8, false),
+                0xa..=0xc => (4, false),
+                _ => (2, false),
+            };
+            // map modes fetch data only on the first scanline of mode line
+            let (start, end) = if mode < 8 || is_first_mode_line {
+                self.playfield_dma()
             } else {
-                start_dma_cycles += 1;
+                (0, 0)
+            };
+            for cycle in start..end {
+                let i = (cycle - start) % period;
+                if i == period - 1 || (name_fetch && i == period / 2 - 1) {
+                    set(cycle);
+                }
+            }
+        }
+        for n in 0..REFRESH_COUNT {
+            let due = REFRESH_FIRST_CYCLE + n * REFRESH_PERIOD;
+            if let Some(cycle) = (due..due + REFRESH_PERIOD).find(|&c| slots & (1 << c) == 0) {
+                slots |= 1 << cycle;
             }
         }
-        self.cycle = start_dma_cycles;
-        self.visible_cycle = line_start_cycle.max(start_dma_cycles);
-        self.dma_cycles = dma_cycles;
+        slots
+    }
 
-        self.inc_total_cycles(self.cycle as u64);
+    /// Number of cycles of the current scanline available to CPU
+    pub fn free_cycles(&self) -> usize {
+        SCAN_LINE_CYCLES - self.dma_slots.count_ones() as usize
     }
 
     fn create_mode_line(&self, mode: u8, opts: MODE_OPTS) -> ModeLineDescr {
@@ -555,8 +680,9 @@ impl Antic {
     #[inline(always)]
     pub fn do_wsync(&mut self) {
         let c = self.cycle;
-        if self.cycle < 104 {
-            self.cycle = 104;
+        // CPU runs the next cycle
+        if self.cycle < WSYNC_RELEASE_CYCLE - 1 {
+            self.cycle = WSYNC_RELEASE_CYCLE - 1;
             self.clear_wsync();
         } else {
             self.cycle = SCAN_LINE_CYCLES - 1;
@@ -574,6 +700,8 @@ impl Antic {
         let value = match addr {
             consts::NMIST => self.nmist.bits | 0x1f,
             consts::VCOUNT => self.vcount,
+            consts::PENH => self.penh,
+            consts::PENV => self.penv,
             _ => 0xff,
         };
         // bevy::log::warn!("ANTIC read: {:02x}: {:02x}", addr, value);
@@ -598,16 +726,84 @@ impl Antic {
     }
 }
 
+impl SaveState for Antic {
+    fn save_state(&self, writer: &mut StateWriter) {
+        writer.u8(self.ir);
+        writer.usize(self.line_height);
+        writer.usize(self.n_bytes);
+        writer.usize(self.line_voffset);
+        writer.usize(self.start_scan_line);
+        writer.usize(self.next_scan_line);
+        writer.u8(self.dmactl.bits());
+        writer.u8(self.nmist.bits());
+        writer.u8(self.nmien.bits());
+        writer.u8(self.chactl);
+        writer.u8(self.chbase);
+        writer.u8(self.hscrol);
+        writer.u8(self.vscrol);
+        writer.u8(self.pmbase);
+        writer.u16(self.dlist);
+        writer.u8(self.nmi_pending.bits());
+        writer.bool(self.nmi_asserted);
+        writer.usize(self.cycle);
+        writer.u64(self.total_cycles);
+        writer.usize(self.visible_cycle);
+        writer.u64(self.dma_slots as u64);
+        writer.u64((self.dma_slots >> 64) as u64);
+        writer.usize(self.scan_line);
+        writer.u8(self.vcount);
+        writer.usize(self.video_memory);
+        writer.bool(self.wsync);
+        writer.bool(self.is_visible);
+        writer.bool(self.is_vscroll);
+        writer.u8(self.penh);
+        writer.u8(self.penv);
+    }
+
+    fn load_state(&mut self, reader: &mut StateReader) -> Result<(), String> {
+        self.ir = reader.u8()?;
+        self.line_height = reader.usize()?;
+        self.n_bytes = reader.usize()?;
+        self.line_voffset = reader.usize()?;
+        self.start_scan_line = reader.usize()?;
+        self.next_scan_line = reader.usize()?;
+        self.dmactl = DMACTL::from_bits_truncate(reader.u8()?);
+        self.nmist = NMIST::from_bits_truncate(reader.u8()?);
+        self.nmien = NMIEN::from_bits_truncate(reader.u8()?);
+        self.chactl = reader.u8()?;
+        self.chbase = reader.u8()?;
+        self.hscrol = reader.u8()?;
+        self.vscrol = reader.u8()?;
+        self.pmbase = reader.u8()?;
+        self.dlist = reader.u16()?;
+        self.nmi_pending = NMIST::from_bits_truncate(reader.u8()?);
+        self.nmi_asserted = reader.bool()?;
+        self.cycle = reader.usize()?;
+        self.total_cycles = reader.u64()?;
+        self.visible_cycle = reader.usize()?;
+        self.dma_slots = reader.u64()? as u128 | (reader.u64()? as u128) << 64;
+        self.scan_line = reader.usize()?;
+        self.vcount = reader.u8()?;
+        self.video_memory = reader.usize()?;
+        self.wsync = reader.bool()?;
+        self.is_visible = reader.bool()?;
+        self.is_vscroll = reader.bool()?;
+        self.penh = reader.u8()?;
+        self.penv = reader.u8()?;
+        Ok(())
+    }
+}
+
 #[inline(always)]
-pub fn tick(
+pub fn tick<D: AnticDisplay>(
     atari_system: &mut AtariSystem,
     cpu: &mut MOS6502,
-    antic_data: &mut AnticData,
-    // data_texture: &mut Texture,
+    antic_data: &mut D,
 ) {
     if atari_system.antic.cycle == 0 {
         if atari_system.antic.scan_line == 8 {
             antic_data.clear();
+            atari_system.charset_cache.clear();
         } else if atari_system.antic.scan_line == 0 {
             // antic reset
             atari_system.antic.next_scan_line = 8;
@@ -615,20 +811,33 @@ pub fn tick(
         }
         atari_system.scanline_tick(atari_system.antic.scan_line);
 
+        // GTIA writes done on the previous scanline after its registers were latched
+        let prev_line = atari_system.antic.scan_line.wrapping_sub(1);
+        if (8..248).contains(&prev_line) {
+            for (scan_line, hpos, regs) in atari_system.gtia.changes.iter() {
+                if *scan_line == prev_line {
+                    antic_data.change_gtia_regs(prev_line - 8, *hpos, regs);
+                }
+            }
+        }
+        atari_system.gtia.changes.clear();
+        atari_system.gtia.record_changes = antic_data.gtia_changes();
+        if antic_data.collisions_data().is_none() && (8..248).contains(&prev_line) {
+            let data = atari_system.collision_detector.collisions(prev_line);
+            atari_system.gtia.update_collisions(data);
+        }
+
         if atari_system.antic.dmactl.contains(DMACTL::PLAYER_DMA) {
+            let scan_line = atari_system.antic.scan_line;
             if atari_system.gtia.gractl.contains(gtia::GRACTL::MISSILE_DMA) {
                 let b = get_pm_data(atari_system, 0);
-                atari_system.gtia.write(gtia::GRAFM, b);
+                atari_system.gtia.pm_dma(gtia::GRAFM, b, scan_line);
             }
             if atari_system.gtia.gractl.contains(gtia::GRACTL::PLAYER_DMA) {
-                let b = get_pm_data(atari_system, 1);
-                atari_system.gtia.write(gtia::GRAFP0, b);
-                let b = get_pm_data(atari_system, 2);
-                atari_system.gtia.write(gtia::GRAFP1, b);
-                let b = get_pm_data(atari_system, 3);
-                atari_system.gtia.write(gtia::GRAFP2, b);
-                let b = get_pm_data(atari_system, 4);
-                atari_system.gtia.write(gtia::GRAFP3, b);
+                for n in 0..4 {
+                    let b = get_pm_data(atari_system, n + 1);
+                    atari_system.gtia.pm_dma(gtia::GRAFP0 + n, b, scan_line);
+                }
             }
         }
 
@@ -645,32 +854,54 @@ pub fn tick(
         atari_system.antic.check_nmi();
         if atari_system.antic.wsync() {
             atari_system.antic.clear_wsync();
-            atari_system.antic.cycle = 105;
-            atari_system.antic.inc_total_cycles(105);
+            atari_system.antic.cycle = WSYNC_RELEASE_CYCLE;
+            atari_system
+                .antic
+                .inc_total_cycles(WSYNC_RELEASE_CYCLE as u64);
         }
     }
     if atari_system.antic.fire_nmi() {
-        cpu.non_maskable_interrupt_request();
+        atari_system.request_nmi(cpu);
     }
     if atari_system.antic.gets_visible() {
         if atari_system.antic.scan_line >= 8 && atari_system.antic.scan_line < 248 {
             // assert!(antic_data.gtia_regs.regs.len() == 240);
 
             antic_data.set_gtia_regs(atari_system.antic.scan_line - 8, &atari_system.gtia.regs);
+            // earlier writes are included in latched registers
+            atari_system.gtia.changes.clear();
+            let cpu_collisions = antic_data.collisions_data().is_none();
+            if cpu_collisions {
+                let regs = &atari_system.gtia.regs;
+                atari_system.collision_detector.set_gtia_regs(regs);
+            }
             if atari_system.antic.scan_line == atari_system.antic.start_scan_line {
                 let mut mode_line = atari_system.antic.create_next_mode_line();
                 let charset_offset = (mode_line.chbase as usize) * 256;
+                let charset_size = mode_line.charset_size();
 
                 mode_line.video_memory_offset =
                     antic_data.reserve_antic_memory(mode_line.n_bytes, &mut |data| {
                         atari_system.antic_copy_to_slice(mode_line.data_offset as u16, data)
                     });
-                // todo: detect charset memory changes
-                mode_line.charset_memory_offset = antic_data
-                    .reserve_antic_memory(mode_line.charset_size(), &mut |data| {
+                let cached = atari_system.charset_cache.get(charset_offset, charset_size);
+                mode_line.charset_memory_offset = cached.unwrap_or_else(|| {
+                    let offset = antic_data.reserve_antic_memory(charset_size, &mut |data| {
                         atari_system.antic_copy_to_slice(charset_offset as u16, data)
                     });
+                    atari_system
+                        .charset_cache
+                        .insert(charset_offset, charset_size, offset);
+                    offset
+                });
                 antic_data.insert_mode_line(&mode_line);
+                if cpu_collisions {
+                    let mut detector = std::mem::take(&mut atari_system.collision_detector);
+                    detector.set_mode_line(&mode_line, &mut |offs, data| {
+                        atari_system.antic_copy_to_slice(offs, data)
+                    });
+                    atari_system.collision_detector = detector;
+                }
             }
         }
     }
@@ -678,14 +909,14 @@ pub fn tick(
 }
 
 #[inline(always)]
-pub fn post_instr_tick(atari_system: &mut AtariSystem, collisions: &Option<CollisionsData>) {
+pub fn post_instr_tick(atari_system: &mut AtariSystem, collisions: Option<&CollisionsData>) {
     let antic = &mut atari_system.antic;
     if antic.wsync() {
         antic.do_wsync();
     }
     atari_system.gtia.scan_line =
         antic.scan_line - (antic.scan_line > 0 && antic.cycle < 104) as usize;
-    if let Some(ref collisions) = collisions {
+    if let Some(collisions) = collisions {
         atari_system.gtia.update_collisions_for_scanline(collisions);
     }
 }
@@ -703,5 +934,138 @@ pub fn get_pm_data(system: &mut AtariSystem, n: usize) -> u8 {
             + system.antic.scan_line / 2
             + (system.antic.pmbase & 0b11111100) as usize * 256
     };
-    system.read(offs as u16)
+    system.antic_read(offs as u16)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn free_cycles(ir: u8, dmactl: u8, first_line: bool, hscrol: u8) -> usize {
+        let mut antic = Antic {
+            ir,
+            dmactl: DMACTL::from_bits_truncate(dmactl),
+            scan_line: 20,
+            start_scan_line: if first_line { 20 } else { 16 },
+            hscrol,
+            ..Default::default()
+        };
+        antic.update_dma_cycles();
+        antic.free_cycles()
+    }
+
+    #[test]
+    fn test_free_cycles() {
+        // blank line, refresh only
+        assert_eq!(free_cycles(0x00, 0x22, false, 0), 105);
+        // mode 2, normal playfield, first line with DL and P/M DMA, no refresh
+        assert_eq!(free_cycles(0x02, 0x2e, true, 0), 28);
+        assert_eq!(free_cycles(0x02, 0x22, false, 0), 65);
+        assert_eq!(free_cycles(0x02, 0x23, true, 0), 17);
+        // mode 6 with LMS
+        assert_eq!(free_cycles(0x46, 0x22, true, 0), 62);
+        // map modes fetch data on the first scanline only
+        assert_eq!(free_cycles(0x08, 0x21, true, 0), 96);
+        assert_eq!(free_cycles(0x08, 0x21, false, 0), 105);
+        assert_eq!(free_cycles(0x0f, 0x22, true, 0), 64);
+        assert_eq!(free_cycles(0x0f, 0x23, true, 0), 56);
+        // HSCROL widens playfield, DMA past cycle 105 is cut off
+        assert_eq!(free_cycles(0x1f, 0x22, true, 0), 56);
+        assert_eq!(free_cycles(0x1f, 0x22, true, 15), 60);
+    }
+
+    #[test]
+    fn test_charset_cache_wrap() {
+        let mut cache = CharsetCache::default();
+        // mode 2 charset with CHBASE=$FF covers pages $FF, $00 - $02
+        cache.insert(0xff00, 1024, 0);
+        assert_eq!(cache.get(0xff00, 1024), Some(0));
+        cache.write(0x0300);
+        assert_eq!(cache.get(0xff00, 1024), Some(0));
+        cache.write(0x0200);
+        assert_eq!(cache.get(0xff00, 1024), None);
+    }
+
+    /// runs scanline cycles from 0, returns (first cycle with NMIST bit set, NMI cycle)
+    fn nmi_timing(antic: &mut Antic, status: NMIST) -> (Option<usize>, Option<usize>) {
+        let (mut status_cycle, mut nmi_cycle) = (None, None);
+        antic.check_nmi();
+        for cycle in 0..SCAN_LINE_CYCLES {
+            antic.cycle = cycle;
+            if antic.fire_nmi() && nmi_cycle.is_none() {
+                nmi_cycle = Some(cycle);
+            }
+            if antic.nmist.contains(status) && status_cycle.is_none() {
+                status_cycle = Some(cycle);
+            }
+        }
+        (status_cycle, nmi_cycle)
+    }
+
+    #[test]
+    fn test_vbi_timing() {
+        let mut antic = Antic {
+            scan_line: 248,
+            nmien: NMIEN::VBI,
+            ..Default::default()
+        };
+        assert_eq!(nmi_timing(&mut antic, NMIST::VBI), (Some(7), Some(8)));
+        assert_eq!(antic.read(consts::NMIST), 0x5f);
+    }
+
+    #[test]
+    fn test_dli_timing() {
+        // last scanline of mode 2 line with DLI
+        let mut antic = Antic {
+            ir: 0x82,
+            scan_line: 27,
+            start_scan_line: 20,
+            line_height: 8,
+            nmien: NMIEN::DLI,
+            ..Default::default()
+        };
+        assert_eq!(nmi_timing(&mut antic, NMIST::DLI), (Some(7), Some(8)));
+        assert_eq!(antic.read(consts::NMIST), 0x9f);
+        // no DLI on other scanlines of the mode line
+        antic.write(consts::NMIRES, 0);
+        antic.scan_line = 26;
+        assert_eq!(nmi_timing(&mut antic, NMIST::DLI), (None, None));
+    }
+
+    #[test]
+    fn test_disabled_nmi_status() {
+        let mut antic = Antic {
+            scan_line: 248,
+            ..Default::default()
+        };
+        assert_eq!(nmi_timing(&mut antic, NMIST::VBI), (Some(7), None));
+        antic.write(consts::NMIRES, 0);
+        assert_eq!(antic.read(consts::NMIST), 0x1f);
+    }
+
+    #[test]
+    fn test_reset_key_nmi() {
+        let mut antic = Antic {
+            scan_line: 100,
+            ..Default::default()
+        };
+        antic.press_reset_key();
+        assert_eq!(
+            nmi_timing(&mut antic, NMIST::SYSTEM_RESET),
+            (Some(7), Some(8))
+        );
+        assert_eq!(antic.read(consts::NMIST), 0x3f);
+    }
+
+    #[test]
+    fn test_vblank_refresh() {
+        let mut antic = Antic {
+            ir: 0x0f,
+            dmactl: DMACTL::from_bits_truncate(0x2e),
+            scan_line: 250,
+            ..Default::default()
+        };
+        antic.update_dma_cycles();
+        assert_eq!(antic.free_cycles(), 105);
+    }
 }
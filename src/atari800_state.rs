@@ -1,6 +1,8 @@
 use bevy::prelude::*;
 use emulator_6502::MOS6502;
 
+use crate::config::MachineProfile;
+
 #[derive(Debug, Clone, Copy)]
 #[repr(C, packed)]
 struct Test {
@@ -23,6 +25,34 @@ pub struct Atari800 {
     keyboard_detached: bool,
 }
 
+// 400/800 (0) and 5200 (2) states are not supported
+const MACHINE_XLXE: u8 = 1;
+
+impl Atari800 {
+    pub fn is_pal(&self) -> bool {
+        self.is_pal
+    }
+    pub fn builtin_basic(&self) -> bool {
+        self.builtin_basic
+    }
+    pub fn builtin_game(&self) -> bool {
+        self.builtin_game
+    }
+    pub fn keyboard_detached(&self) -> bool {
+        self.keyboard_detached
+    }
+    /// Profile of XL/XE machine, the only type accepted by `Atari800State::new`
+    pub fn machine_profile(&self, num_xe_banks: u32) -> MachineProfile {
+        if self.builtin_game {
+            MachineProfile::XEGS
+        } else if num_xe_banks > 0 {
+            MachineProfile::XE
+        } else {
+            MachineProfile::XL
+        }
+    }
+}
+
 #[derive(Debug, Clone, Copy)]
 #[repr(C, packed)]
 pub struct Antic {
@@ -173,75 +203,91 @@ pub struct GTIA {
     pub trig_latch: u32,
 }
 
-pub fn read<T>(data: &[u8]) -> (&T, &[u8]) {
-    let (head, body, _) = unsafe { data.align_to::<T>() };
-    assert!(head.is_empty());
-    (&body[0], &data[std::mem::size_of::<T>()..])
+/// splits `len` bytes from the start of data
+fn take(data: &[u8], len: usize) -> Result<(&[u8], &[u8]), String> {
+    if data.len() < len {
+        return Err("truncated Atari800 state".to_string());
+    }
+    Ok(data.split_at(len))
+}
+
+fn take_u16(data: &[u8]) -> Result<(usize, &[u8]), String> {
+    let (value, data) = take(data, 2)?;
+    Ok((value[0] as usize + 256 * value[1] as usize, data))
+}
+
+pub fn read<T>(data: &[u8]) -> Result<(&T, &[u8]), String> {
+    let (value, data) = take(data, std::mem::size_of::<T>())?;
+    // structs are packed, so they are always aligned
+    let (_, body, _) = unsafe { value.align_to::<T>() };
+    Ok((&body[0], data))
 }
 
-pub fn skip_sio(data: &[u8]) -> &[u8] {
-    let mut offs: usize = 0;
+pub fn skip_sio(data: &[u8]) -> Result<&[u8], String> {
+    let mut data = data;
     for i in 0..8 {
-        offs += 4;
-        let len = data[offs] as usize + 256 * data[offs + 1] as usize;
-        offs += 2 + len;
+        let (_, rest) = take(data, 4)?;
+        let (len, rest) = take_u16(rest)?;
+        let (_, rest) = take(rest, len)?;
         info!("SIO {} {}", i, len);
+        data = rest;
     }
-    return &data[offs..];
+    Ok(data)
 }
 
-pub fn read_memory(data: &[u8]) -> (Memory, &[u8]) {
+pub fn read_memory(data: &[u8]) -> Result<(Memory, &[u8]), String> {
     let mut memory = Memory::default();
-    let base_ram_kb = data[0];
-    let data = &data[4..];
-    memory.data = &data[0..0x10000];
-    memory.attrib = &data[0x10000..0x20000];
-    let data = &data[0x20000..];
+    let (header, data) = take(data, 4)?;
+    let base_ram_kb = header[0];
+    let (ram, data) = take(data, 0x10000)?;
+    memory.data = ram;
+    let (attrib, data) = take(data, 0x10000)?;
+    memory.attrib = attrib;
 
-    memory.basic = &data[0..0x2000];
-    let data = &data[0x2000..];
+    let (basic, data) = take(data, 0x2000)?;
+    memory.basic = basic;
 
-    memory.cart0bf = &data[0..0x2000];
-    let data = &data[0x2000..];
+    let (cart0bf, data) = take(data, 0x2000)?;
+    memory.cart0bf = cart0bf;
 
-    memory.os = &data[0..0x4000];
-    let data = &data[0x4000..];
+    let (os, data) = take(data, 0x4000)?;
+    memory.os = os;
 
-    memory.under_atarixl_os = &data[0..0x4000];
-    let data = &data[0x4000..];
+    let (under_atarixl_os, data) = take(data, 0x4000)?;
+    memory.under_atarixl_os = under_atarixl_os;
 
-    memory.xegame = &data[0..0x2000];
-    let data = &data[0x2000..];
+    let (xegame, data) = take(data, 0x2000)?;
+    memory.xegame = xegame;
 
-    let num_xe_banks = data[0] as usize + 256 * (data[1] as usize);
-    let data = &data[4..];
+    let (num_xe_banks, data) = take_u16(data)?;
+    memory.num_xe_banks = num_xe_banks as u32;
+    let (_, data) = take(data, 2)?;
 
     let mut ram_size = base_ram_kb as usize + num_xe_banks * 16;
     let data = if ram_size == 320 {
-        let xe_type = data[0] as usize + 256 * (data[1] as usize);
+        let (xe_type, data) = take_u16(data)?;
         ram_size += xe_type;
-        &data[4..]
+        take(data, 2)?.1
     } else {
         data
     };
-    assert!(ram_size == 64 || ram_size == 128);
-    memory.portb = data[0];
-    memory.cart_a0bf_enabled.copy_from_slice(&data[1..5]);
-    let data = &data[5..];
+    if ram_size != 64 && ram_size != 128 {
+        return Err(format!("not supported RAM size: {}k", ram_size));
+    }
+    let (portb, data) = take(data, 5)?;
+    memory.portb = portb[0];
+    memory.cart_a0bf_enabled.copy_from_slice(&portb[1..5]);
     let data = if ram_size > 64 {
         let atarixe_memory_size = (1 + (ram_size - 64) / 16) * 16384;
-        memory.atarixe_memory = &data[0..atarixe_memory_size];
-        &data[atarixe_memory_size..]
-    } else {
+        let (atarixe_memory, data) = take(data, atarixe_memory_size)?;
+        memory.atarixe_memory = atarixe_memory;
         data
-    };
-    let data = if ram_size > 20 {
-        memory.enable_mapram.copy_from_slice(&data[0..4]);
-        &data[4..]
     } else {
         data
     };
-    (memory, data)
+    let (enable_mapram, data) = take(data, 4)?;
+    memory.enable_mapram.copy_from_slice(enable_mapram);
+    Ok((memory, data))
 }
 
 pub struct Atari800State<'a> {
@@ -266,48 +312,56 @@ impl<'a> Atari800State<'a> {
         cpu.set_stack_pointer(self.cpu.reg_s);
     }
 
-    pub fn new(data: &[u8]) -> Atari800State {
-        let (header, data) = data.split_at(8);
-        assert!(std::str::from_utf8(header).expect("valid utf") == "ATARI800");
-        assert!(data[0] == 8);
-        let verbose = data[1] > 0;
-        assert!(verbose, "verbose save expected");
-        let (_, data) = data.split_at(2);
-
-        let (atari800, data) = read::<Atari800>(data);
-        assert!(
-            atari800.machine_size == 1,
-            "not supported machine size: {}",
-            atari800.machine_size
-        );
-
-        let (cartridge, data) = read::<Cartridge>(data);
-        assert!(
-            cartridge.saved_type == 0,
-            "reading cartridge is not supported"
-        );
-
-        let data = skip_sio(data);
-
-        let (antic, data) = read::<Antic>(data);
-
-        let (cpu, _) = read::<CPU>(data);
-        let mut cpu = cpu.clone();
-        let data = &data[6..];
-        let (memory, data) = read_memory(data);
-        cpu.pc = data[0] as u16 + (data[1] as u16) * 256;
-        let data = &data[2..];
-        let (gtia, data) = read::<GTIA>(data);
-
-        let (pia, data) = read::<PIA>(data);
-        let (pokey, _data) = read::<POKEY>(data);
+    /// Parses verbose state saved by Atari800 emulator
+    pub fn new(data: &[u8]) -> Result<Atari800State, String> {
+        let (header, data) = take(data, 8)?;
+        if header != b"ATARI800" {
+            return Err("not an Atari800 state".to_string());
+        }
+        let (version, data) = take(data, 2)?;
+        if version[0] != 8 {
+            return Err(format!(
+                "not supported Atari800 state version: {}",
+                version[0]
+            ));
+        }
+        if version[1] == 0 {
+            return Err("verbose Atari800 state expected".to_string());
+        }
+
+        let (atari800, data) = read::<Atari800>(data)?;
+        if atari800.machine_size != MACHINE_XLXE {
+            return Err(format!("not supported machine type: {}", {
+                atari800.machine_size
+            }));
+        }
+
+        let (cartridge, data) = read::<Cartridge>(data)?;
+        if cartridge.saved_type != 0 {
+            return Err("reading cartridge is not supported".to_string());
+        }
+
+        let data = skip_sio(data)?;
+
+        let (antic, data) = read::<Antic>(data)?;
+
+        let (cpu, _) = read::<CPU>(data)?;
+        let mut cpu = *cpu;
+        let (_, data) = take(data, 6)?;
+        let (memory, data) = read_memory(data)?;
+        let (pc, data) = take_u16(data)?;
+        cpu.pc = pc as u16;
+        let (gtia, data) = read::<GTIA>(data)?;
+
+        let (pia, data) = read::<PIA>(data)?;
+        let (pokey, _data) = read::<POKEY>(data)?;
 
         info!("cpu: {:?}", cpu);
         info!("gtia: {:?}", gtia);
         info!("pia: {:?}", pia);
         info!("pokey: {:?}", pokey);
 
-        Atari800State {
+        Ok(Atari800State {
             atari800,
             cartridge,
             antic,
@@ -316,6 +370,6 @@ impl<'a> Atari800State<'a> {
             pokey,
             cpu,
             memory,
-        }
+        })
     }
 }
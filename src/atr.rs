@@ -7,27 +7,54 @@ use crate::platform::FileSystem;
 pub struct ATR {
     path: String,
     updated_at: Option<Instant>,
+    modified: bool,
     sector_size: usize,
     data: Vec<u8>,
 }
 
 impl ATR {
-    pub fn new(path: &str, data: &[u8]) -> Self {
-        assert!(data[0] == 0x96);
-        assert!(data[1] == 0x02);
+    pub fn new(path: &str, data: &[u8]) -> Result<Self, String> {
+        if data.len() < 16 || data[0] != 0x96 || data[1] != 0x02 {
+            return Err(format!("invalid ATR header: {}", path));
+        }
         let sector_size = data[4] as usize + 256 * data[5] as usize;
-        assert!(sector_size == 128 || sector_size == 256);
-        Self {
+        if sector_size != 128 && sector_size != 256 {
+            return Err(format!("invalid ATR sector size: {}", sector_size));
+        }
+        Ok(Self {
             path: path.to_owned(),
             data: data.to_owned(),
             sector_size,
             updated_at: None,
-        }
+            modified: false,
+        })
+    }
+    pub fn path(&self) -> &str {
+        &self.path
+    }
+
+    pub fn data(&self) -> &[u8] {
+        &self.data
+    }
+
+    /// true if any sector was written since the image was attached
+    pub fn is_modified(&self) -> bool {
+        self.modified
+    }
+
+    pub fn set_modified(&mut self, modified: bool) {
+        self.modified = modified;
     }
+
     pub fn sector_size(&self) -> usize {
         self.sector_size
     }
 
+    /// size of given sector, first three are always 128 bytes
+    pub fn sector_len(&self, sector: usize) -> usize {
+        self.get_range(sector).len()
+    }
+
     pub fn get_data(&self, start: usize, end: usize) -> Option<&[u8]> {
         if end >= start && end + 16 <= self.data.len() {
             Some(&self.data[start + 16..end + 16])
@@ -56,9 +83,10 @@ impl ATR {
 
     pub fn put_sector(&mut self, n: usize, data: &[u8]) -> u8 {
         let range = self.get_range(n);
-        if data.len() == range.len() {
+        if data.len() == range.len() && range.end <= self.data.len() {
             self.data[range].copy_from_slice(data);
             self.updated_at = Some(Instant::now());
+            self.modified = true;
             0x01
         } else {
             0xff
@@ -3,13 +3,23 @@ pub trait Cartridge: Sync + Send {
     fn read(&self, addr: usize) -> &u8;
     fn write(&mut self, addr: usize, value: u8);
     fn reset(&mut self) {}
+    /// currently selected bank, stored in save states
+    fn bank(&self) -> usize {
+        0
+    }
+    fn set_bank(&mut self, _bank: usize) {}
 }
 
 impl dyn Cartridge {
     pub fn from_bytes(bytes: &[u8]) -> Result<Box<dyn Cartridge>, String> {
         assert!(std::str::from_utf8(&bytes[0..7]) == Ok("CART\0\0\0"));
-        assert!((bytes.len() - 16) & 0x1fff == 0, "invalid car file size");
         let cart_type = bytes[7];
+        match cart_type {
+            // 5200 32k, 16k two chip, 16k one chip, 8k, 4k
+            4 | 6 | 16 | 19 | 20 => return Cart5200::new(&bytes[16..], cart_type == 6),
+            _ => (),
+        }
+        assert!((bytes.len() - 16) & 0x1fff == 0, "invalid car file size");
         match cart_type {
             // TODO: cast bytes[4..8] to u32
             1 => Ok(Box::new(Standard8k {
@@ -26,6 +36,51 @@ impl dyn Cartridge {
             _ => Err(format!("unsupported {} cartridge type", cart_type)),
         }
     }
+
+    /// headerless 5200 cartridge image, 16k ones are assumed to be two chip
+    pub fn from_raw_5200(bytes: &[u8]) -> Result<Box<dyn Cartridge>, String> {
+        Cart5200::new(bytes, bytes.len() == 0x4000)
+    }
+
+    pub fn is_car(bytes: &[u8]) -> bool {
+        bytes.len() > 16 && &bytes[0..4] == b"CART"
+    }
+}
+
+/// 5200 cartridge mapped at $4000-$BFFF, smaller ones are mirrored
+pub struct Cart5200 {
+    data: Vec<u8>,
+    // 16k cartridge with 8k chips at $4000 and $8000
+    two_chip: bool,
+}
+
+impl Cart5200 {
+    fn new(data: &[u8], two_chip: bool) -> Result<Box<dyn Cartridge>, String> {
+        match data.len() {
+            0x1000 | 0x2000 | 0x4000 | 0x8000 => Ok(Box::new(Cart5200 {
+                data: data.to_vec(),
+                two_chip,
+            })),
+            len => Err(format!("invalid 5200 cartridge size: {}", len)),
+        }
+    }
+}
+
+impl Cartridge for Cart5200 {
+    fn is_enabled(&self) -> bool {
+        true
+    }
+
+    fn read(&self, addr: usize) -> &u8 {
+        let offset = addr.wrapping_sub(0x4000) & 0x7fff;
+        if self.two_chip {
+            &self.data[(offset >> 14) * 0x2000 + (offset & 0x1fff)]
+        } else {
+            &self.data[offset & (self.data.len() - 1)]
+        }
+    }
+
+    fn write(&mut self, _addr: usize, _value: u8) {}
 }
 pub struct Standard8k {
     data: Vec<u8>,
@@ -67,6 +122,14 @@ impl Cartridge for AtariMax1M {
     fn reset(&mut self) {
         self.cart_bank = 0;
     }
+
+    fn bank(&self) -> usize {
+        self.cart_bank
+    }
+
+    fn set_bank(&mut self, bank: usize) {
+        self.cart_bank = bank;
+    }
 }
 
 pub struct AtariMax128k {
@@ -92,4 +155,12 @@ impl Cartridge for AtariMax128k {
     fn reset(&mut self) {
         self.cart_bank = 0;
     }
+
+    fn bank(&self) -> usize {
+        self.cart_bank
+    }
+
+    fn set_bank(&mut self, bank: usize) {
+        self.cart_bank = bank;
+    }
 }
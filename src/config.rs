@@ -2,8 +2,162 @@
 use bevy::prelude::{info, Local, Plugin, Res, ResMut};
 use serde::{Deserialize, Serialize};
 
+use crate::software_render::Palette;
+
+#[derive(Clone, Copy, Serialize, Deserialize, Debug, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Renderer {
+    Gpu,
+    Software,
+}
+
+impl Default for Renderer {
+    fn default() -> Self {
+        Renderer::Gpu
+    }
+}
+
+#[derive(Clone, Copy, Serialize, Deserialize, Debug, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum VideoStandard {
+    Pal,
+    Ntsc,
+}
+
+impl Default for VideoStandard {
+    fn default() -> Self {
+        VideoStandard::Pal
+    }
+}
+
+impl VideoStandard {
+    pub fn scan_lines(&self) -> usize {
+        match self {
+            VideoStandard::Pal => 312,
+            VideoStandard::Ntsc => 262,
+        }
+    }
+
+    pub fn fps(&self) -> usize {
+        match self {
+            VideoStandard::Pal => 50,
+            VideoStandard::Ntsc => 60,
+        }
+    }
+
+    /// CPU clock, rounded to the whole number of frames per second
+    pub fn cycles_per_second(&self) -> f64 {
+        (self.scan_lines() * 114 * self.fps()) as f64
+    }
+}
+
+#[derive(Clone, Copy, Serialize, Deserialize, Debug, PartialEq, Eq)]
+pub enum MachineProfile {
+    /// 400/800 with OS-B, 48k RAM, no PORTB memory control
+    #[serde(rename = "800")]
+    Atari800,
+    #[serde(rename = "xl")]
+    XL,
+    #[serde(rename = "xe")]
+    XE,
+    /// XE Game System with built-in game ROM and detachable keyboard
+    #[serde(rename = "xegs")]
+    XEGS,
+    /// 5200 SuperSystem, 16k RAM, 32k cartridge space, 2k BIOS
+    #[serde(rename = "5200")]
+    Atari5200,
+}
+
+impl Default for MachineProfile {
+    fn default() -> Self {
+        MachineProfile::XE
+    }
+}
+
+impl MachineProfile {
+    pub fn has_portb_memory_control(&self) -> bool {
+        matches!(
+            self,
+            MachineProfile::XL | MachineProfile::XE | MachineProfile::XEGS
+        )
+    }
+
+    /// size of OS ROM, 10k for OS-B, 16k for XL OS, 2k for 5200 BIOS
+    pub fn osrom_size(&self) -> usize {
+        match self {
+            MachineProfile::Atari800 => 0x2800,
+            MachineProfile::Atari5200 => 0x800,
+            _ => 0x4000,
+        }
+    }
+}
+
+/// RAM size and PORTB extended memory decoding
+#[derive(Clone, Copy, Serialize, Deserialize, Debug, PartialEq, Eq)]
+pub enum MemoryModel {
+    #[serde(rename = "64k")]
+    Mem64,
+    /// 130XE, bits 2-3 select the bank, separate CPU (bit 4) and ANTIC (bit 5) access
+    #[serde(rename = "130xe")]
+    Mem130XE,
+    /// bits 2-3, 5-6 select the bank, bit 4 enables both CPU and ANTIC access
+    #[serde(rename = "320k_rambo")]
+    Rambo320,
+    /// bits 2-3, 6-7 select the bank, separate CPU and ANTIC access
+    #[serde(rename = "320k_compy")]
+    Compy320,
+    /// bits 1-3, 5-6 select the bank, bit 4 enables both CPU and ANTIC access
+    #[serde(rename = "576k")]
+    Mem576,
+    /// bits 1-3, 5-7 select the bank, bit 4 enables both CPU and ANTIC access
+    #[serde(rename = "1088k")]
+    Mem1088,
+}
+
+impl Default for MemoryModel {
+    fn default() -> Self {
+        MemoryModel::Compy320
+    }
+}
+
+impl MemoryModel {
+    /// number of 16k extended memory banks
+    pub fn ext_banks(&self) -> usize {
+        match self {
+            MemoryModel::Mem64 => 0,
+            MemoryModel::Mem130XE => 4,
+            MemoryModel::Rambo320 | MemoryModel::Compy320 => 16,
+            MemoryModel::Mem576 => 32,
+            MemoryModel::Mem1088 => 64,
+        }
+    }
+
+    pub fn ram_size(&self) -> usize {
+        0x10000 + self.ext_banks() * 0x4000
+    }
+
+    /// true if ANTIC access to extended memory is controlled by PORTB bit 5
+    pub fn separate_antic_access(&self) -> bool {
+        matches!(self, MemoryModel::Mem130XE | MemoryModel::Compy320)
+    }
+
+    /// extended memory bank selected by PORTB value
+    pub fn bank(&self, portb: u8) -> usize {
+        let portb = portb as usize;
+        match self {
+            MemoryModel::Mem64 => 0,
+            MemoryModel::Mem130XE => (portb >> 2) & 3,
+            MemoryModel::Rambo320 => (portb >> 2) & 3 | ((portb >> 5) & 3) << 2,
+            MemoryModel::Compy320 => (portb >> 2) & 3 | ((portb >> 6) & 3) << 2,
+            MemoryModel::Mem576 => (portb >> 1) & 7 | ((portb >> 5) & 3) << 3,
+            MemoryModel::Mem1088 => (portb >> 1) & 7 | ((portb >> 5) & 7) << 3,
+        }
+    }
+}
+
 #[derive(Clone, Serialize, Deserialize, Debug, PartialEq)]
 pub struct EmulatorConfig {
+    /// read collisions back from GPU, otherwise they are computed on CPU
     #[serde(default = "default_true")]
     pub collisions: bool,
 
@@ -24,6 +178,43 @@ pub struct EmulatorConfig {
 
     #[serde(default)]
     pub basic: bool,
+
+    #[serde(default)]
+    pub renderer: Renderer,
+
+    #[serde(default)]
+    pub palette: Palette,
+
+    #[serde(default = "default_rewind_seconds")]
+    pub rewind_seconds: usize,
+
+    #[serde(default)]
+    pub video_standard: VideoStandard,
+
+    #[serde(default)]
+    pub memory: MemoryModel,
+
+    #[serde(default)]
+    pub machine: MachineProfile,
+
+    #[serde(default)]
+    pub keyboard_detached: bool,
+
+    /// handle OS SIO calls with a hook instead of serial transfer through POKEY
+    #[serde(default = "default_true")]
+    pub sio_accelerator: bool,
+
+    /// mouse and gamepad analog sticks drive paddles instead of joysticks
+    #[serde(default)]
+    pub paddles: bool,
+
+    /// mouse is light pen / light gun
+    #[serde(default)]
+    pub light_pen: bool,
+
+    /// characters are typed according to host keyboard layout instead of key positions
+    #[serde(default)]
+    pub symbolic_keyboard: bool,
 }
 
 #[derive(Clone, Serialize, Deserialize, Debug, PartialEq)]
@@ -48,6 +239,39 @@ pub struct LocalEmulatorConfig {
 
     #[serde(default)]
     pub basic: Option<bool>,
+
+    #[serde(default)]
+    pub renderer: Option<Renderer>,
+
+    #[serde(default)]
+    pub palette: Option<Palette>,
+
+    #[serde(default)]
+    pub rewind_seconds: Option<usize>,
+
+    #[serde(default)]
+    pub video_standard: Option<VideoStandard>,
+
+    #[serde(default)]
+    pub memory: Option<MemoryModel>,
+
+    #[serde(default)]
+    pub machine: Option<MachineProfile>,
+
+    #[serde(default)]
+    pub keyboard_detached: Option<bool>,
+
+    #[serde(default)]
+    pub sio_accelerator: Option<bool>,
+
+    #[serde(default)]
+    pub paddles: Option<bool>,
+
+    #[serde(default)]
+    pub light_pen: Option<bool>,
+
+    #[serde(default)]
+    pub symbolic_keyboard: Option<bool>,
 }
 
 fn default_scale() -> f32 {
@@ -56,11 +280,33 @@ fn default_scale() -> f32 {
 fn default_true() -> bool {
     true
 }
+fn default_rewind_seconds() -> usize {
+    10
+}
+
+impl Default for EmulatorConfig {
+    fn default() -> Self {
+        serde_json::from_str("{}").unwrap()
+    }
+}
 
 impl EmulatorConfig {
     pub fn is_multi(&self) -> bool {
         false
     }
+
+    /// capacity of the rewind buffer in frames
+    pub fn rewind_frames(&self) -> usize {
+        self.rewind_seconds * self.video_standard.fps()
+    }
+
+    /// default palette follows the video standard
+    pub fn effective_palette(&self) -> Palette {
+        match (self.palette, self.video_standard) {
+            (Palette::Default, VideoStandard::Ntsc) => Palette::Ntsc,
+            (palette, _) => palette,
+        }
+    }
 }
 
 pub struct GlobalEmulatorConfig(pub EmulatorConfig);
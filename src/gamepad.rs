@@ -2,44 +2,145 @@ use bevy::prelude::*;
 
 use bevy::prelude::GamepadButtonType;
 
+use crate::config::EmulatorConfig;
 use crate::focus::Focused;
+use crate::mapping::{Controls, AXIS_THRESHOLD};
+use crate::pokey::{self, POT_MAX};
+use crate::software_render::{HEIGHT, WIDTH};
 use crate::system::AtariSystem;
 
+/// maps -1.0..1.0 axis value to paddle position, turning right decreases it
+fn paddle_value(axis: f32) -> u8 {
+    ((1.0 - axis.clamp(-1.0, 1.0)) / 2.0 * POT_MAX as f32) as u8
+}
+
 pub fn update(
     mut query: Query<&mut AtariSystem, With<Focused>>,
     gamepad_buttons: Res<Input<GamepadButton>>,
     axis: Res<Axis<GamepadAxis>>,
+    mouse_buttons: Res<Input<MouseButton>>,
+    windows: Res<Windows>,
+    config: Res<EmulatorConfig>,
 ) {
     for mut atari_system in query.iter_mut() {
-        let mut consol = 0;
-        let axis_threshold = 0.5;
+        // mouse X / Y are paddles 0 / 1 while cursor is over the window
+        let cursor = windows.get_primary().and_then(|window| {
+            let pos = window.cursor_position()?;
+            Some((
+                pos.x / window.width() * 2.0 - 1.0,
+                pos.y / window.height() * 2.0 - 1.0,
+            ))
+        });
+        if let (true, Some((x, y))) = (config.paddles, cursor) {
+            let value = [paddle_value(x), paddle_value(y)];
+            let fire = [
+                mouse_buttons.pressed(MouseButton::Left),
+                mouse_buttons.pressed(MouseButton::Right),
+            ];
+            for (index, (value, fire)) in value.into_iter().zip(fire).enumerate() {
+                atari_system.set_paddle(index, value, fire);
+            }
+        }
+        let mapping = &atari_system.input_mapping;
+        let mut controls = Controls::default();
+        for ((idx, button), target) in mapping.buttons.iter() {
+            if gamepad_buttons.pressed(GamepadButton(Gamepad(*idx), *button)) {
+                controls.apply(*target);
+            }
+        }
+        for ((idx, axis_type, positive), target) in mapping.axes.iter() {
+            let value = axis
+                .get(GamepadAxis(Gamepad(*idx), *axis_type))
+                .unwrap_or_default();
+            let value = if *positive { value } else { -value };
+            if value >= AXIS_THRESHOLD {
+                controls.apply(*target);
+            }
+        }
         for idx in 0..2 {
             let pad = Gamepad(idx);
-            let stick_x = axis
-                .get(GamepadAxis(pad, GamepadAxisType::LeftStickX))
-                .unwrap_or_default();
-            let stick_y = axis
-                .get(GamepadAxis(pad, GamepadAxisType::LeftStickY))
-                .unwrap_or_default();
+            if config.paddles {
+                // left / right stick X axes and triggers are paddles 2 * idx, 2 * idx + 1
+                let paddles = [
+                    (GamepadAxisType::LeftStickX, GamepadButtonType::LeftTrigger),
+                    (
+                        GamepadAxisType::RightStickX,
+                        GamepadButtonType::RightTrigger,
+                    ),
+                ];
+                for (i, (stick, trigger)) in paddles.into_iter().enumerate() {
+                    let index = idx * 2 + i;
+                    if index < 2 && cursor.is_some() {
+                        continue;
+                    }
+                    let value = axis.get(GamepadAxis(pad, stick)).unwrap_or_default();
+                    let fire = gamepad_buttons.pressed(GamepadButton(pad, trigger));
+                    atari_system.set_paddle(index, paddle_value(value), fire);
+                }
+                atari_system.set_joystick(0, idx, 0, false);
+                continue;
+            }
+            atari_system.set_joystick(0, idx, controls.dirs[idx], controls.fire[idx]);
+        }
+        atari_system.update_consol(1, controls.consol);
+    }
+}
 
-            let up = gamepad_buttons.pressed(GamepadButton(pad, GamepadButtonType::DPadUp))
-                || stick_y >= axis_threshold;
-            let down = gamepad_buttons.pressed(GamepadButton(pad, GamepadButtonType::DPadDown))
-                || stick_y <= -axis_threshold;
-            let left = gamepad_buttons.pressed(GamepadButton(pad, GamepadButtonType::DPadLeft))
-                || stick_x <= -axis_threshold;
-            let right = gamepad_buttons.pressed(GamepadButton(pad, GamepadButtonType::DPadRight))
-                || stick_x >= axis_threshold;
-            let dirs = up as u8 | down as u8 * 2 | left as u8 * 4 | right as u8 * 8;
-            let fire = gamepad_buttons.pressed(GamepadButton(pad, GamepadButtonType::East))
-                || gamepad_buttons.pressed(GamepadButton(pad, GamepadButtonType::LeftTrigger))
-                || gamepad_buttons.pressed(GamepadButton(pad, GamepadButtonType::RightTrigger));
+/// joystick multiplexer input used by light pen trigger
+const LIGHT_PEN_INPUT: usize = 3;
 
-            atari_system.set_joystick(0, idx, dirs, fire);
-            consol |= gamepad_buttons.pressed(GamepadButton(pad, GamepadButtonType::South)) as u8
-                + gamepad_buttons.pressed(GamepadButton(pad, GamepadButtonType::North)) as u8 * 2
-                + gamepad_buttons.pressed(GamepadButton(pad, GamepadButtonType::West)) as u8 * 4;
-        }
-        atari_system.update_consol(1, consol);
+/// Mouse as light pen / light gun: cursor over the Atari screen sets
+/// the light pen position, left button is trigger on joystick 1 up line
+pub fn update_light_pen(
+    mut query: Query<&mut AtariSystem, With<Focused>>,
+    mouse_buttons: Res<Input<MouseButton>>,
+    windows: Res<Windows>,
+    config: Res<EmulatorConfig>,
+) {
+    if !config.light_pen {
+        return;
+    }
+    let window = match windows.get_primary() {
+        Some(window) => window,
+        None => return,
+    };
+    // screen sprite is centered in the window, see `compute_atari_screen_scale`
+    let (width, height) = (WIDTH as f32, HEIGHT as f32);
+    let scale = (window.width() / width).min(window.height() / height);
+    let position = window.cursor_position().and_then(|pos| {
+        let x = (pos.x - window.width() / 2.0) / scale + width / 2.0;
+        let y = height / 2.0 - (pos.y - window.height() / 2.0) / scale;
+        // frame starts at color clock 32 and scanline 8
+        let on_screen = (0.0..width).contains(&x) && (0.0..height).contains(&y);
+        on_screen.then(|| ((x / 2.0) as u8 + 32, y as usize + 8))
+    });
+    let trigger = mouse_buttons.pressed(MouseButton::Left);
+    for mut atari_system in query.iter_mut() {
+        atari_system.set_light_pen(position);
+        atari_system.set_joystick(LIGHT_PEN_INPUT, 0, trigger as u8, false);
+    }
+}
+
+/// Symbolic keyboard: characters typed on the host keyboard are typed on
+/// the Atari keyboard, independently of the host keyboard layout
+pub fn update_symbolic_keyboard(
+    mut query: Query<&mut AtariSystem, With<Focused>>,
+    mut chars: EventReader<ReceivedCharacter>,
+    keyboard: Res<Input<KeyCode>>,
+    config: Res<EmulatorConfig>,
+) {
+    let is_ctl = keyboard.pressed(KeyCode::LControl) || keyboard.pressed(KeyCode::RControl);
+    let is_alt = keyboard.pressed(KeyCode::LAlt) || keyboard.pressed(KeyCode::RAlt);
+    // control combinations are passed as key positions, AltGr may be reported as Ctrl+Alt
+    let text: String = chars
+        .iter()
+        .map(|event| event.char)
+        .filter(|c| !c.is_control() && pokey::char_to_kbcode(*c).is_some())
+        .collect();
+    if !config.symbolic_keyboard || (is_ctl && !is_alt) || text.is_empty() {
+        return;
+    }
+    for mut atari_system in query.iter_mut() {
+        atari_system.keystrokes(&text);
     }
 }
@@ -9,6 +9,7 @@ use bevy_atari_antic::GTIARegs;
 
 use crate::pokey::PokeyRegQueue;
 use crate::pokey::PokeyRegWrite;
+use crate::state::{SaveState, StateReader, StateWriter};
 
 // WRITE
 pub const HPOSP0: usize = 0x00;
@@ -83,11 +84,19 @@ pub struct Gtia {
     pub regs: GTIARegs,
     collisions: [u8; 0x16], // R
     pub trig: [u8; 4],      // R
+    // cleared when trigger is pressed and GRACTL latch is enabled
+    trig_latch: [u8; 4],
     pub gractl: GRACTL,
+    pub vdelay: u8,
     pub consol: u8,
     pub consol_mask: u8,
     pub consol_force_mask: u8,
+    pub ntsc: bool,
     pub pokey_reg_queue: Arc<RefCell<PokeyRegQueue>>,
+    /// registers after each write done by CPU: (scan line, color clock, regs)
+    pub changes: Vec<(usize, usize, GTIARegs)>,
+    /// `changes` are recorded only for displays rendering them
+    pub record_changes: bool,
 }
 
 impl Default for Gtia {
@@ -96,13 +105,18 @@ impl Default for Gtia {
             regs: GTIARegs::default(),
             collisions: [0x00; 0x16],
             trig: [0xff, 0xff, 0xff, 0x00],
+            trig_latch: [0xff; 4],
             gractl: GRACTL::from_bits_truncate(0),
+            vdelay: 0,
             consol: 0x7,
             consol_mask: 0x7,
             consol_force_mask: 0x7, // force option on start;
+            ntsc: false,
             scan_line: 0,
             collision_update_scanline: 0,
             pokey_reg_queue: Default::default(),
+            changes: Vec::new(),
+            record_changes: false,
         }
     }
 }
@@ -117,8 +131,14 @@ impl Gtia {
                 v
             }
             CONSOL => self.consol & self.consol_mask & self.consol_force_mask,
-            TRIG0..=TRIG3 => self.trig[addr - TRIG0],
-            PAL => 0x01, // 0x01 - PAL, 0x0f - NTSC
+            TRIG0..=TRIG3 => self.trig[addr - TRIG0] & self.trig_latch[addr - TRIG0],
+            PAL => {
+                if self.ntsc {
+                    0x0f
+                } else {
+                    0x01
+                }
+            }
             _ => 0x0f,
         };
         // warn!("GTIA read: {:02x}: {:02x}", addr, value);
@@ -147,7 +167,17 @@ impl Gtia {
                     | (_size_pm(value >> 4) << 4)
                     | (_size_pm(value >> 6) << 6)
             }
-            _GRACTL => self.gractl = GRACTL::from_bits_truncate(value),
+            VDELAY => self.vdelay = value,
+            _GRACTL => {
+                self.gractl = GRACTL::from_bits_truncate(value);
+                if self.gractl.contains(GRACTL::TRIGGER_LATCH) {
+                    for (latch, trig) in self.trig_latch.iter_mut().zip(self.trig) {
+                        *latch &= trig;
+                    }
+                } else {
+                    self.trig_latch = [0xff; 4];
+                }
+            }
             CONSOL => {
                 self.consol_mask = 0x7 & !value;
                 self.pokey_reg_queue
@@ -169,8 +199,34 @@ impl Gtia {
             _ => (),
         }
     }
+    /// Records registers changed by CPU write at given color clock
+    pub fn record_change(&mut self, addr: usize, scan_line: usize, hpos: usize) {
+        if self.record_changes && addr & 0x1f <= PRIOR {
+            self.changes.push((scan_line, hpos, self.regs.clone()));
+        }
+    }
+
     pub fn set_trig(&mut self, n: usize, is_pressed: bool) {
         self.trig[n] = if is_pressed { 0 } else { 0x01 };
+        if self.gractl.contains(GRACTL::TRIGGER_LATCH) {
+            self.trig_latch[n] &= self.trig[n];
+        }
+    }
+
+    /// Player / missile graphics loaded by ANTIC DMA on `scan_line`.
+    /// Objects with VDELAY bit set ignore DMA on even scanlines.
+    pub fn pm_dma(&mut self, addr: usize, value: u8, scan_line: usize) {
+        let delayed = if scan_line & 1 == 0 { self.vdelay } else { 0 };
+        match addr {
+            GRAFM => {
+                let mask = (0..4)
+                    .filter(|i| delayed & (1 << i) > 0)
+                    .fold(0, |mask, i| mask | (3 << (i * 2)));
+                self.regs.grafm = (self.regs.grafm & mask) | (value & !mask);
+            }
+            GRAFP0..=GRAFP3 if delayed & (0x10 << (addr - GRAFP0)) > 0 => (),
+            _ => self.write(addr, value),
+        }
     }
 
     pub fn update_collisions_for_scanline(&mut self, collisions: &CollisionsData) {
@@ -229,3 +285,81 @@ impl Gtia {
         // }
     }
 }
+
+impl SaveState for Gtia {
+    fn save_state(&self, writer: &mut StateWriter) {
+        let regs = &self.regs;
+        writer.bytes(&regs.col);
+        writer.bytes(&regs.grafp);
+        writer.u8(regs.grafm);
+        writer.u8(regs.prior);
+        writer.bytes(&regs.hposp);
+        writer.bytes(&regs.hposm);
+        for size in regs.sizep.iter() {
+            writer.u8(*size as u8);
+        }
+        writer.u8(regs.sizem as u8);
+        writer.usize(self.scan_line);
+        writer.usize(self.collision_update_scanline);
+        writer.bytes(&self.collisions);
+        writer.bytes(&self.trig);
+        writer.bytes(&self.trig_latch);
+        writer.u8(self.gractl.bits());
+        writer.u8(self.vdelay);
+        writer.u8(self.consol);
+        writer.u8(self.consol_mask);
+        writer.u8(self.consol_force_mask);
+    }
+
+    fn load_state(&mut self, reader: &mut StateReader) -> Result<(), String> {
+        let regs = &mut self.regs;
+        reader.bytes(&mut regs.col)?;
+        reader.bytes(&mut regs.grafp)?;
+        regs.grafm = reader.u8()?;
+        regs.prior = reader.u8()?;
+        reader.bytes(&mut regs.hposp)?;
+        reader.bytes(&mut regs.hposm)?;
+        for size in regs.sizep.iter_mut() {
+            *size = reader.u8()? as _;
+        }
+        regs.sizem = reader.u8()? as _;
+        self.scan_line = reader.usize()?;
+        self.collision_update_scanline = reader.usize()?;
+        reader.bytes(&mut self.collisions)?;
+        reader.bytes(&mut self.trig)?;
+        reader.bytes(&mut self.trig_latch)?;
+        self.gractl = GRACTL::from_bits_truncate(reader.u8()?);
+        self.vdelay = reader.u8()?;
+        self.consol = reader.u8()?;
+        self.consol_mask = reader.u8()?;
+        self.consol_force_mask = reader.u8()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_trigger_latch() {
+        let mut gtia = Gtia::default();
+        gtia.write(_GRACTL, 0x04);
+        gtia.set_trig(0, true);
+        gtia.set_trig(0, false);
+        assert_eq!(gtia.read(TRIG0), 0);
+        gtia.write(_GRACTL, 0x00);
+        assert_eq!(gtia.read(TRIG0), 1);
+    }
+
+    #[test]
+    fn test_vdelay() {
+        let mut gtia = Gtia::default();
+        gtia.write(VDELAY, 0x10);
+        gtia.pm_dma(GRAFP0, 0xaa, 10);
+        gtia.pm_dma(GRAFP1, 0x55, 10);
+        assert_eq!(gtia.regs.grafp[..2], [0x00, 0x55]);
+        gtia.pm_dma(GRAFP0, 0xaa, 11);
+        assert_eq!(gtia.regs.grafp[0], 0xaa);
+    }
+}
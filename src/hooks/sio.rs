@@ -23,7 +23,7 @@ fn set_sio_status(cpu: &mut MOS6502, atari_system: &mut AtariSystem, status: u8)
 }
 
 pub fn sioint_hook(atari_system: &mut AtariSystem, cpu: &mut MOS6502) {
-    if !atari_system.is_rom_enabled() {
+    if !atari_system.sio_accelerator || !atari_system.is_rom_enabled() {
         return;
     }
     let device = atari_system.read(DDEVIC);
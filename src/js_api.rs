@@ -1,5 +1,4 @@
 use wasm_bindgen::prelude::wasm_bindgen;
-use wasm_bindgen::JsValue;
 
 use crate::messages::{send_message, Message};
 
@@ -35,6 +34,36 @@ pub fn keystrokes(text: String) {
     send_message(Message::KeyStrokes { text });
 }
 
+#[allow(dead_code)]
+#[wasm_bindgen]
+pub fn save_state(path: String) {
+    send_message(Message::SaveState { path });
+}
+
+#[allow(dead_code)]
+#[wasm_bindgen]
+pub fn load_state(path: String) {
+    send_message(Message::LoadState { path });
+}
+
+#[allow(dead_code)]
+#[wasm_bindgen]
+pub fn start_recording() {
+    send_message(Message::StartRecording);
+}
+
+#[allow(dead_code)]
+#[wasm_bindgen]
+pub fn stop_recording(path: String) {
+    send_message(Message::StopRecording { path });
+}
+
+#[allow(dead_code)]
+#[wasm_bindgen]
+pub fn play_movie(path: String) {
+    send_message(Message::PlayMovie { path });
+}
+
 #[allow(dead_code)]
 #[wasm_bindgen]
 pub fn cmd(cmd: String) {
@@ -61,20 +90,3 @@ pub fn reset(cold: bool, disable_basic: Option<bool>) {
 pub fn set_resolution(width: f32, height: f32) {
     send_message(Message::SetResolution { width, height });
 }
-
-#[wasm_bindgen(catch)]
-extern "C" {
-    pub fn pokey_post_message(a: &JsValue);
-    pub fn sio_get_status(device: u8, unit: u8, data: &mut [u8]) -> u8;
-    pub fn sio_get_sector(device: u8, unit: u8, sector: u16, data: &mut [u8]) -> u8;
-    pub fn sio_put_sector(device: u8, unit: u8, sector: u16, data: &[u8]) -> u8;
-
-    #[wasm_bindgen(catch)]
-    pub async fn ls(path: &str) -> Result<JsValue, JsValue>;
-
-    #[wasm_bindgen(catch)]
-    pub async fn readFile(path: &str) -> Result<JsValue, JsValue>;
-
-    #[wasm_bindgen(catch)]
-    pub async fn writeFile(path: &str, contents: &[u8]) -> Result<(), JsValue>;
-}
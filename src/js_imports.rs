@@ -0,0 +1,21 @@
+//! functions provided by the JS side of the web frontend
+use wasm_bindgen::prelude::wasm_bindgen;
+use wasm_bindgen::JsValue;
+
+#[wasm_bindgen(catch)]
+extern "C" {
+    pub fn pokey_post_message(a: &JsValue);
+    pub fn pokey_set_clock(clock: f64);
+    pub fn sio_get_status(device: u8, unit: u8, data: &mut [u8]) -> u8;
+    pub fn sio_get_sector(device: u8, unit: u8, sector: u16, data: &mut [u8]) -> u8;
+    pub fn sio_put_sector(device: u8, unit: u8, sector: u16, data: &[u8]) -> u8;
+
+    #[wasm_bindgen(catch)]
+    pub async fn ls(path: &str) -> Result<JsValue, JsValue>;
+
+    #[wasm_bindgen(catch)]
+    pub async fn readFile(path: &str) -> Result<JsValue, JsValue>;
+
+    #[wasm_bindgen(catch)]
+    pub async fn writeFile(path: &str, contents: &[u8]) -> Result<(), JsValue>;
+}
@@ -0,0 +1,245 @@
+use crate::pokey::{char_to_kbcode, CONTROL, FUNCTION_KEYS, HELP, SHIFT};
+
+/// longest accepted delay, one hour
+const MAX_WAIT_SECONDS: usize = 3600;
+/// one hour at NTSC frame rate
+const MAX_WAIT_FRAMES: usize = MAX_WAIT_SECONDS * 60;
+
+const ESC: u8 = 0x1c;
+const INVERSE: u8 = 0x27;
+
+/// Action taken by the keystrokes queue in a single frame
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Stroke {
+    /// KBCODE pressed, or typed key released
+    Key(Option<u8>),
+    /// console keys pressed, bits of CONSOL
+    Consol(u8),
+    Break(bool),
+    /// number of frames without action
+    Wait(usize),
+    /// delay in milliseconds, converted to frames of the video standard when reached
+    WaitMs(u32),
+    /// waits until OS completed cold start and keyboard buffer (CH) is empty
+    UntilReady,
+}
+
+/// Parses typed text into queue of strokes.
+///
+/// Characters are typed as on US keyboard, control chars below 10 are delays
+/// of (c + 1) seconds. Special keys are enclosed in braces:
+///
+/// * `{RETURN}`, `{ESC}`, `{TAB}`, `{DELETE}`, `{CLEAR}`, `{CAPS}`, `{INVERSE}`, `{HELP}`,
+///   `{SPACE}`, `{UP}`, `{DOWN}`, `{LEFT}`, `{RIGHT}`, `{F1}` - `{F4}` or single character
+///   key name, with optional `CTRL-` / `SHIFT-` prefixes, eg. `{CTRL-A}`, `{SHIFT-RETURN}`
+/// * `{ATASCII 123}`, `{ATASCII $7b}` - screen editor keys producing the character,
+///   codes above $7f are typed in inverse video mode
+/// * `{START}`, `{SELECT}`, `{OPTION}`, `{BREAK}`, console keys may be held longer,
+///   eg. `{OPTION 3s}`
+/// * `{WAIT 10}` waits 10 frames, `{WAIT 2s}` - 2 seconds at the frame rate of the
+///   configured video standard
+/// * `{UNTIL-READY}` waits until OS is ready for keyboard input
+pub fn parse(text: &str) -> Result<Vec<Stroke>, String> {
+    let mut strokes = Vec::new();
+    let mut chars = text.chars();
+    while let Some(c) = chars.next() {
+        if c == '{' {
+            let mut name = String::new();
+            loop {
+                match chars.next() {
+                    Some('}') => break,
+                    Some(c) => name.push(c),
+                    None => return Err(format!("unterminated escape: {{{}", name)),
+                }
+            }
+            escape(&name, &mut strokes)?;
+        } else if (c as u32) < 10 {
+            strokes.push(Stroke::WaitMs((c as u32 + 1) * 1000));
+        } else if let Some(kbcode) = char_to_kbcode(c) {
+            key(&mut strokes, kbcode);
+            if c == '\n' {
+                idle(&mut strokes, 16);
+            }
+        }
+    }
+    Ok(strokes)
+}
+
+fn idle(strokes: &mut Vec<Stroke>, frames: usize) {
+    delay(strokes, Stroke::Wait(frames));
+}
+
+fn delay(strokes: &mut Vec<Stroke>, stroke: Stroke) {
+    if !matches!(stroke, Stroke::Wait(0) | Stroke::WaitMs(0)) {
+        strokes.push(stroke);
+    }
+}
+
+fn key(strokes: &mut Vec<Stroke>, kbcode: u8) {
+    strokes.push(Stroke::Key(Some(kbcode)));
+    strokes.push(Stroke::Key(None));
+    idle(strokes, 2);
+}
+
+/// number of frames, or seconds with `s` suffix, up to one hour
+fn duration(arg: &str) -> Result<Stroke, String> {
+    let invalid = || format!("invalid duration: {}", arg);
+    if let Some(seconds) = arg.strip_suffix('S') {
+        let seconds: f32 = seconds.trim().parse().map_err(|_| invalid())?;
+        if !(0.0..=MAX_WAIT_SECONDS as f32).contains(&seconds) {
+            return Err(invalid());
+        }
+        Ok(Stroke::WaitMs((seconds * 1000.0).round() as u32))
+    } else {
+        match arg.parse() {
+            Ok(frames) if frames <= MAX_WAIT_FRAMES => Ok(Stroke::Wait(frames)),
+            _ => Err(invalid()),
+        }
+    }
+}
+
+fn escape(name: &str, strokes: &mut Vec<Stroke>) -> Result<(), String> {
+    let upper = name.trim().to_ascii_uppercase();
+    let (command, arg) = match upper.split_once(' ') {
+        Some((command, arg)) => (command, Some(arg.trim())),
+        None => (upper.as_str(), None),
+    };
+    let consol = match command {
+        "START" => 1,
+        "SELECT" => 2,
+        "OPTION" => 4,
+        _ => 0,
+    };
+    match (command, arg) {
+        (_, arg) if consol > 0 => {
+            strokes.push(Stroke::Consol(consol));
+            let held = arg.map(duration).transpose()?;
+            delay(strokes, held.unwrap_or(Stroke::Wait(2)));
+            strokes.push(Stroke::Consol(0));
+            idle(strokes, 2);
+        }
+        ("BREAK", None) => {
+            strokes.push(Stroke::Break(true));
+            strokes.push(Stroke::Break(false));
+            idle(strokes, 2);
+        }
+        ("WAIT", Some(arg)) => delay(strokes, duration(arg)?),
+        ("UNTIL-READY", None) => strokes.push(Stroke::UntilReady),
+        ("ATASCII", Some(arg)) => {
+            let code = match arg.strip_prefix('$') {
+                Some(hex) => u8::from_str_radix(hex, 16),
+                None => arg.parse(),
+            }
+            .map_err(|_| format!("invalid ATASCII code: {}", arg))?;
+            for kbcode in atascii_keys(code)? {
+                key(strokes, kbcode);
+            }
+        }
+        (_, None) => {
+            let mut name = command;
+            let mut modifiers = 0;
+            loop {
+                if let Some(rest) = name.strip_prefix("CTRL-").filter(|rest| !rest.is_empty()) {
+                    modifiers |= CONTROL;
+                    name = rest;
+                } else if let Some(rest) =
+                    name.strip_prefix("SHIFT-").filter(|rest| !rest.is_empty())
+                {
+                    modifiers |= SHIFT;
+                    name = rest;
+                } else {
+                    break;
+                }
+            }
+            let kbcode = key_code(name).ok_or_else(|| format!("unknown key: {{{}}}", name))?;
+            key(strokes, kbcode | modifiers);
+        }
+        _ => return Err(format!("invalid escape: {{{}}}", name)),
+    }
+    Ok(())
+}
+
+/// KBCODE of key name, single character is the unshifted key
+fn key_code(name: &str) -> Option<u8> {
+    let mut chars = name.chars();
+    if let (Some(c), None) = (chars.next(), chars.next()) {
+        return char_to_kbcode(c.to_ascii_lowercase());
+    }
+    Some(match name {
+        "RETURN" => 0x0c,
+        "ESC" => ESC,
+        "TAB" => 0x2c,
+        "SPACE" => 0x21,
+        "DELETE" => 0x34,
+        "CLEAR" => 0x36 | SHIFT,
+        "CAPS" => 0x3c,
+        "INVERSE" => INVERSE,
+        "HELP" => HELP,
+        "UP" => 0x0e | CONTROL,
+        "DOWN" => 0x0f | CONTROL,
+        "LEFT" => 0x06 | CONTROL,
+        "RIGHT" => 0x07 | CONTROL,
+        "F1" => FUNCTION_KEYS[0],
+        "F2" => FUNCTION_KEYS[1],
+        "F3" => FUNCTION_KEYS[2],
+        "F4" => FUNCTION_KEYS[3],
+        _ => return None,
+    })
+}
+
+/// keys typing ATASCII character in the screen editor
+fn atascii_keys(code: u8) -> Result<Vec<u8>, String> {
+    let keys = match code & 0x7f {
+        0x00 => vec![0x20 | CONTROL],
+        c @ 0x01..=0x1a => vec![char_to_kbcode((b'a' + c - 1) as char).unwrap() | CONTROL],
+        0x1b => vec![ESC, ESC],
+        // cursor movement characters
+        c @ 0x1c..=0x1f => vec![ESC, [0x0e, 0x0f, 0x06, 0x07][(c - 0x1c) as usize] | CONTROL],
+        0x60 => vec![0x22 | CONTROL],
+        0x7b => vec![0x02 | CONTROL],
+        0x7d => vec![ESC, 0x36 | SHIFT],
+        0x7e => vec![ESC, 0x34],
+        0x7f => vec![ESC, 0x2c],
+        c => match char_to_kbcode(c as char) {
+            Some(kbcode) => vec![kbcode],
+            None => return Err(format!("no key for ATASCII code: ${:02x}", code)),
+        },
+    };
+    Ok(if code & 0x80 > 0 {
+        [&[INVERSE][..], &keys[..], &[INVERSE][..]].concat()
+    } else {
+        keys
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(parse("{ATASCII 1}"), parse("{CTRL-A}"));
+        assert_eq!(parse("{WAIT 2s}"), Ok(vec![Stroke::WaitMs(2000)]));
+        assert!(parse("{WAIT 99999999999}").is_err());
+        assert!(parse("{WAIT 18446744073709551615}").is_err());
+        assert!(parse("{WAIT 1e30s}").is_err());
+        assert!(parse("{FOO}").is_err());
+        assert!(parse("{WAIT}").is_err());
+        assert!(parse("{CTRL-A").is_err());
+    }
+
+    #[test]
+    fn test_atascii_keys() {
+        assert_eq!(atascii_keys(0x41), Ok(vec![0x7f]));
+        assert_eq!(atascii_keys(0xc1), Ok(vec![INVERSE, 0x7f, INVERSE]));
+        // every code is typed with at least one key
+        for code in 0..=0xff {
+            let keys = atascii_keys(code);
+            assert!(
+                matches!(keys, Ok(keys) if !keys.is_empty()),
+                "${:02x}",
+                code
+            );
+        }
+    }
+}
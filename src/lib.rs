@@ -0,0 +1,35 @@
+//! Atari XL/XE emulator core, usable without Bevy app, see `machine::Machine`.
+//!
+//! The emulator binary (`main.rs`) adds rendering, audio output, debugger and UI on top of it.
+#[macro_use]
+extern crate bitflags;
+pub mod antic;
+mod atari800_state;
+// pub mod atari_text;
+pub mod atr;
+mod cartridge;
+pub mod config;
+pub mod gamepad;
+pub mod gtia;
+pub use config::EmulatorConfig;
+#[cfg(target_arch = "wasm32")]
+pub mod js_imports;
+pub mod keystrokes;
+pub mod machine;
+pub mod mapping;
+pub mod movie;
+pub mod multiplexer;
+pub mod pia;
+pub mod platform;
+pub mod pokey;
+pub mod resources;
+pub mod rewind;
+pub mod sio;
+pub mod software_render;
+pub mod state;
+
+pub mod focus;
+
+mod hooks;
+pub mod system;
+pub mod time_used_plugin;
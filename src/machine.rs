@@ -0,0 +1,865 @@
+use bevy::prelude::{info, warn, Input, KeyCode};
+use emulator_6502::{Interface6502, MOS6502};
+
+use crate::antic::{self, AnticDisplay};
+use crate::cartridge::Cartridge;
+use crate::config::{EmulatorConfig, MachineProfile};
+use crate::mapping::InputMapping;
+use crate::system::AtariSystem;
+use crate::{atari800_state, atr, hooks, movie, state};
+
+const XEX_LOADER: &[u8; 144] = include_bytes!("../xex_loader/xex_loader.atr");
+
+fn xex2atr(data: &[u8]) -> Vec<u8> {
+    let n_sectors = (data.len() + 127) / 128 + 1;
+    let size = n_sectors * 128 / 16; // size in paragraphs;
+    let size_h = (size / 256) as u8;
+    let size_l = (size % 256) as u8;
+
+    let mut atr_buf = vec![0; n_sectors * 128 + 16];
+
+    atr_buf[0..144].copy_from_slice(XEX_LOADER);
+    atr_buf[144..144 + data.len()].copy_from_slice(data);
+    atr_buf[2] = size_l;
+    atr_buf[3] = size_h;
+
+    // the last 6 bytes of sector have special meaning
+    // first 3 is lenght of xex file
+    // remaining ones is xex reading offset, zeroed on start
+    atr_buf[144 - 6] = (data.len() & 0xff) as u8;
+    atr_buf[144 - 5] = ((data.len() >> 8) & 0xff) as u8;
+    atr_buf[144 - 4] = ((data.len() >> 16) & 0xff) as u8;
+    atr_buf
+}
+
+pub fn set_binary(
+    atari_system: &mut AtariSystem,
+    cpu: &mut MOS6502,
+    key: &str,
+    path: &str,
+    data: Option<&[u8]>,
+) {
+    if !matches!(key, "xex" | "state" | "movie") {
+        if !atari_system.movie.is_idle() {
+            warn!("media changed, stopping movie");
+            movie::stop(atari_system);
+        }
+        match data {
+            Some(data) => atari_system
+                .media
+                .insert(key.to_string(), movie::media_hash(data)),
+            None => atari_system.media.remove(key),
+        };
+    }
+    match key {
+        "basic" => {
+            atari_system.set_basic(data);
+        }
+        "game" => {
+            atari_system.set_game(data);
+        }
+        "bios5200" => {
+            atari_system.set_bios5200(data);
+        }
+        "osrom" => {
+            info!("loading osrom, len: {:?}", data.as_ref().map(|v| v.len()));
+            atari_system.set_osrom(data);
+        }
+        "disk_1" | "disk_2" | "disk_3" | "disk_4" => {
+            let n = (key.bytes().nth(5).unwrap() - 48 - 1) as usize;
+            atari_system.set_disk(
+                n,
+                data.and_then(|data| {
+                    atr::ATR::new(path, data)
+                        .map_err(|err| warn!("cannot load disk: {}", err))
+                        .ok()
+                }),
+            );
+        }
+        "xex" => {
+            let data = data.map(xex2atr);
+            set_binary(
+                atari_system,
+                cpu,
+                "disk_1",
+                path,
+                data.as_ref().map(|v| v.as_slice()),
+            );
+        }
+        "car" => {
+            let is_5200 = atari_system.machine_profile() == MachineProfile::Atari5200;
+            atari_system.set_cart(data.and_then(|data| {
+                if is_5200 && !<dyn Cartridge>::is_car(data) {
+                    <dyn Cartridge>::from_raw_5200(data)
+                } else {
+                    <dyn Cartridge>::from_bytes(data)
+                }
+                .map_err(|err| warn!("cannot load cartridge: {}", err))
+                .ok()
+            }));
+        }
+        "state" => {
+            if let Some(data) = data {
+                let data = state::gunzip(data).unwrap_or_else(|_| data.to_vec());
+                if state::is_native_state(&data) {
+                    if let Err(err) = state::load(atari_system, cpu, &data) {
+                        warn!("cannot load state: {}", err);
+                    }
+                } else {
+                    match atari800_state::Atari800State::new(&data) {
+                        Ok(a800_state) => a800_state.reload(atari_system, cpu),
+                        Err(err) => warn!("cannot load state: {}", err),
+                    }
+                }
+            }
+        }
+        "mapping" => {
+            atari_system.input_mapping = match data.map(InputMapping::from_json) {
+                Some(Ok(mapping)) => mapping,
+                Some(Err(err)) => {
+                    warn!("cannot load input mapping: {}", err);
+                    return;
+                }
+                None => InputMapping::default(),
+            };
+        }
+        "movie" => {
+            let result = data
+                .ok_or_else(|| "no movie data".to_string())
+                .and_then(movie::Movie::from_bytes)
+                .and_then(|m| movie::start_playback(atari_system, cpu, m));
+            if let Err(err) = result {
+                warn!("cannot play movie: {}", err);
+            }
+        }
+        _ => {
+            warn!("unknown binary");
+        }
+    }
+}
+
+#[inline(always)]
+pub fn is_frame_start(atari_system: &AtariSystem) -> bool {
+    (atari_system.antic.scan_line, atari_system.antic.cycle) == (0, 0)
+}
+
+/// Start of the vertical blank, at the same scanline for PAL and NTSC
+#[inline(always)]
+pub fn is_frame_end(atari_system: &AtariSystem) -> bool {
+    (atari_system.antic.scan_line, atari_system.antic.cycle) == (248, 0)
+}
+
+/// Passes host keyboard state to the emulated machine, called once per frame.
+pub fn handle_keyboard(
+    atari_system: &mut AtariSystem,
+    cpu: &mut MOS6502,
+    keyboard: &mut Input<KeyCode>,
+    config: &EmulatorConfig,
+) {
+    if atari_system.handle_keyboard(keyboard, cpu, config) {
+        atari_system.request_irq(cpu);
+    }
+}
+
+/// Executes single CPU cycle, `antic::tick` has to be called first.
+/// Returns true if the current instruction is finished.
+#[inline(always)]
+pub fn cpu_tick<D: AnticDisplay>(
+    atari_system: &mut AtariSystem,
+    cpu: &mut MOS6502,
+    display: &D,
+) -> bool {
+    atari_system.cpu_cycle(cpu);
+    hooks::hook(cpu, atari_system);
+
+    let finished_instr = atari_system.cpu_remaining_cycles(cpu) == 0;
+    if finished_instr {
+        antic::post_instr_tick(atari_system, display.collisions_data());
+        // POKEY IRQ is level triggered, request it until acknowledged
+        if atari_system.pokey.irq_line() && cpu.get_status_register() & 0x04 == 0 {
+            atari_system.request_irq(cpu);
+        }
+    }
+    atari_system.inc_cycle();
+    if atari_system.movie.is_playing() {
+        movie::play_events(atari_system);
+    }
+    finished_instr
+}
+
+/// Atari machine running without Bevy app, rendering backend or audio output.
+///
+/// Video output goes to the `AnticDisplay` passed to run methods,
+/// `antic::NullDisplay` may be used if it is not needed.
+pub struct Machine {
+    pub system: AtariSystem,
+    pub cpu: MOS6502,
+    pub config: EmulatorConfig,
+    keyboard: Input<KeyCode>,
+}
+
+impl Default for Machine {
+    fn default() -> Self {
+        Machine::new()
+    }
+}
+
+impl Machine {
+    pub fn new() -> Self {
+        let mut machine = Self {
+            system: AtariSystem::new_headless(),
+            cpu: MOS6502::default(),
+            config: EmulatorConfig::default(),
+            keyboard: Input::default(),
+        };
+        machine.system.configure(&machine.config);
+        machine
+    }
+
+    pub fn configure(&mut self, config: EmulatorConfig) {
+        self.system.configure(&config);
+        self.config = config;
+    }
+
+    /// Loads media, `key` is one of: `osrom`, `bios5200`, `basic`, `game`, `disk_1`..`disk_4`, `xex`, `car`, `state`, `movie`, `mapping`
+    pub fn set_binary(&mut self, key: &str, path: &str, data: Option<&[u8]>) {
+        set_binary(&mut self.system, &mut self.cpu, key, path, data);
+    }
+
+    /// Gzipped snapshot of the machine, see `state::save`
+    pub fn save_state(&self) -> Vec<u8> {
+        state::save(&self.system, &self.cpu)
+    }
+
+    pub fn load_state(&mut self, data: &[u8]) -> Result<(), String> {
+        state::load(&mut self.system, &mut self.cpu, data)
+    }
+
+    /// Starts recording inputs, see `movie::start_recording`
+    pub fn start_recording(&mut self) {
+        movie::start_recording(&mut self.system, &mut self.cpu);
+    }
+
+    /// Stops recording, returns serialized movie
+    pub fn stop_recording(&mut self) -> Option<Vec<u8>> {
+        movie::stop_recording(&mut self.system).map(|movie| movie.to_bytes())
+    }
+
+    pub fn play_movie(&mut self, data: &[u8]) -> Result<(), String> {
+        let movie = movie::Movie::from_bytes(data)?;
+        movie::start_playback(&mut self.system, &mut self.cpu, movie)
+    }
+
+    pub fn reset(&mut self, cold: bool, disable_basic: bool) {
+        self.system.reset(&mut self.cpu, cold, disable_basic);
+    }
+
+    /// Runs single CPU cycle, returns true if instruction is finished
+    pub fn step<D: AnticDisplay>(&mut self, display: &mut D) -> bool {
+        if is_frame_start(&self.system) {
+            handle_keyboard(
+                &mut self.system,
+                &mut self.cpu,
+                &mut self.keyboard,
+                &self.config,
+            );
+            self.keyboard.clear();
+        }
+        antic::tick(&mut self.system, &mut self.cpu, display);
+        let finished_instr = cpu_tick(&mut self.system, &mut self.cpu, display);
+        if is_frame_end(&self.system) {
+            self.system.pokey.send_regs();
+        }
+        finished_instr
+    }
+
+    /// Runs at least `cycles` cycles (including ones stolen by ANTIC DMA)
+    pub fn run_cycles<D: AnticDisplay>(&mut self, display: &mut D, cycles: u64) {
+        let end = self.system.antic.total_cycles + cycles;
+        while self.system.antic.total_cycles < end {
+            self.step(display);
+        }
+    }
+
+    /// Runs emulation until the end of visible part of the frame
+    pub fn run_frame<D: AnticDisplay>(&mut self, display: &mut D) {
+        loop {
+            self.step(display);
+            if is_frame_end(&self.system) {
+                break;
+            }
+        }
+    }
+
+    pub fn run_frames<D: AnticDisplay>(&mut self, display: &mut D, frames: usize) {
+        for _ in 0..frames {
+            self.run_frame(display);
+        }
+    }
+
+    pub fn read(&mut self, addr: u16) -> u8 {
+        self.system.read(addr)
+    }
+
+    pub fn write(&mut self, addr: u16, value: u8) {
+        self.system.write(addr, value)
+    }
+
+    pub fn copy_to_slice(&mut self, addr: u16, data: &mut [u8]) {
+        self.system.copy_to_slice(addr, data)
+    }
+
+    pub fn copy_from_slice(&mut self, addr: u16, data: &[u8]) {
+        self.system.copy_from_slice(addr, data)
+    }
+
+    pub fn press_key(&mut self, key_code: KeyCode) {
+        self.keyboard.press(key_code);
+    }
+
+    pub fn release_key(&mut self, key_code: KeyCode) {
+        self.keyboard.release(key_code);
+    }
+
+    pub fn keystrokes(&mut self, text: &str) {
+        self.system.keystrokes(text);
+    }
+
+    pub fn set_joystick(&mut self, port: usize, dirs: u8, fire: bool) {
+        self.system.set_joystick(1, port, dirs, fire);
+    }
+
+    pub fn set_consol(&mut self, state: u8) {
+        self.system.update_consol(1, state);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::antic::NullDisplay;
+    use crate::pokey;
+    use crate::sio;
+
+    /// machine looping on `JMP $2000` with IRQs disabled and POKEY keyboard scan enabled
+    fn idle_machine() -> Machine {
+        let mut machine = Machine::new();
+        machine.copy_from_slice(0x2000, &[0x4c, 0x00, 0x20]);
+        machine.cpu.set_program_counter(0x2000);
+        machine.cpu.set_status_register(0x24);
+        machine.write(0xd20f, 0x03);
+        machine
+    }
+
+    #[test]
+    fn test_run_cycles() {
+        let mut machine = Machine::new();
+        // LDA #$42; STA $0600; JMP $2005
+        machine.copy_from_slice(0x2000, &[0xa9, 0x42, 0x8d, 0x00, 0x06, 0x4c, 0x05, 0x20]);
+        machine.cpu.set_program_counter(0x2000);
+        machine.run_cycles(&mut NullDisplay, 1000);
+        assert_eq!(machine.read(0x600), 0x42);
+    }
+
+    #[test]
+    fn test_run_frame() {
+        let mut machine = idle_machine();
+        machine.run_frame(&mut NullDisplay);
+        assert!(is_frame_end(&machine.system));
+        machine.run_frame(&mut NullDisplay);
+        assert_eq!(machine.system.antic.total_cycles, (248 + 312) * 114);
+    }
+
+    #[test]
+    fn test_ntsc_frame() {
+        let mut machine = idle_machine();
+        machine.configure(EmulatorConfig {
+            video_standard: crate::config::VideoStandard::Ntsc,
+            ..Default::default()
+        });
+        machine.run_frames(&mut NullDisplay, 2);
+        assert_eq!(machine.system.antic.total_cycles, (248 + 262) * 114);
+        assert_eq!(machine.read(0xd014), 0x0f);
+    }
+
+    #[test]
+    fn test_130xe_antic_access() {
+        let mut machine = Machine::new();
+        machine.configure(EmulatorConfig {
+            memory: crate::config::MemoryModel::Mem130XE,
+            ..Default::default()
+        });
+        machine.write(0x4000, 0x11);
+        // PORTB output mode, CPU access to bank 0
+        machine.write(0xd303, 0x04);
+        machine.write(0xd301, 0xe3);
+        machine.write(0x4000, 0x22);
+        assert_eq!(machine.read(0x4000), 0x22);
+        assert_eq!(machine.system.antic_read(0x4000), 0x11);
+        // ANTIC access only
+        machine.write(0xd301, 0xd3);
+        assert_eq!(machine.read(0x4000), 0x11);
+        assert_eq!(machine.system.antic_read(0x4000), 0x22);
+    }
+
+    #[test]
+    fn test_800_profile() {
+        let mut machine = Machine::new();
+        machine.configure(EmulatorConfig {
+            machine: crate::config::MachineProfile::Atari800,
+            ..Default::default()
+        });
+        machine.set_binary("osrom", "atariosb.rom", Some(&[0x42; 0x2800]));
+        // disabling OS with PORTB has no effect on 800
+        machine.write(0xd303, 0x04);
+        machine.write(0xd301, 0xfe);
+        assert_eq!(machine.read(0xe000), 0x42);
+        assert_eq!(machine.read(0xc000), 0xff);
+    }
+
+    #[test]
+    fn test_xegs_game_size() {
+        let mut machine = Machine::new();
+        machine.configure(EmulatorConfig {
+            machine: crate::config::MachineProfile::XEGS,
+            ..Default::default()
+        });
+        // PORTB output mode, game enabled
+        machine.write(0xd303, 0x04);
+        machine.write(0xd301, 0xbf);
+        machine.write(0xa000, 0x11);
+        // invalid image is ignored
+        machine.set_binary("game", "game.rom", Some(&[0x42; 100]));
+        assert_eq!(machine.read(0xa000), 0x11);
+        machine.set_binary("game", "game.rom", Some(&[0x42; 0x2000]));
+        assert_eq!(machine.read(0xa000), 0x42);
+        machine.set_binary("game", "game.rom", None);
+        assert_eq!(machine.read(0xa000), 0x11);
+    }
+
+    #[test]
+    fn test_5200_profile() {
+        let mut machine = Machine::new();
+        machine.configure(EmulatorConfig {
+            machine: crate::config::MachineProfile::Atari5200,
+            ..Default::default()
+        });
+        machine.set_binary("bios5200", "5200.rom", Some(&[0x42; 0x800]));
+        let mut cart = vec![0; 0x4000];
+        cart[0] = 0x11;
+        cart[0x2000] = 0x22;
+        machine.set_binary("car", "game.a52", Some(&cart));
+        assert_eq!(machine.read(0xf800), 0x42);
+        assert_eq!(machine.read(0xf000), 0x42);
+        // 16k two chip cartridge, mirrored 8k halves
+        assert_eq!(machine.read(0x4000), 0x11);
+        assert_eq!(machine.read(0x6000), 0x11);
+        assert_eq!(machine.read(0x8000), 0x22);
+        assert_eq!(machine.read(0xa000), 0x22);
+        machine.write(0x4000, 0x33);
+        assert_eq!(machine.read(0x4000), 0x11);
+        // GTIA at $C000, no PIA
+        assert_eq!(machine.read(0xc014), 0x01);
+        assert_eq!(machine.read(0xd300), 0xff);
+        // analog stick on POKEY pots at $E800
+        assert_eq!(machine.read(0xe800), 114);
+        machine.set_joystick(0, 4, false);
+        assert_eq!(machine.read(0xe800), 6);
+        assert_eq!(machine.read(0xe801), 114);
+    }
+
+    #[test]
+    fn test_sio_read() {
+        let mut machine = idle_machine();
+        // single density disk, first sector filled with 0x55
+        let mut atr = vec![0; 16 + 3 * 128];
+        atr[0..6].copy_from_slice(&[0x96, 0x02, 0x18, 0x00, 0x80, 0x00]);
+        atr[16..16 + 128].fill(0x55);
+        machine.set_binary("disk_1", "test.atr", Some(&atr));
+        machine.write(0xd20f, 0x13);
+        // SERIN and XMTDONE interrupts
+        machine.write(0xd20e, 0x28);
+        // command line asserted
+        machine.write(0xd303, 0x34);
+        let frame = [0x31, 0x52, 0x01, 0x00];
+        for v in frame.iter().chain(&[sio::checksum(&frame)]) {
+            machine.write(0xd20d, *v);
+            machine.run_cycles(&mut NullDisplay, 1000);
+        }
+        assert_eq!(machine.read(0xd20e) & 0x08, 0);
+        machine.write(0xd303, 0x3c);
+        let mut received = vec![];
+        for _ in 0..1000 {
+            machine.run_cycles(&mut NullDisplay, 100);
+            if machine.read(0xd20e) & 0x20 == 0 {
+                received.push(machine.read(0xd20d));
+                machine.write(0xd20e, 0x08);
+                machine.write(0xd20e, 0x28);
+            }
+        }
+        assert_eq!(received.len(), 2 + 128 + 1);
+        assert_eq!(&received[..2], &[sio::ACK, sio::COMPLETE]);
+        assert!(received[2..130].iter().all(|v| *v == 0x55));
+        assert_eq!(received[130], sio::checksum(&[0x55; 128]));
+    }
+
+    #[test]
+    fn test_pokey_timer_irq() {
+        let mut machine = Machine::new();
+        // IRQ vector of empty OS ROM points to $0000
+        // LDA #0; STA IRQEN; INC $0600; RTI
+        machine.copy_from_slice(
+            0x0000,
+            &[0xa9, 0x00, 0x8d, 0x0e, 0xd2, 0xee, 0x00, 0x06, 0x40],
+        );
+        // CLI; JMP $2001
+        machine.copy_from_slice(0x2000, &[0x58, 0x4c, 0x01, 0x20]);
+        machine.cpu.set_program_counter(0x2000);
+        // 64kHz clock, timer 1 period is 10 * 28 cycles
+        machine.write(0xd208, 0x00);
+        machine.write(0xd200, 9);
+        machine.write(0xd20e, 0x01);
+        machine.write(0xd209, 0);
+        machine.run_cycles(&mut NullDisplay, 250);
+        assert_eq!(machine.read(0x600), 0);
+        machine.run_cycles(&mut NullDisplay, 100);
+        assert_eq!(machine.read(0x600), 1);
+        machine.run_cycles(&mut NullDisplay, 1000);
+        assert_eq!(machine.read(0x600), 1);
+    }
+
+    #[test]
+    fn test_pot_scan() {
+        let mut machine = idle_machine();
+        machine.system.set_paddle(0, 10, true);
+        // POTGO
+        machine.write(0xd20b, 0);
+        machine.run_cycles(&mut NullDisplay, 5 * 114);
+        assert_eq!(machine.read(0xd200), 5);
+        assert_eq!(machine.read(0xd208), 0xff);
+        machine.run_cycles(&mut NullDisplay, 10 * 114);
+        assert_eq!(machine.read(0xd200), 10);
+        assert_eq!(machine.read(0xd208), 0xfe);
+        // fast pot scan
+        machine.write(0xd20f, 0x07);
+        machine.write(0xd20b, 0);
+        machine.run_cycles(&mut NullDisplay, 20);
+        assert_eq!(machine.read(0xd200), 10);
+        // POTx keeps the latched value until the next POTGO
+        machine.system.set_paddle(0, 5, true);
+        assert_eq!(machine.read(0xd200), 10);
+        machine.write(0xd20b, 0);
+        machine.run_cycles(&mut NullDisplay, 20);
+        assert_eq!(machine.read(0xd200), 5);
+        // paddle trigger is on joystick left line
+        machine.write(0xd302, 0x04);
+        assert_eq!(machine.read(0xd300) & 0x04, 0);
+    }
+
+    #[test]
+    fn test_800_reset_key() {
+        let mut machine = idle_machine();
+        machine.configure(EmulatorConfig {
+            machine: MachineProfile::Atari800,
+            ..Default::default()
+        });
+        machine.reset(false, true);
+        machine.run_cycles(&mut NullDisplay, 2 * 114);
+        // NMI handler of empty OS ROM is at $0000
+        assert_eq!(machine.read(0xd40f), 0x3f);
+        assert_ne!(machine.cpu.get_program_counter() & 0xff00, 0x2000);
+    }
+
+    #[test]
+    fn test_cpu_collisions() {
+        let mut machine = idle_machine();
+        // 24 blank lines, mode F line at $4000, JVB
+        let dlist = [0x70, 0x70, 0x70, 0x4f, 0x00, 0x40, 0x41, 0x00, 0x30];
+        machine.copy_from_slice(0x3000, &dlist);
+        machine.copy_from_slice(0x4000, &[0xff; 40]);
+        machine.write(0xd402, 0x00);
+        machine.write(0xd403, 0x30);
+        machine.write(0xd400, 0x22);
+        // player 0 and 1 overlapping in the middle of the screen
+        machine.write(0xd000, 0x80);
+        machine.write(0xd001, 0x84);
+        machine.write(0xd00d, 0xff);
+        machine.write(0xd00e, 0xff);
+        machine.run_frames(&mut NullDisplay, 2);
+        // lit pixels of mode F collide as PF2
+        assert_eq!(machine.read(0xd004), 0x04);
+        assert_eq!(machine.read(0xd00c), 0x02);
+        assert_eq!(machine.read(0xd00d), 0x01);
+        machine.write(0xd01e, 0);
+        assert_eq!(machine.read(0xd004), 0x00);
+    }
+
+    /// counts bytes of ANTIC memory passed to the display
+    #[derive(Default)]
+    struct CountingDisplay {
+        reserved: usize,
+    }
+
+    impl AnticDisplay for CountingDisplay {
+        fn clear(&mut self) {
+            self.reserved = 0;
+        }
+        fn set_gtia_regs(&mut self, _index: usize, _regs: &bevy_atari_antic::GTIARegs) {}
+        fn reserve_antic_memory(&mut self, len: usize, _f: &mut dyn FnMut(&mut [u8])) -> usize {
+            self.reserved += len;
+            self.reserved - len
+        }
+        fn insert_mode_line(&mut self, _mode_line: &bevy_atari_antic::ModeLineDescr) {}
+    }
+
+    #[test]
+    fn test_charset_cache() {
+        let mut machine = Machine::new();
+        // STA $2100; JMP $2000
+        machine.copy_from_slice(0x2000, &[0x8d, 0x00, 0x21, 0x4c, 0x00, 0x20]);
+        machine.cpu.set_program_counter(0x2000);
+        // three mode 2 lines at $4000, JVB
+        let dlist = [0x42, 0x00, 0x40, 0x02, 0x02, 0x41, 0x00, 0x30];
+        machine.copy_from_slice(0x3000, &dlist);
+        machine.write(0xd402, 0x00);
+        machine.write(0xd403, 0x30);
+        machine.write(0xd400, 0x22);
+        machine.write(0xd409, 0xe0);
+        let mut display = CountingDisplay::default();
+        machine.run_frames(&mut display, 2);
+        assert_eq!(display.reserved, 3 * 40 + 1024);
+        // charset modified by CPU is uploaded again
+        machine.write(0xd409, 0x20);
+        machine.run_frames(&mut display, 2);
+        assert!(display.reserved > 3 * 40 + 1024);
+    }
+
+    #[test]
+    fn test_light_pen() {
+        let mut machine = idle_machine();
+        machine.system.set_light_pen(Some((100, 60)));
+        machine.run_frame(&mut NullDisplay);
+        assert_eq!(machine.read(0xd40c), 100);
+        assert_eq!(machine.read(0xd40d), 30);
+        // trigger is on joystick up line
+        machine.system.set_joystick(3, 0, 1, false);
+        machine.write(0xd302, 0x04);
+        assert_eq!(machine.read(0xd300) & 0x01, 0);
+    }
+
+    #[test]
+    fn test_save_load_state() {
+        let mut machine = Machine::new();
+        // INC $0600; JMP $2000
+        machine.copy_from_slice(0x2000, &[0xee, 0x00, 0x06, 0x4c, 0x00, 0x20]);
+        machine.cpu.set_program_counter(0x2000);
+        machine.run_frame(&mut NullDisplay);
+        let state = machine.save_state();
+        let saved = (
+            machine.read(0x600),
+            machine.cpu.get_program_counter(),
+            machine.system.antic.total_cycles,
+        );
+        machine.run_frame(&mut NullDisplay);
+        machine.load_state(&state).unwrap();
+        let restored = (
+            machine.read(0x600),
+            machine.cpu.get_program_counter(),
+            machine.system.antic.total_cycles,
+        );
+        assert_eq!(restored, saved);
+
+        // truncated state is rejected without touching the machine
+        let snapshot = state::snapshot(&machine.system, &machine.cpu);
+        machine.run_frame(&mut NullDisplay);
+        let current = state::snapshot(&machine.system, &machine.cpu);
+        assert!(machine.load_state(&snapshot[..snapshot.len() / 2]).is_err());
+        assert!(state::snapshot(&machine.system, &machine.cpu) == current);
+
+        // disks of truncated state are not attached
+        let atr = |path| {
+            let mut atr = atr::ATR::new(
+                path,
+                &[0x96, 0x02, 0, 0, 0x80, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0],
+            )
+            .unwrap();
+            atr.set_modified(true);
+            atr
+        };
+        machine.system.set_disk(0, Some(atr("a.atr")));
+        let snapshot = state::snapshot(&machine.system, &machine.cpu);
+        machine.system.set_disk(0, Some(atr("b.atr")));
+        let current = state::snapshot(&machine.system, &machine.cpu);
+        assert!(machine.load_state(&snapshot[..snapshot.len() - 1]).is_err());
+        assert!(state::snapshot(&machine.system, &machine.cpu) == current);
+
+        // as well as truncated Atari800 state
+        machine.set_binary("state", "test.a8s", Some(b"ATARI800\x08\x01"));
+        assert!(state::snapshot(&machine.system, &machine.cpu) == current);
+    }
+
+    #[test]
+    fn test_save_load_mid_instruction() {
+        let mut machine = idle_machine();
+        while machine.system.cpu_remaining_cycles(&machine.cpu) == 0 {
+            machine.step(&mut NullDisplay);
+        }
+        let snapshot = state::snapshot(&machine.system, &machine.cpu);
+        machine.run_cycles(&mut NullDisplay, 1000);
+        let expected = state::snapshot(&machine.system, &machine.cpu);
+        machine.load_state(&snapshot).unwrap();
+        machine.run_cycles(&mut NullDisplay, 1000);
+        assert!(state::snapshot(&machine.system, &machine.cpu) == expected);
+
+        // state of other memory model is rejected
+        machine.configure(EmulatorConfig {
+            memory: crate::config::MemoryModel::Mem64,
+            ..Default::default()
+        });
+        assert!(machine.load_state(&snapshot).is_err());
+    }
+
+    #[test]
+    fn test_movie_playback() {
+        let mut machine = Machine::new();
+        // LDA $D20A; EOR $D300; STA ($80),Y; INY; JMP $2000
+        let code = [
+            0xad, 0x0a, 0xd2, 0x4d, 0x00, 0xd3, 0x91, 0x80, 0xc8, 0x4c, 0x00, 0x20,
+        ];
+        machine.copy_from_slice(0x2000, &code);
+        machine.copy_from_slice(0x80, &[0x00, 0x06]);
+        machine.cpu.set_program_counter(0x2000);
+        machine.run_frame(&mut NullDisplay);
+        machine.start_recording();
+        for dirs in [1, 2, 4, 8] {
+            machine.set_joystick(0, dirs, false);
+            machine.run_frame(&mut NullDisplay);
+        }
+        machine.press_key(KeyCode::A);
+        machine.run_frames(&mut NullDisplay, 2);
+        machine.release_key(KeyCode::A);
+        machine.run_frame(&mut NullDisplay);
+        let data = machine.stop_recording().unwrap();
+        // keyboard is recorded only when changed
+        let movie = movie::Movie::from_bytes(&data).unwrap();
+        let keyboard_events = movie
+            .events
+            .iter()
+            .filter(|(_, event)| matches!(event, movie::InputEvent::Keyboard(_)))
+            .count();
+        assert_eq!(keyboard_events, 2);
+        let mut recorded = [0; 256];
+        machine.copy_to_slice(0x600, &mut recorded);
+
+        machine.play_movie(&data).unwrap();
+        machine.run_frames(&mut NullDisplay, 7);
+        assert!(machine.system.movie.is_idle());
+        let mut replayed = [0; 256];
+        machine.copy_to_slice(0x600, &mut replayed);
+        assert_eq!(replayed, recorded);
+    }
+
+    #[test]
+    fn test_keyboard_scan() {
+        let mut machine = idle_machine();
+        machine.write(0xd20e, 0x40);
+        let mut run = |machine: &mut Machine| {
+            machine.run_frames(&mut NullDisplay, 2);
+            let irq = machine.read(0xd20e) & 0x40 == 0;
+            machine.write(0xd20e, 0x00);
+            machine.write(0xd20e, 0x40);
+            (irq, machine.read(0xd209), machine.read(0xd20f) & 0x0c)
+        };
+        machine.press_key(KeyCode::A);
+        assert_eq!(run(&mut machine), (true, 0x3f, 0x08));
+        // second key is reported after the first one is released
+        machine.press_key(KeyCode::B);
+        assert_eq!(run(&mut machine), (false, 0x3f, 0x08));
+        machine.release_key(KeyCode::A);
+        assert_eq!(run(&mut machine), (true, 0x15, 0x08));
+        machine.press_key(KeyCode::LShift);
+        assert_eq!(run(&mut machine), (false, 0x15, 0x00));
+        machine.release_key(KeyCode::B);
+        assert_eq!(run(&mut machine), (false, 0x15, 0x04));
+        machine.press_key(KeyCode::C);
+        assert_eq!(run(&mut machine), (true, 0x52, 0x00));
+        machine.release_key(KeyCode::C);
+        machine.release_key(KeyCode::LShift);
+        assert_eq!(run(&mut machine), (false, 0x52, 0x0c));
+        machine.press_key(KeyCode::F1);
+        assert_eq!(run(&mut machine), (true, pokey::HELP, 0x08));
+        machine.release_key(KeyCode::F1);
+        run(&mut machine);
+        // Alt F2 is 1200XL F2, not START
+        machine.press_key(KeyCode::LAlt);
+        machine.press_key(KeyCode::F2);
+        assert_eq!(run(&mut machine), (true, pokey::FUNCTION_KEYS[1], 0x08));
+        assert_eq!(machine.read(0xd01f) & 0x01, 0x01);
+    }
+
+    #[test]
+    fn test_input_mapping() {
+        let mut machine = idle_machine();
+        machine.write(0xd302, 0x3c);
+        let mapping = br#"{
+            "keys": {"Q": {"joystick": [1, "up"]}, "Tab": "start", "Z": {"key": 63}}
+        }"#;
+        machine.set_binary("mapping", "mapping.json", Some(mapping));
+        machine.press_key(KeyCode::Q);
+        machine.press_key(KeyCode::Tab);
+        machine.press_key(KeyCode::Z);
+        machine.run_frames(&mut NullDisplay, 2);
+        assert_eq!(machine.read(0xd300), 0xef);
+        assert_eq!(machine.read(0xd01f) & 0x01, 0x00);
+        assert_eq!(machine.read(0xd209), 0x3f);
+        // default gamepad bindings are kept, unknown keys are rejected
+        let mapping = InputMapping::from_json(br#"{"keys": {"Q": "start"}}"#).unwrap();
+        assert_eq!(mapping.buttons, InputMapping::default().buttons);
+        assert!(InputMapping::from_json(br#"{"keys": {"Foo": "start"}}"#).is_err());
+        assert!(InputMapping::from_json(br#"{"buttons": {"East": "start"}}"#).is_err());
+    }
+
+    #[test]
+    fn test_keystrokes() {
+        let mut machine = idle_machine();
+        // typing starts ~2 seconds after power on
+        machine.run_frames(&mut NullDisplay, 101);
+        machine.keystrokes("A\"");
+        machine.run_frames(&mut NullDisplay, 2);
+        assert_eq!(machine.read(0xd209), 0x7f);
+        machine.run_frames(&mut NullDisplay, 4);
+        assert_eq!(machine.read(0xd209), 0x5e);
+        assert_eq!(pokey::char_to_kbcode('a'), Some(0x3f));
+        assert_eq!(pokey::char_to_kbcode('ą'), None);
+    }
+
+    #[test]
+    fn test_keystrokes_ntsc() {
+        let mut machine = idle_machine();
+        machine.configure(EmulatorConfig {
+            video_standard: crate::config::VideoStandard::Ntsc,
+            ..Default::default()
+        });
+        machine.run_frames(&mut NullDisplay, 120);
+        // delays in seconds follow the NTSC frame rate
+        machine.keystrokes("{WAIT 1s}a");
+        machine.run_frames(&mut NullDisplay, 55);
+        assert_ne!(machine.read(0xd209), 0x3f);
+        machine.run_frames(&mut NullDisplay, 10);
+        assert_eq!(machine.read(0xd209), 0x3f);
+    }
+
+    #[test]
+    fn test_keystroke_escapes() {
+        let mut machine = idle_machine();
+        machine.run_frames(&mut NullDisplay, 101);
+        machine.keystrokes("{ctrl-a}{START}");
+        machine.run_frames(&mut NullDisplay, 2);
+        assert_eq!(machine.read(0xd209), 0xbf);
+        assert_eq!(machine.read(0xd01f) & 0x07, 0x07);
+        machine.run_frames(&mut NullDisplay, 3);
+        assert_eq!(machine.read(0xd01f) & 0x07, 0x06);
+        machine.run_frames(&mut NullDisplay, 3);
+        assert_eq!(machine.read(0xd01f) & 0x07, 0x07);
+    }
+}
@@ -1,39 +1,20 @@
-#[macro_use]
-extern crate bitflags;
+use bevy_atari::{
+    antic, config, focus, gamepad, machine, mapping, movie, platform, resources, rewind,
+    software_render, state, system, time_used_plugin,
+};
 use std::time::Duration;
-pub mod antic;
-mod atari800_state;
-// pub mod atari_text;
-pub mod atr;
-mod cartridge;
-pub mod config;
-pub mod gamepad;
 pub mod gdb;
-pub mod gtia;
-use config::EmulatorConfig;
+use config::{EmulatorConfig, Renderer};
+use machine::set_binary;
 #[cfg(target_arch = "wasm32")]
 mod js_api;
 pub mod messages;
-pub mod multiplexer;
-pub mod pia;
-pub mod platform;
-pub mod pokey;
-
-pub mod resources;
 #[cfg(feature = "egui")]
 mod ui;
 use gdb::GdbMessage;
 use platform::FileSystem;
 use resources::UIConfig;
 
-pub mod focus;
-
-mod hooks;
-mod system;
-pub mod time_used_plugin;
-
-use crate::cartridge::Cartridge;
-
 include!(concat!(env!("OUT_DIR"), "/build_config.rs"));
 
 #[allow(unused_imports)]
@@ -59,8 +40,11 @@ use bevy_atari_antic::AtariAnticPlugin;
 use emulator_6502::{Interface6502, MOS6502};
 // use render::ANTIC_DATA_HANDLE;
 // use render_resources::{AnticData, CustomTexture, SimpleMaterial};
-use bevy_atari_antic::AnticData;
+use antic::AnticDisplay;
+use bevy_atari_antic::{AnticData, CollisionsData, GTIARegs, ModeLineDescr};
 use focus::Focused;
+use rewind::Rewind;
+use software_render::SoftwareRenderer;
 use system::{Antic, AtariSystem};
 
 // #[global_allocator]
@@ -190,10 +174,67 @@ pub struct AtariBundle {
     system: AtariSystem,
     debugger: Debugger,
     cpu: CPU,
+    rewind: Rewind,
     antic_data_handle: Handle<AnticData>,
     texture: Handle<Image>,
 }
 
+/// frames rendered in software after the last one using GTIA features missing in the GPU renderer
+const GPU_FALLBACK_FRAMES: usize = 50;
+
+/// Software renderer used by GPU renderer for frames it cannot display
+#[derive(Component)]
+pub struct GpuFallback {
+    renderer: SoftwareRenderer,
+    image: Handle<Image>,
+    /// number of next frames rendered in software
+    frames: usize,
+}
+
+/// Display wrapper noting GTIA features not rendered by the GPU renderer:
+/// GTIA modes 9, 10 and 11 and mid-scanline register changes
+struct GpuFeatures<'a, D> {
+    display: &'a mut D,
+    unsupported: bool,
+}
+
+impl<'a, D: AnticDisplay> GpuFeatures<'a, D> {
+    fn new(display: &'a mut D) -> Self {
+        Self {
+            display,
+            unsupported: false,
+        }
+    }
+}
+
+impl<D: AnticDisplay> AnticDisplay for GpuFeatures<'_, D> {
+    fn clear(&mut self) {
+        self.display.clear()
+    }
+    fn set_gtia_regs(&mut self, index: usize, regs: &GTIARegs) {
+        self.unsupported |= regs.prior & 0xc0 > 0;
+        self.display.set_gtia_regs(index, regs)
+    }
+    fn change_gtia_regs(&mut self, index: usize, hpos: usize, regs: &GTIARegs) {
+        // writes after WSYNC (as done by DLIs) change only few pixels of the right border,
+        // GPU renderer shows them from the next scanline
+        self.unsupported |= hpos < antic::WSYNC_RELEASE_CYCLE * 2;
+        self.display.change_gtia_regs(index, hpos, regs)
+    }
+    fn gtia_changes(&self) -> bool {
+        true
+    }
+    fn reserve_antic_memory(&mut self, len: usize, f: &mut dyn FnMut(&mut [u8])) -> usize {
+        self.display.reserve_antic_memory(len, f)
+    }
+    fn insert_mode_line(&mut self, mode_line: &ModeLineDescr) {
+        self.display.insert_mode_line(mode_line)
+    }
+    fn collisions_data(&self) -> Option<&CollisionsData> {
+        self.display.collisions_data()
+    }
+}
+
 // fn gunzip(data: &[u8]) -> Vec<u8> {
 //     let mut decoder = flate2::read::GzDecoder::new(&data[..]);
 //     let mut result = Vec::new();
@@ -226,11 +267,30 @@ fn debug_keyboard(
     mut config: ResMut<UIConfig>,
 ) {
     let is_shift = keyboard.any_pressed([KeyCode::LShift, KeyCode::RShift]);
+    let is_ctl = keyboard.any_pressed([KeyCode::LControl, KeyCode::RControl]);
     if let Some((mut debugger, mut cpu, mut system)) = query.iter_mut().next() {
         for key_code in auto_repeat.pressed(&keyboard) {
             match key_code {
                 KeyCode::F5 => system.reset(&mut cpu.cpu, false, !config.basic),
                 KeyCode::F8 => debugger.paused = !debugger.paused,
+                KeyCode::F9 if is_ctl => {
+                    let path = movie::QUICK_MOVIE_PATH.to_string();
+                    messages::send_message(if is_shift {
+                        messages::Message::PlayMovie { path }
+                    } else if system.movie.is_recording() {
+                        messages::Message::StopRecording { path }
+                    } else {
+                        messages::Message::StartRecording
+                    })
+                }
+                KeyCode::F9 => {
+                    let path = state::QUICK_STATE_PATH.to_string();
+                    messages::send_message(if is_shift {
+                        messages::Message::LoadState { path }
+                    } else {
+                        messages::Message::SaveState { path }
+                    })
+                }
                 KeyCode::F10 => debugger.step_over(&mut system, &cpu.cpu),
                 KeyCode::F11 => debugger.step_into(),
                 KeyCode::F12 => {
@@ -246,209 +306,252 @@ fn debug_keyboard(
     }
 }
 
+#[allow(clippy::too_many_arguments)]
+fn run_frame<D: AnticDisplay>(
+    focused: bool,
+    atari_system: &mut AtariSystem,
+    cpu: &mut MOS6502,
+    debugger: &mut Debugger,
+    display: &mut D,
+    keyboard: &mut Input<KeyCode>,
+    config: &EmulatorConfig,
+) {
+    loop {
+        // movies record and replay keyboard every frame, regardless of focus
+        let keyboard_enabled = focused || !atari_system.movie.is_idle();
+        if keyboard_enabled && machine::is_frame_start(atari_system) {
+            machine::handle_keyboard(atari_system, cpu, keyboard, config);
+        };
+
+        antic::tick(atari_system, cpu, display);
+
+        if debugger.paused {
+            break;
+        }
+
+        let finished_instr = machine::cpu_tick(atari_system, cpu, display);
+
+        if finished_instr {
+            let mut pause = false;
+            for breakpoint in &debugger.breakpoints {
+                match breakpoint {
+                    BreakPoint::PC(pc) => {
+                        if cpu.get_program_counter() == *pc {
+                            pause = true;
+                            break;
+                        }
+                    }
+                    BreakPoint::NotPC(pc) => {
+                        if cpu.get_program_counter() != *pc {
+                            pause = true;
+                            break;
+                        }
+                    }
+                    BreakPoint::IndirectPC(addr) => {
+                        let pc = atari_system.read(*addr) as u16
+                            + atari_system.read(addr + 1) as u16 * 256;
+                        if pc != 0 && cpu.get_program_counter() == pc {
+                            pause = true;
+                            break;
+                        }
+                    }
+                    _ => (),
+                }
+            }
+            if !pause {
+                match debugger.step {
+                    Step::Into => {
+                        pause = true;
+                        debugger.step = Step::None
+                    }
+                    Step::Over { sp } => {
+                        if cpu.get_stack_pointer() == sp {
+                            pause = true;
+                            debugger.step = Step::None;
+                        }
+                    }
+                    Step::NextScanline { scanline } => {
+                        if atari_system.antic.scan_line != scanline {
+                            pause = true;
+                            debugger.step = Step::None;
+                        }
+                    }
+                    _ => (),
+                }
+            }
+            if pause {
+                debugger.pause();
+            }
+        }
+        if machine::is_frame_end(atari_system) {
+            atari_system.pokey.send_regs();
+            if debugger.step == Step::NextFrame {
+                debugger.step = Step::None;
+                debugger.pause();
+            }
+            break;
+        }
+        if debugger.paused && !atari_system.antic.wsync() {
+            break;
+        }
+    }
+}
+
 fn atari_system(
     mut query: Query<(
         Option<&Focused>,
         &mut AtariSystem,
         &mut CPU,
         &mut Debugger,
+        &mut Rewind,
         &Handle<AnticData>,
+        &Handle<Image>,
+        Option<&mut SoftwareRenderer>,
+        Option<&mut GpuFallback>,
     )>,
     mut antic_data_assets: ResMut<Assets<AnticData>>,
+    mut images: ResMut<Assets<Image>>,
     mut keyboard: ResMut<Input<KeyCode>>,
     render_device: Res<RenderDevice>,
     config: Res<EmulatorConfig>,
 ) {
-    for (focused, mut atari_system, mut cpu, mut debugger, antic_data_handle) in query.iter_mut() {
+    for (
+        focused,
+        mut atari_system,
+        mut cpu,
+        mut debugger,
+        mut rewind,
+        antic_data_handle,
+        image_handle,
+        mut software_renderer,
+        mut gpu_fallback,
+    ) in query.iter_mut()
+    {
         atari_system.configure(&config);
-        let mut cpu = &mut cpu.cpu;
-        let antic_data = antic_data_assets.get_mut(antic_data_handle).unwrap();
+        rewind.set_capacity(config.rewind_frames());
+        if config.is_changed() {
+            if let Some(renderer) = software_renderer.as_mut() {
+                renderer.set_palette(config.effective_palette());
+            }
+            if let Some(fallback) = gpu_fallback.as_mut() {
+                fallback.renderer.set_palette(config.effective_palette());
+            }
+        }
+        let cpu = &mut cpu.cpu;
 
-        antic_data.config.debug_scan_line = atari_system.antic.scan_line as i32 - 8;
+        let antic_data = if software_renderer.is_none() {
+            let antic_data = antic_data_assets.get_mut(antic_data_handle).unwrap();
+            antic_data.config.debug_scan_line = atari_system.antic.scan_line as i32 - 8;
+            Some(antic_data)
+        } else {
+            None
+        };
         if debugger.paused {
             continue;
         }
 
-        if let Some(ref collisions_data) = antic_data.collisions_data {
-            collisions_data.read_collisions(&*render_device);
-        }
-
-        loop {
-            if (atari_system.antic.scan_line, atari_system.antic.cycle) == (0, 0) {
-                if focused.is_some()
-                    && atari_system.handle_keyboard(&mut keyboard, &mut cpu, &config)
-                {
-                    cpu.interrupt_request();
+        // rewinding is possible only on frame boundary
+        let rewinding = focused.is_some()
+            && keyboard.pressed(KeyCode::F6)
+            && atari_system.movie.is_idle()
+            && machine::is_frame_end(&atari_system);
+        if rewinding {
+            if let Some(snapshot) = rewind.rewind() {
+                if let Err(err) = state::load(&mut atari_system, cpu, snapshot) {
+                    warn!("cannot rewind: {}", err);
                 }
-            };
-
-            antic::tick(&mut *atari_system, &mut cpu, &mut *antic_data);
-
-            if debugger.paused {
-                break;
             }
+        }
 
-            cpu.cycle(&mut *atari_system);
-            hooks::hook(&mut cpu, &mut atari_system);
-
-            let finished_instr = cpu.get_remaining_cycles() == 0;
-            if finished_instr {
-                antic::post_instr_tick(&mut *atari_system, &antic_data.collisions_data);
+        if let Some(mut renderer) = software_renderer {
+            run_frame(
+                focused.is_some(),
+                &mut atari_system,
+                cpu,
+                &mut debugger,
+                &mut *renderer,
+                &mut keyboard,
+                &config,
+            );
+            if let Some(image) = images.get_mut(image_handle) {
+                image.data.copy_from_slice(renderer.frame());
             }
-            atari_system.inc_cycle();
-
-            if finished_instr {
-                let mut pause = false;
-                for breakpoint in &debugger.breakpoints {
-                    match breakpoint {
-                        BreakPoint::PC(pc) => {
-                            if cpu.get_program_counter() == *pc {
-                                pause = true;
-                                break;
-                            }
-                        }
-                        BreakPoint::NotPC(pc) => {
-                            if cpu.get_program_counter() != *pc {
-                                pause = true;
-                                break;
-                            }
-                        }
-                        BreakPoint::IndirectPC(addr) => {
-                            let pc = atari_system.read(*addr) as u16
-                                + atari_system.read(addr + 1) as u16 * 256;
-                            if pc != 0 && cpu.get_program_counter() == pc {
-                                pause = true;
-                                break;
-                            }
-                        }
-                        _ => (),
+        } else if let Some(antic_data) = antic_data {
+            match gpu_fallback.as_mut().filter(|fallback| fallback.frames > 0) {
+                Some(fallback) => {
+                    let mut display = GpuFeatures::new(&mut fallback.renderer);
+                    run_frame(
+                        focused.is_some(),
+                        &mut atari_system,
+                        cpu,
+                        &mut debugger,
+                        &mut display,
+                        &mut keyboard,
+                        &config,
+                    );
+                    let unsupported = display.unsupported;
+                    fallback.frames = if unsupported {
+                        GPU_FALLBACK_FRAMES
+                    } else {
+                        fallback.frames - 1
+                    };
+                    if let Some(image) = images.get_mut(&fallback.image) {
+                        image.data.copy_from_slice(fallback.renderer.frame());
                     }
                 }
-                if !pause {
-                    match debugger.step {
-                        Step::Into => {
-                            pause = true;
-                            debugger.step = Step::None
-                        }
-                        Step::Over { sp } => {
-                            if cpu.get_stack_pointer() == sp {
-                                pause = true;
-                                debugger.step = Step::None;
-                            }
-                        }
-                        Step::NextScanline { scanline } => {
-                            if atari_system.antic.scan_line != scanline {
-                                pause = true;
-                                debugger.step = Step::None;
-                            }
-                        }
-                        _ => (),
+                None => {
+                    if let Some(ref collisions_data) = antic_data.collisions_data {
+                        collisions_data.read_collisions(&*render_device);
                     }
-                }
-                if pause {
-                    debugger.pause();
-                }
-            }
-            if atari_system.antic.cycle == 0 {
-                // if let Some(BreakPoint::ScanLine(scan_line)) = &debugger.break_point {
-                //     if *scan_line == atari_system.antic.scan_line {
-                //         debugger.paused = true;
-                //         debugger.break_point = None;
-                //         display_config.debug = true;
-                //         break;
-                //     }
-                // }
-                if atari_system.antic.scan_line == 248 {
-                    atari_system.pokey.send_regs();
-                    if debugger.step == Step::NextFrame {
-                        debugger.step = Step::None;
-                        debugger.pause();
+                    let mut display = GpuFeatures::new(antic_data);
+                    run_frame(
+                        focused.is_some(),
+                        &mut atari_system,
+                        cpu,
+                        &mut debugger,
+                        &mut display,
+                        &mut keyboard,
+                        &config,
+                    );
+                    if let Some(fallback) = gpu_fallback.as_mut().filter(|_| display.unsupported) {
+                        fallback.frames = GPU_FALLBACK_FRAMES;
                     }
-                    break;
                 }
             }
-            if debugger.paused && !atari_system.antic.wsync() {
-                break;
-            }
         }
-    }
-}
-
-const XEX_LOADER: &[u8; 144] = include_bytes!("../xex_loader/xex_loader.atr");
-
-fn xex2atr(data: &[u8]) -> Vec<u8> {
-    let n_sectors = (data.len() + 127) / 128 + 1;
-    let size = n_sectors * 128 / 16; // size in paragraphs;
-    let size_h = (size / 256) as u8;
-    let size_l = (size % 256) as u8;
-
-    let mut atr_buf = vec![0; n_sectors * 128 + 16];
 
-    atr_buf[0..144].copy_from_slice(XEX_LOADER);
-    atr_buf[144..144 + data.len()].copy_from_slice(data);
-    atr_buf[2] = size_l;
-    atr_buf[3] = size_h;
-
-    // the last 6 bytes of sector have special meaning
-    // first 3 is lenght of xex file
-    // remaining ones is xex reading offset, zeroed on start
-    atr_buf[144 - 6] = (data.len() & 0xff) as u8;
-    atr_buf[144 - 5] = ((data.len() >> 8) & 0xff) as u8;
-    atr_buf[144 - 4] = ((data.len() >> 16) & 0xff) as u8;
-    atr_buf
+        if !rewinding && config.rewind_seconds > 0 && machine::is_frame_end(&atari_system) {
+            rewind.push(state::snapshot(&atari_system, cpu));
+        }
+    }
 }
 
-fn set_binary(
-    atari_system: &mut AtariSystem,
-    _cpu: &mut CPU,
-    key: &str,
-    path: &str,
-    data: Option<&[u8]>,
+/// Shows frames of the GPU renderer or its software fallback
+#[cfg_attr(not(feature = "egui"), allow(unused_variables))]
+fn update_screen_texture(
+    query: Query<(&AtariSlot, &Handle<Image>, &GpuFallback)>,
+    mut screens: Query<&mut Handle<Image>, (With<FullScreen>, Without<GpuFallback>)>,
+    #[cfg(feature = "egui")] mut egui_context: ResMut<EguiContext>,
 ) {
-    match key {
-        "basic" => {
-            atari_system.set_basic(data);
-        }
-        "osrom" => {
-            info!("loading osrom, len: {:?}", data.as_ref().map(|v| v.len()));
-            atari_system.set_osrom(data);
-        }
-        "disk_1" | "disk_2" | "disk_3" | "disk_4" => {
-            let n = (key.bytes().nth(5).unwrap() - 48 - 1) as usize;
-            atari_system.set_disk(n, data.map(|data| atr::ATR::new(path, &data)));
-        }
-        "xex" => {
-            let data = data.map(xex2atr);
-            set_binary(
-                atari_system,
-                _cpu,
-                "disk_1",
-                path,
-                data.as_ref().map(|v| v.as_slice()),
-            );
-        }
-        "car" => {
-            atari_system.set_cart(
-                data.map(|data| <dyn Cartridge>::from_bytes(&data).ok())
-                    .flatten(),
-            );
-        }
-        // "state" => {
-        //     if let Some(data) = data {
-        //         let data = gunzip(&data);
-        //         let a800_state = atari800_state::Atari800State::new(&data);
-        //         a800_state.reload(&mut *atari_system, &mut cpu.cpu);
-        //         // *frame = FrameState::default();
-        //         state.set(EmulatorState::Running).ok();
-        //         info!("LOADED! {:?}", *state);
-        //     }
-        // }
-        _ => {
-            warn!("unknown binary");
+    for (slot, gpu_image, fallback) in query.iter() {
+        let image = match fallback.frames {
+            0 => gpu_image,
+            _ => &fallback.image,
+        };
+        for mut texture in screens.iter_mut().filter(|texture| **texture != *image) {
+            *texture = image.clone();
+            #[cfg(feature = "egui")]
+            egui_context.set_egui_texture(slot.0 as u64, image.clone());
         }
     }
 }
 
+fn update_winit_config(config: Res<EmulatorConfig>, mut winit_config: ResMut<WinitConfig>) {
+    if config.is_changed() {
+        winit_config.force_fps = Some(config.video_standard.fps() as f64);
+    }
+}
+
 #[allow(dead_code)]
 fn fs_events(
     mut query: Query<(&AtariSlot, &mut AtariSystem, &mut CPU, &mut Debugger), With<Focused>>,
@@ -459,8 +562,10 @@ fn fs_events(
         for event in events.iter() {
             match event {
                 platform::FsEvent::AttachBinary { key, path, data } => {
-                    set_binary(&mut atari_system, &mut cpu, &key, path, Some(data));
-                    atari_system.reset(&mut cpu.cpu, true, true)
+                    set_binary(&mut atari_system, &mut cpu.cpu, &key, path, Some(data));
+                    if !matches!(key.as_str(), "state" | "movie" | "mapping") {
+                        atari_system.reset(&mut cpu.cpu, true, true)
+                    }
                 }
                 _ => continue,
             }
@@ -477,6 +582,14 @@ fn compute_atari_screen_scale(window_width: f32, window_height: f32) -> bevy::ma
 #[derive(Component)]
 pub struct FullScreen;
 
+/// text typed after start, from `--keystrokes TEXT` command line argument
+#[cfg(not(target_arch = "wasm32"))]
+fn keystrokes_arg() -> Option<String> {
+    let mut args = std::env::args().skip_while(|arg| arg != "--keystrokes");
+    args.next()?;
+    args.next()
+}
+
 fn setup(
     window_descriptor: Res<WindowDescriptor>,
     mut commands: Commands,
@@ -489,14 +602,26 @@ fn setup(
 ) {
     let slot = 0;
 
-    let main_image_handle = bevy_atari_antic::create_main_image(&mut *images);
-    let antic_data = AnticData::new(&render_device, main_image_handle.clone(), config.collisions);
-    let antic_data_handle = antic_data_assets.add(antic_data);
+    let software_renderer = (config.renderer == Renderer::Software)
+        .then(|| SoftwareRenderer::new(config.effective_palette()));
+
+    let (main_image_handle, antic_data_handle) = if software_renderer.is_some() {
+        (
+            images.add(software_render::create_image()),
+            Default::default(),
+        )
+    } else {
+        let main_image_handle = bevy_atari_antic::create_main_image(&mut *images);
+        let antic_data =
+            AnticData::new(&render_device, main_image_handle.clone(), config.collisions);
+        (main_image_handle, antic_data_assets.add(antic_data))
+    };
     #[cfg(feature = "egui")]
     egui_context.set_egui_texture(slot as u64, main_image_handle.clone());
     let mut atari_bundle = AtariBundle {
         slot: AtariSlot(slot),
         antic_data_handle,
+        texture: main_image_handle.clone(),
         debugger: Debugger {
             gdb_sender: (*gdb_channel).as_ref().map(|c| c.0.clone()),
             ..Default::default()
@@ -511,10 +636,23 @@ fn setup(
     atari_bundle
         .system
         .reset(&mut atari_bundle.cpu.cpu, true, true);
+    #[cfg(not(target_arch = "wasm32"))]
+    if let Some(text) = keystrokes_arg() {
+        atari_bundle.system.keystrokes(&text);
+    }
     // atari_bundle.debugger.breakpoints.push(BreakPoint::IndirectPC(0x2e0));
 
     let mut entity_commands = commands.spawn();
     entity_commands.insert_bundle(atari_bundle);
+    if let Some(software_renderer) = software_renderer {
+        entity_commands.insert(software_renderer);
+    } else {
+        entity_commands.insert(GpuFallback {
+            renderer: SoftwareRenderer::new(config.effective_palette()),
+            image: images.add(software_render::create_image()),
+            frames: 0,
+        });
+    }
     if !config.is_multi() {
         entity_commands.insert(Focused);
     }
@@ -605,7 +743,7 @@ fn main() {
 
     app.add_plugin(time_used_plugin::TimeUsedPlugin);
     app.insert_resource(WinitConfig {
-        force_fps: Some(50.0),
+        force_fps: Some(config.video_standard.fps() as f64),
         ..Default::default()
     });
 
@@ -633,6 +771,9 @@ fn main() {
     app.add_system(fs_events);
 
     let fs = platform::FileSystem::new(task_pool.clone());
+    if let Some(path) = mapping::mapping_path() {
+        fs.attach_binary("mapping", &path);
+    }
     // fs.attach_binary("osrom", "os.rom");
     // fs.attach_binary("car", "flob.1.0.3b.car");
 
@@ -645,10 +786,17 @@ fn main() {
         // .add_startup_system(debug::setup.system())
         .add_state(EmulatorState::Running)
         .add_system_to_stage(CoreStage::PreUpdate, gamepad::update.system())
+        .add_system_to_stage(CoreStage::PreUpdate, gamepad::update_light_pen.system())
+        .add_system_to_stage(
+            CoreStage::PreUpdate,
+            gamepad::update_symbolic_keyboard.system(),
+        )
         .add_system_set(
             SystemSet::on_update(EmulatorState::Running)
                 .with_system(atari_system.system().label("run_atari")),
         )
         .add_system(debug_keyboard.system())
+        .add_system(update_screen_texture.after("run_atari"))
+        .add_system(update_winit_config)
         .run();
 }
@@ -0,0 +1,268 @@
+use std::collections::HashMap;
+
+use bevy::prelude::{GamepadAxisType, GamepadButtonType, KeyCode};
+use serde::{Deserialize, Serialize};
+
+use crate::movie;
+
+/// mapping profile read at startup, may be replaced with `mapping` URL param on web
+#[cfg(target_arch = "wasm32")]
+pub fn mapping_path() -> Option<String> {
+    Some("/local/mapping.json".to_string())
+}
+
+/// mapping profile read at startup, from the user config directory
+#[cfg(not(target_arch = "wasm32"))]
+pub fn mapping_path() -> Option<String> {
+    let config_dir = std::env::var("XDG_CONFIG_HOME")
+        .or_else(|_| std::env::var("HOME").map(|home| format!("{}/.config", home)))
+        .ok()?;
+    Some(format!("{}/bevy-atari/mapping.json", config_dir))
+}
+
+const BUTTONS: &[GamepadButtonType] = &[
+    GamepadButtonType::South,
+    GamepadButtonType::East,
+    GamepadButtonType::North,
+    GamepadButtonType::West,
+    GamepadButtonType::C,
+    GamepadButtonType::Z,
+    GamepadButtonType::LeftTrigger,
+    GamepadButtonType::LeftTrigger2,
+    GamepadButtonType::RightTrigger,
+    GamepadButtonType::RightTrigger2,
+    GamepadButtonType::Select,
+    GamepadButtonType::Start,
+    GamepadButtonType::Mode,
+    GamepadButtonType::LeftThumb,
+    GamepadButtonType::RightThumb,
+    GamepadButtonType::DPadUp,
+    GamepadButtonType::DPadDown,
+    GamepadButtonType::DPadLeft,
+    GamepadButtonType::DPadRight,
+];
+
+const AXES: &[GamepadAxisType] = &[
+    GamepadAxisType::LeftStickX,
+    GamepadAxisType::LeftStickY,
+    GamepadAxisType::LeftZ,
+    GamepadAxisType::RightStickX,
+    GamepadAxisType::RightStickY,
+    GamepadAxisType::RightZ,
+    GamepadAxisType::DPadX,
+    GamepadAxisType::DPadY,
+];
+
+/// axis value above which mapped direction is active
+pub const AXIS_THRESHOLD: f32 = 0.5;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Direction {
+    Up,
+    Down,
+    Left,
+    Right,
+}
+
+impl Direction {
+    /// PORTA bit of the direction, set when active
+    pub fn bits(&self) -> u8 {
+        match self {
+            Direction::Up => 1,
+            Direction::Down => 2,
+            Direction::Left => 4,
+            Direction::Right => 8,
+        }
+    }
+}
+
+/// Atari input driven by host key, gamepad button or axis
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Target {
+    /// KBCODE value, bits 6 and 7 add shift and control, keyboard only
+    Key(u8),
+    /// joystick port and direction
+    Joystick(usize, Direction),
+    /// joystick port
+    Fire(usize),
+    Start,
+    Select,
+    Option,
+    /// keyboard only
+    Break,
+}
+
+/// State of joysticks and console keys driven by mapped inputs
+#[derive(Default, Debug, PartialEq)]
+pub struct Controls {
+    pub dirs: [u8; 2],
+    pub fire: [bool; 2],
+    pub consol: u8,
+    pub break_key: bool,
+}
+
+impl Controls {
+    pub fn apply(&mut self, target: Target) {
+        match target {
+            Target::Joystick(port, dir) => self.dirs[port & 1] |= dir.bits(),
+            Target::Fire(port) => self.fire[port & 1] = true,
+            Target::Start => self.consol |= 1,
+            Target::Select => self.consol |= 2,
+            Target::Option => self.consol |= 4,
+            Target::Break => self.break_key = true,
+            Target::Key(_) => (),
+        }
+    }
+
+    /// joystick input value of the port
+    pub fn joystick(&self, port: usize) -> u8 {
+        self.dirs[port] | (self.fire[port] as u8) << 4
+    }
+}
+
+/// Mapping profile as stored in JSON file, for example:
+///
+/// ```json
+/// {
+///   "keys": {"Up": {"joystick": [0, "up"]}, "RControl": {"fire": 0}, "F1": "start"},
+///   "buttons": {"0:East": {"fire": 0}, "1:DPadUp": {"joystick": [1, "up"]}},
+///   "axes": {"0:LeftStickX-": {"joystick": [0, "left"]}}
+/// }
+/// ```
+///
+/// Keys are named as in movies, buttons and axes are prefixed with gamepad index,
+/// axes are suffixed with direction.
+#[derive(Serialize, Deserialize)]
+struct MappingFile {
+    #[serde(default)]
+    keys: HashMap<String, Target>,
+    #[serde(default)]
+    buttons: Option<HashMap<String, Target>>,
+    #[serde(default)]
+    axes: Option<HashMap<String, Target>>,
+}
+
+/// Bindings of host inputs to Atari inputs.
+///
+/// Keys not listed in `keys` use the built-in US layout. Default gamepad
+/// bindings are replaced when the profile has `buttons` or `axes`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct InputMapping {
+    pub keys: HashMap<KeyCode, Target>,
+    /// gamepad index and button
+    pub buttons: HashMap<(usize, GamepadButtonType), Target>,
+    /// gamepad index, axis and true for positive direction
+    pub axes: HashMap<(usize, GamepadAxisType, bool), Target>,
+}
+
+impl Default for InputMapping {
+    /// first two gamepads are joysticks 1 and 2: d-pad and left stick,
+    /// East and triggers are fire, South / North / West are START / SELECT / OPTION
+    fn default() -> Self {
+        let mut buttons = HashMap::new();
+        let mut axes = HashMap::new();
+        for port in 0..2 {
+            let directions = [
+                (GamepadButtonType::DPadUp, Direction::Up),
+                (GamepadButtonType::DPadDown, Direction::Down),
+                (GamepadButtonType::DPadLeft, Direction::Left),
+                (GamepadButtonType::DPadRight, Direction::Right),
+            ];
+            for (button, dir) in directions {
+                buttons.insert((port, button), Target::Joystick(port, dir));
+            }
+            for button in [
+                GamepadButtonType::East,
+                GamepadButtonType::LeftTrigger,
+                GamepadButtonType::RightTrigger,
+            ] {
+                buttons.insert((port, button), Target::Fire(port));
+            }
+            buttons.insert((port, GamepadButtonType::South), Target::Start);
+            buttons.insert((port, GamepadButtonType::North), Target::Select);
+            buttons.insert((port, GamepadButtonType::West), Target::Option);
+            let stick = [
+                (GamepadAxisType::LeftStickX, false, Direction::Left),
+                (GamepadAxisType::LeftStickX, true, Direction::Right),
+                (GamepadAxisType::LeftStickY, false, Direction::Down),
+                (GamepadAxisType::LeftStickY, true, Direction::Up),
+            ];
+            for (axis, positive, dir) in stick {
+                axes.insert((port, axis, positive), Target::Joystick(port, dir));
+            }
+        }
+        Self {
+            keys: HashMap::new(),
+            buttons,
+            axes,
+        }
+    }
+}
+
+/// splits `index:name` into gamepad index and name
+fn gamepad_input(name: &str) -> Result<(usize, &str), String> {
+    let (index, name) = name
+        .split_once(':')
+        .ok_or_else(|| format!("missing gamepad index: {}", name))?;
+    let index = index
+        .parse()
+        .map_err(|_| format!("invalid gamepad index: {}", index))?;
+    Ok((index, name))
+}
+
+fn check_target(target: Target) -> Result<Target, String> {
+    match target {
+        Target::Joystick(port, _) | Target::Fire(port) if port > 1 => {
+            Err(format!("invalid joystick port: {}", port))
+        }
+        target => Ok(target),
+    }
+}
+
+impl InputMapping {
+    pub fn from_json(data: &[u8]) -> Result<Self, String> {
+        let file: MappingFile = serde_json::from_slice(data).map_err(|err| err.to_string())?;
+        let mut mapping = InputMapping::default();
+        for (name, target) in file.keys {
+            let key =
+                movie::key_from_name(&name).ok_or_else(|| format!("unknown key: {}", name))?;
+            mapping.keys.insert(key, check_target(target)?);
+        }
+        if let Some(buttons) = file.buttons {
+            mapping.buttons.clear();
+            for (name, target) in buttons {
+                let (index, button_name) = gamepad_input(&name)?;
+                let button = BUTTONS
+                    .iter()
+                    .find(|button| format!("{:?}", button) == button_name)
+                    .ok_or_else(|| format!("unknown button: {}", name))?;
+                mapping
+                    .buttons
+                    .insert((index, *button), check_target(target)?);
+            }
+        }
+        if let Some(axes) = file.axes {
+            mapping.axes.clear();
+            for (name, target) in axes {
+                let (index, axis_name) = gamepad_input(&name)?;
+                let (axis_name, positive) = if let Some(axis_name) = axis_name.strip_suffix('+') {
+                    (axis_name, true)
+                } else if let Some(axis_name) = axis_name.strip_suffix('-') {
+                    (axis_name, false)
+                } else {
+                    return Err(format!("missing axis direction: {}", name));
+                };
+                let axis = AXES
+                    .iter()
+                    .find(|axis| format!("{:?}", axis) == axis_name)
+                    .ok_or_else(|| format!("unknown axis: {}", name))?;
+                mapping
+                    .axes
+                    .insert((index, *axis, positive), check_target(target)?);
+            }
+        }
+        Ok(mapping)
+    }
+}
@@ -1,5 +1,6 @@
-use crate::{gdb::GdbMessage, AtariSlot, AtariSystem, BreakPoint, Debugger, EmulatorState, CPU};
+use crate::{gdb::GdbMessage, AtariSlot, BreakPoint, Debugger, EmulatorState, CPU};
 use bevy::prelude::*;
+use bevy_atari::{platform::FileSystem, system::AtariSystem};
 use emulator_6502::Interface6502;
 use gdbstub_mos_arch::MosRegs;
 use once_cell::sync::Lazy;
@@ -47,6 +48,19 @@ pub enum Message {
     KeyStrokes {
         text: String,
     },
+    SaveState {
+        path: String,
+    },
+    LoadState {
+        path: String,
+    },
+    StartRecording,
+    StopRecording {
+        path: String,
+    },
+    PlayMovie {
+        path: String,
+    },
 }
 
 pub fn send_message(msg: Message) {
@@ -58,7 +72,8 @@ pub fn events(
     mut query: Query<(&AtariSlot, &mut AtariSystem, &mut CPU, &mut Debugger)>,
     mut state: ResMut<State<EmulatorState>>,
     mut windows: ResMut<Windows>,
-    ui_config: ResMut<crate::resources::UIConfig>,
+    ui_config: ResMut<bevy_atari::resources::UIConfig>,
+    fs: Res<FileSystem>,
 ) {
     let mut _messages = MESSAGES.write();
     for (atari_slot, mut atari_system, mut cpu, mut debugger) in query.iter_mut() {
@@ -140,12 +155,37 @@ pub fn events(
                             Some(data) => Some(&data[..]),
                             None => None,
                         };
-                        crate::set_binary(&mut atari_system, &mut cpu, &key, &path, data);
+                        bevy_atari::machine::set_binary(
+                            &mut atari_system,
+                            &mut cpu.cpu,
+                            &key,
+                            &path,
+                            data,
+                        );
                     }
                 }
                 Message::KeyStrokes { text } => {
                     atari_system.keystrokes(&text);
                 }
+                Message::SaveState { path } => {
+                    info!("saving state to {}", path);
+                    fs.write(&path, &bevy_atari::state::save(&atari_system, &cpu.cpu));
+                }
+                Message::LoadState { path } => {
+                    fs.attach_binary("state", &path);
+                }
+                Message::StartRecording => {
+                    bevy_atari::movie::start_recording(&mut atari_system, &mut cpu.cpu);
+                }
+                Message::StopRecording { path } => {
+                    if let Some(movie) = bevy_atari::movie::stop_recording(&mut atari_system) {
+                        info!("saving movie to {}", path);
+                        fs.write(&path, &movie.to_bytes());
+                    }
+                }
+                Message::PlayMovie { path } => {
+                    fs.attach_binary("movie", &path);
+                }
                 Message::Command { cmd } => {
                     let parts = cmd.split(" ").collect::<Vec<_>>();
                     match parts[0] {
@@ -0,0 +1,599 @@
+use bevy::prelude::{info, warn, Input, KeyCode};
+use emulator_6502::MOS6502;
+
+use crate::state::{self, StateReader, StateWriter};
+use crate::system::AtariSystem;
+
+const MAGIC: &[u8; 4] = b"BA8M";
+const VERSION: u8 = 1;
+
+/// file used by movie hotkeys
+#[cfg(target_arch = "wasm32")]
+pub const QUICK_MOVIE_PATH: &str = "/local/quick.movie";
+#[cfg(not(target_arch = "wasm32"))]
+pub const QUICK_MOVIE_PATH: &str = "quick.movie";
+
+/// keys which may reach the emulated machine, stored in movies by name
+const KEYS: &[KeyCode] = &[
+    KeyCode::A,
+    KeyCode::B,
+    KeyCode::C,
+    KeyCode::D,
+    KeyCode::E,
+    KeyCode::F,
+    KeyCode::G,
+    KeyCode::H,
+    KeyCode::I,
+    KeyCode::J,
+    KeyCode::K,
+    KeyCode::L,
+    KeyCode::M,
+    KeyCode::N,
+    KeyCode::O,
+    KeyCode::P,
+    KeyCode::Q,
+    KeyCode::R,
+    KeyCode::S,
+    KeyCode::T,
+    KeyCode::U,
+    KeyCode::V,
+    KeyCode::W,
+    KeyCode::X,
+    KeyCode::Y,
+    KeyCode::Z,
+    KeyCode::Key0,
+    KeyCode::Key1,
+    KeyCode::Key2,
+    KeyCode::Key3,
+    KeyCode::Key4,
+    KeyCode::Key5,
+    KeyCode::Key6,
+    KeyCode::Key7,
+    KeyCode::Key8,
+    KeyCode::Key9,
+    KeyCode::F1,
+    KeyCode::F2,
+    KeyCode::F3,
+    KeyCode::F4,
+    KeyCode::F5,
+    KeyCode::F6,
+    KeyCode::F7,
+    KeyCode::F8,
+    KeyCode::F9,
+    KeyCode::F10,
+    KeyCode::F11,
+    KeyCode::F12,
+    KeyCode::Up,
+    KeyCode::Down,
+    KeyCode::Left,
+    KeyCode::Right,
+    KeyCode::Escape,
+    KeyCode::Back,
+    KeyCode::Return,
+    KeyCode::Space,
+    KeyCode::Tab,
+    KeyCode::Capital,
+    KeyCode::LShift,
+    KeyCode::RShift,
+    KeyCode::LControl,
+    KeyCode::RControl,
+    KeyCode::LAlt,
+    KeyCode::RAlt,
+    KeyCode::Apostrophe,
+    KeyCode::Asterisk,
+    KeyCode::Backslash,
+    KeyCode::Colon,
+    KeyCode::Comma,
+    KeyCode::Equals,
+    KeyCode::LBracket,
+    KeyCode::RBracket,
+    KeyCode::Minus,
+    KeyCode::Period,
+    KeyCode::Plus,
+    KeyCode::Semicolon,
+    KeyCode::Slash,
+    KeyCode::Underline,
+];
+
+pub fn key_name(key: KeyCode) -> String {
+    format!("{:?}", key)
+}
+
+pub fn key_from_name(name: &str) -> Option<KeyCode> {
+    KEYS.iter().copied().find(|key| key_name(*key) == name)
+}
+
+/// FNV-1a, used to identify media the movie was recorded with
+pub fn media_hash(data: &[u8]) -> u64 {
+    data.iter().fold(0xcbf29ce484222325, |hash, b| {
+        (hash ^ *b as u64).wrapping_mul(0x100000001b3)
+    })
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum KeyEvent {
+    Press(KeyCode),
+    Release(KeyCode),
+    /// key already pressed when recording started
+    Held(KeyCode),
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum InputEvent {
+    /// host keyboard state passed to `AtariSystem::handle_keyboard`
+    Keyboard(Vec<KeyEvent>),
+    Joystick {
+        input: usize,
+        port: usize,
+        value: u8,
+    },
+    Consol {
+        input: usize,
+        value: u8,
+    },
+    KeyStrokes(String),
+    Paddle {
+        index: usize,
+        value: u8,
+        fire: bool,
+    },
+    /// color clock and scanline
+    LightPen(Option<(u8, usize)>),
+}
+
+/// Recorded session: initial machine state and inputs timestamped with `antic.total_cycles`
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Movie {
+    /// cycle when recording started
+    pub start: u64,
+    pub end: u64,
+    pub media: Vec<(String, u64)>,
+    pub state: Vec<u8>,
+    pub events: Vec<(u64, InputEvent)>,
+}
+
+impl Movie {
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut writer = StateWriter::default();
+        writer.bytes(MAGIC);
+        writer.u8(VERSION);
+        writer.u64(self.start);
+        writer.u64(self.end);
+        writer.u32(self.media.len() as u32);
+        for (key, hash) in &self.media {
+            writer.str(key);
+            writer.u64(*hash);
+        }
+        writer.blob(&self.state);
+        writer.u32(self.events.len() as u32);
+        for (cycle, event) in &self.events {
+            writer.u64(*cycle);
+            match event {
+                InputEvent::Keyboard(keys) => {
+                    writer.u8(0);
+                    writer.u32(keys.len() as u32);
+                    for key in keys {
+                        let (kind, key) = match key {
+                            KeyEvent::Release(key) => (0, key),
+                            KeyEvent::Press(key) => (1, key),
+                            KeyEvent::Held(key) => (2, key),
+                        };
+                        writer.u8(kind);
+                        writer.str(&key_name(*key));
+                    }
+                }
+                InputEvent::Joystick { input, port, value } => {
+                    writer.u8(1);
+                    writer.u8(*input as u8);
+                    writer.u8(*port as u8);
+                    writer.u8(*value);
+                }
+                InputEvent::Consol { input, value } => {
+                    writer.u8(2);
+                    writer.u8(*input as u8);
+                    writer.u8(*value);
+                }
+                InputEvent::KeyStrokes(text) => {
+                    writer.u8(3);
+                    writer.str(text);
+                }
+                InputEvent::Paddle { index, value, fire } => {
+                    writer.u8(4);
+                    writer.u8(*index as u8);
+                    writer.u8(*value);
+                    writer.bool(*fire);
+                }
+                InputEvent::LightPen(position) => {
+                    writer.u8(5);
+                    writer.bool(position.is_some());
+                    let (hpos, scan_line) = position.unwrap_or_default();
+                    writer.u8(hpos);
+                    writer.u16(scan_line as u16);
+                }
+            }
+        }
+        state::gzip(&writer.into_inner())
+    }
+
+    pub fn from_bytes(data: &[u8]) -> Result<Movie, String> {
+        let data = state::gunzip(data)?;
+        let mut reader = StateReader::new(&data);
+        let mut magic = [0; 4];
+        reader.bytes(&mut magic)?;
+        if &magic != MAGIC {
+            return Err("not a bevy-atari movie".to_string());
+        }
+        let version = reader.u8()?;
+        if version != VERSION {
+            return Err(format!("unsupported movie version: {}", version));
+        }
+        let mut movie = Movie {
+            start: reader.u64()?,
+            end: reader.u64()?,
+            ..Default::default()
+        };
+        for _ in 0..reader.u32()? {
+            movie.media.push((reader.str()?, reader.u64()?));
+        }
+        movie.state = reader.blob()?;
+        for _ in 0..reader.u32()? {
+            let cycle = reader.u64()?;
+            let event = match reader.u8()? {
+                0 => {
+                    let mut keys = Vec::new();
+                    for _ in 0..reader.u32()? {
+                        let kind = reader.u8()?;
+                        let name = reader.str()?;
+                        let key =
+                            key_from_name(&name).ok_or_else(|| format!("unknown key: {}", name))?;
+                        keys.push(match kind {
+                            0 => KeyEvent::Release(key),
+                            1 => KeyEvent::Press(key),
+                            _ => KeyEvent::Held(key),
+                        });
+                    }
+                    InputEvent::Keyboard(keys)
+                }
+                1 => InputEvent::Joystick {
+                    input: reader.u8()? as usize,
+                    port: reader.u8()? as usize,
+                    value: reader.u8()?,
+                },
+                2 => InputEvent::Consol {
+                    input: reader.u8()? as usize,
+                    value: reader.u8()?,
+                },
+                3 => InputEvent::KeyStrokes(reader.str()?),
+                4 => InputEvent::Paddle {
+                    index: reader.u8()? as usize,
+                    value: reader.u8()?,
+                    fire: reader.bool()?,
+                },
+                5 => {
+                    let is_some = reader.bool()?;
+                    let position = (reader.u8()?, reader.u16()? as usize);
+                    InputEvent::LightPen(is_some.then(|| position))
+                }
+                tag => return Err(format!("invalid movie event: {}", tag)),
+            };
+            movie.events.push((cycle, event));
+        }
+        Ok(movie)
+    }
+}
+
+pub struct Recorder {
+    movie: Movie,
+    // keys pressed after the last `handle_keyboard`
+    keys: Vec<KeyCode>,
+}
+
+impl Recorder {
+    fn record_keyboard(&mut self, cycle: u64, keyboard: &Input<KeyCode>) {
+        let mut events = Vec::new();
+        for key in keyboard.get_pressed() {
+            if !self.keys.contains(key) && !keyboard.just_pressed(*key) {
+                events.push(KeyEvent::Held(*key));
+            }
+        }
+        for key in keyboard.get_just_pressed() {
+            events.push(KeyEvent::Press(*key));
+        }
+        for key in keyboard.get_just_released() {
+            events.push(KeyEvent::Release(*key));
+        }
+        for key in &self.keys {
+            if !keyboard.pressed(*key) && !keyboard.just_released(*key) {
+                events.push(KeyEvent::Release(*key));
+            }
+        }
+        events.retain(|event| match event {
+            KeyEvent::Press(key) | KeyEvent::Release(key) | KeyEvent::Held(key) => {
+                KEYS.contains(key)
+            }
+        });
+        // unchanged keyboard is replayed from the previous events
+        if !events.is_empty() {
+            self.movie
+                .events
+                .push((cycle, InputEvent::Keyboard(events)));
+        }
+    }
+}
+
+pub struct Player {
+    movie: Movie,
+    pos: usize,
+    keyboard: Input<KeyCode>,
+}
+
+impl Player {
+    fn next_event(&mut self, cycle: u64) -> Option<InputEvent> {
+        let (event_cycle, event) = self.movie.events.get(self.pos)?;
+        if *event_cycle > cycle {
+            return None;
+        }
+        if matches!(event, InputEvent::Keyboard(_)) && *event_cycle == cycle {
+            // consumed by `handle_keyboard`
+            return None;
+        }
+        self.pos += 1;
+        Some(event.clone())
+    }
+
+    fn is_finished(&self, cycle: u64) -> bool {
+        self.pos >= self.movie.events.len() && cycle >= self.movie.end
+    }
+}
+
+/// Input movie attached to `AtariSystem`
+pub enum MovieState {
+    Idle,
+    Recording(Recorder),
+    Playing(Player),
+}
+
+impl Default for MovieState {
+    fn default() -> Self {
+        MovieState::Idle
+    }
+}
+
+impl MovieState {
+    pub fn is_idle(&self) -> bool {
+        matches!(self, MovieState::Idle)
+    }
+
+    pub fn is_recording(&self) -> bool {
+        matches!(self, MovieState::Recording(_))
+    }
+
+    #[inline(always)]
+    pub fn is_playing(&self) -> bool {
+        matches!(self, MovieState::Playing(_))
+    }
+
+    pub fn record(&mut self, cycle: u64, event: InputEvent) {
+        if let MovieState::Recording(recorder) = self {
+            recorder.movie.events.push((cycle, event));
+        }
+    }
+}
+
+fn current_media(atari_system: &AtariSystem) -> Vec<(String, u64)> {
+    atari_system
+        .media
+        .iter()
+        .map(|(key, hash)| (key.clone(), *hash))
+        .collect()
+}
+
+/// Starts recording inputs from the current machine state.
+///
+/// The snapshot is restored immediately, so the recording starts exactly
+/// like the playback.
+/// Pending keystrokes are dropped, they are not a part of the snapshot.
+pub fn start_recording(atari_system: &mut AtariSystem, cpu: &mut MOS6502) {
+    let cycle = atari_system.antic.total_cycles;
+    let snapshot = state::snapshot(atari_system, cpu);
+    if let Err(err) = state::load(atari_system, cpu, &snapshot) {
+        warn!("cannot start recording: {}", err);
+        return;
+    }
+    atari_system.clear_keystrokes();
+    let mut movie = Movie {
+        start: cycle,
+        end: cycle,
+        media: current_media(atari_system),
+        state: snapshot,
+        events: Vec::new(),
+    };
+    // inputs are not a part of the snapshot
+    movie
+        .events
+        .extend(atari_system.input_events().map(|e| (cycle, e)));
+    atari_system.movie = MovieState::Recording(Recorder {
+        movie,
+        keys: Vec::new(),
+    });
+    info!("movie recording started");
+}
+
+/// Stops recording, returns None if nothing was recorded
+pub fn stop_recording(atari_system: &mut AtariSystem) -> Option<Movie> {
+    match std::mem::take(&mut atari_system.movie) {
+        MovieState::Recording(recorder) => {
+            let mut movie = recorder.movie;
+            movie.end = atari_system.antic.total_cycles;
+            info!("movie recording stopped, {} events", movie.events.len());
+            Some(movie)
+        }
+        movie => {
+            atari_system.movie = movie;
+            None
+        }
+    }
+}
+
+/// Restores the initial state of the movie and replays its inputs.
+///
+/// Loaded media have to be the same as during recording.
+pub fn start_playback(
+    atari_system: &mut AtariSystem,
+    cpu: &mut MOS6502,
+    movie: Movie,
+) -> Result<(), String> {
+    let media = current_media(atari_system);
+    if media != movie.media {
+        let keys = movie
+            .media
+            .iter()
+            .chain(media.iter())
+            .filter(|item| !media.contains(item) || !movie.media.contains(item))
+            .map(|(key, _)| key.as_str())
+            .collect::<Vec<_>>();
+        return Err(format!("movie media mismatch: {}", keys.join(", ")));
+    }
+    state::load(atari_system, cpu, &movie.state)?;
+    atari_system.clear_keystrokes();
+    atari_system.reset_inputs();
+    info!("movie playback started, {} events", movie.events.len());
+    atari_system.movie = MovieState::Playing(Player {
+        movie,
+        pos: 0,
+        keyboard: Input::default(),
+    });
+    play_events(atari_system);
+    Ok(())
+}
+
+pub fn stop(atari_system: &mut AtariSystem) {
+    if atari_system.movie.is_recording() {
+        warn!("movie recording discarded");
+    }
+    atari_system.movie = MovieState::Idle;
+}
+
+/// Applies all movie events due at the current cycle
+pub fn play_events(atari_system: &mut AtariSystem) {
+    let cycle = atari_system.antic.total_cycles;
+    loop {
+        let event = match &mut atari_system.movie {
+            MovieState::Playing(player) => match player.next_event(cycle) {
+                Some(event) => event,
+                None if player.is_finished(cycle) => {
+                    info!("movie playback finished");
+                    atari_system.movie = MovieState::Idle;
+                    return;
+                }
+                None => return,
+            },
+            _ => return,
+        };
+        match event {
+            // keyboard event missed by `handle_keyboard`
+            InputEvent::Keyboard(_) => warn!("movie desync at cycle {}", cycle),
+            InputEvent::Joystick { input, port, value } => {
+                atari_system.set_joystick_input(input, port, value)
+            }
+            InputEvent::Consol { input, value } => atari_system.set_consol_input(input, value),
+            InputEvent::KeyStrokes(text) => {
+                if let Err(err) = atari_system.push_keystrokes(&text) {
+                    warn!("invalid keystrokes: {}", err);
+                }
+            }
+            InputEvent::Paddle { index, value, fire } => {
+                atari_system.set_paddle_input(index, value, fire)
+            }
+            InputEvent::LightPen(position) => atari_system.set_light_pen_input(position),
+        }
+    }
+}
+
+/// Wraps `handle_keyboard`, records host keyboard or replaces it with the recorded one
+pub fn handle_keyboard(
+    atari_system: &mut AtariSystem,
+    keyboard: &mut Input<KeyCode>,
+    mut handler: impl FnMut(&mut AtariSystem, &mut Input<KeyCode>) -> bool,
+) -> bool {
+    let cycle = atari_system.antic.total_cycles;
+    match std::mem::take(&mut atari_system.movie) {
+        MovieState::Idle => handler(atari_system, keyboard),
+        MovieState::Recording(mut recorder) => {
+            recorder.record_keyboard(cycle, keyboard);
+            let irq = handler(atari_system, keyboard);
+            recorder.keys = keyboard.get_pressed().copied().collect();
+            atari_system.movie = MovieState::Recording(recorder);
+            irq
+        }
+        MovieState::Playing(mut player) => {
+            if let Some((event_cycle, InputEvent::Keyboard(keys))) =
+                player.movie.events.get(player.pos)
+            {
+                if *event_cycle == cycle {
+                    for key in keys {
+                        match key {
+                            KeyEvent::Press(key) => player.keyboard.press(*key),
+                            KeyEvent::Release(key) => player.keyboard.release(*key),
+                            KeyEvent::Held(key) => {
+                                // held keys come first, drop their just_pressed state
+                                player.keyboard.press(*key);
+                                player.keyboard.clear();
+                            }
+                        }
+                    }
+                    player.pos += 1;
+                }
+            }
+            let irq = handler(atari_system, &mut player.keyboard);
+            player.keyboard.clear();
+            atari_system.movie = MovieState::Playing(player);
+            play_events(atari_system);
+            irq
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_movie_serialization() {
+        let movie = Movie {
+            start: 42,
+            end: 1000,
+            media: vec![("osrom".to_string(), media_hash(&[1, 2, 3]))],
+            state: vec![1, 2, 3, 4],
+            events: vec![
+                (
+                    10,
+                    InputEvent::Keyboard(vec![
+                        KeyEvent::Held(KeyCode::LShift),
+                        KeyEvent::Press(KeyCode::A),
+                    ]),
+                ),
+                (
+                    20,
+                    InputEvent::Joystick {
+                        input: 1,
+                        port: 0,
+                        value: 0x11,
+                    },
+                ),
+                (30, InputEvent::Consol { input: 1, value: 1 }),
+                (40, InputEvent::KeyStrokes("RUN\n".to_string())),
+                (
+                    50,
+                    InputEvent::Paddle {
+                        index: 1,
+                        value: 100,
+                        fire: true,
+                    },
+                ),
+                (60, InputEvent::LightPen(Some((100, 50)))),
+            ],
+        };
+        assert_eq!(Movie::from_bytes(&movie.to_bytes()), Ok(movie));
+    }
+}
@@ -13,6 +13,14 @@ impl<T: Default + BitOrAssign + Copy> Multiplexer<T> {
         self.inputs[i] = v;
     }
 
+    pub fn get_input(&self, i: usize) -> T {
+        self.inputs[i]
+    }
+
+    pub fn size(&self) -> usize {
+        self.inputs.len()
+    }
+
     pub fn get_output(&self) -> T {
         let mut out: T = Default::default();
         for v in self.inputs.iter() {
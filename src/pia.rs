@@ -1,3 +1,4 @@
+use crate::state::{SaveState, StateReader, StateWriter};
 use crate::system::PORTB;
 pub struct PIA {
     porta_dir: u8, // 0 - input  1 - output
@@ -56,6 +57,11 @@ impl PIA {
         }
     }
 
+    /// SIO command line (CB2 output low)
+    pub fn sio_command(&self) -> bool {
+        self.portb_ctl & 0x38 == 0x30
+    }
+
     pub fn write(&mut self, addr: usize, value: u8) {
         match addr & 3 {
             PACTL_ADDR => self.porta_ctl = value,
@@ -91,6 +97,39 @@ impl PIA {
     }
 }
 
+impl SaveState for PIA {
+    fn save_state(&self, writer: &mut StateWriter) {
+        writer.bytes(&[
+            self.porta_dir,
+            self.portb_dir,
+            self.porta_ctl,
+            self.portb_ctl,
+            self.portb_out,
+            self.porta_out,
+            self.portb_in,
+            self.porta_in,
+        ]);
+    }
+
+    fn load_state(&mut self, reader: &mut StateReader) -> Result<(), String> {
+        let mut regs = [0; 8];
+        reader.bytes(&mut regs)?;
+        let [porta_dir, portb_dir, porta_ctl, portb_ctl, portb_out, porta_out, portb_in, porta_in] =
+            regs;
+        *self = Self {
+            porta_dir,
+            portb_dir,
+            porta_ctl,
+            portb_ctl,
+            portb_out,
+            porta_out,
+            portb_in,
+            porta_in,
+        };
+        Ok(())
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -59,11 +59,12 @@ impl FileSystemInternal {
 
     pub fn read(
         &self,
-        path: &'static str,
+        path: &str,
         create_response: impl FnOnce(Vec<u8>) -> FsEvent + Send + 'static,
     ) {
         let api = self.api.clone();
-        self.file_op(async move { api.read(path).await.map(create_response) });
+        let path = path.to_owned();
+        self.file_op(async move { api.read(&path).await.map(create_response) });
     }
 
     pub fn write(
@@ -121,11 +122,13 @@ impl FileSystem {
 }
 
 impl FileSystem {
-    pub fn attach_binary(&self, key: &'static str, path: &'static str) {
+    pub fn attach_binary(&self, key: &str, path: &str) {
         bevy::log::info!("attach {} binary: {}", key, path);
-        self.inner.read(path, |data| FsEvent::AttachBinary {
-            key: key.to_string(),
-            path: path.to_string(),
+        let key = key.to_string();
+        let path2 = path.to_string();
+        self.inner.read(path, move |data| FsEvent::AttachBinary {
+            key,
+            path: path2,
             data,
         });
     }
@@ -17,15 +17,10 @@ impl super::FileApi for FileApiImpl {
 
     fn write<'a>(
         &'a self,
-        _path: &'a str,
-        _contents: &'a [u8],
+        path: &'a str,
+        contents: &'a [u8],
     ) -> BoxedFuture<'a, Result<(), Self::FileError>> {
-        Box::pin(async move {
-            // js_api::writeFile(path, contents)
-            //     .await
-            //     .map_err(|e| JsFileError::Error(e))
-            Ok(())
-        })
+        Box::pin(async move { std::fs::write(path, contents) })
     }
 
     fn read_dir<'a>(
@@ -1,4 +1,4 @@
-use crate::js_api;
+use crate::js_imports;
 use bevy::utils::BoxedFuture;
 use wasm_bindgen::JsValue;
 
@@ -14,7 +14,7 @@ impl super::FileApi for FileApiImpl {
     type FileError = JsFileError;
     fn read<'a>(&'a self, path: &'a str) -> BoxedFuture<'a, Result<Vec<u8>, JsFileError>> {
         Box::pin(async move {
-            js_api::readFile(path)
+            js_imports::readFile(path)
                 .await
                 .map(|result| js_sys::Uint8Array::from(result).to_vec())
                 .map_err(|e| JsFileError::Error(e))
@@ -27,7 +27,7 @@ impl super::FileApi for FileApiImpl {
         contents: &'a [u8],
     ) -> BoxedFuture<'a, Result<(), Self::FileError>> {
         Box::pin(async move {
-            js_api::writeFile(path, contents)
+            js_imports::writeFile(path, contents)
                 .await
                 .map_err(|e| JsFileError::Error(e))
         })
@@ -38,7 +38,7 @@ impl super::FileApi for FileApiImpl {
         path: &'a str,
     ) -> BoxedFuture<'a, Result<Vec<String>, Self::FileError>> {
         Box::pin(async move {
-            js_api::ls(path)
+            js_imports::ls(path)
                 .await
                 .map(|result| {
                     let mut files = Vec::new();
@@ -0,0 +1,492 @@
+use bevy::prelude::KeyCode;
+
+use crate::config::MachineProfile;
+use crate::state::{SaveState, StateReader, StateWriter};
+use crate::EmulatorConfig;
+
+/// HELP key of XL / XE machines
+pub const HELP: u8 = 0x11;
+/// F1 - F4 keys of 1200XL
+pub const FUNCTION_KEYS: [u8; 4] = [0x03, 0x04, 0x13, 0x14];
+
+/// KBCODE bits of modifiers
+pub const SHIFT: u8 = 0x40;
+pub const CONTROL: u8 = 0x80;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ScanState {
+    Idle,
+    /// key found by the scan, confirmed when found again in the next pass
+    Detected,
+    Held,
+    /// key not found in the last pass, released when not found again
+    Released,
+}
+
+/// Keyboard matrix scanned by POKEY, one key every 114 cycles.
+///
+/// Held host keys are mapped to Atari keys, shift and control are separate
+/// lines sampled when a key is reported in KBCODE.
+pub struct Keyboard {
+    // host key, KBCODE value and if its mapping forces shift / control lines,
+    // in order of key presses
+    held: Vec<(KeyCode, u8, bool)>,
+    // key typed as text, with its shift and control lines
+    typed: Option<u8>,
+    shift: bool,
+    control: bool,
+    pub break_key: bool,
+    counter: u8,
+    latch: u8,
+    state: ScanState,
+}
+
+impl Default for Keyboard {
+    fn default() -> Self {
+        Self {
+            held: Vec::new(),
+            typed: None,
+            shift: false,
+            control: false,
+            break_key: false,
+            counter: 0,
+            latch: 0,
+            state: ScanState::Idle,
+        }
+    }
+}
+
+impl Keyboard {
+    /// sets shift and control lines of the host keyboard
+    pub fn set_modifiers(&mut self, shift: bool, control: bool) {
+        self.shift = shift;
+        self.control = control;
+    }
+
+    fn host_modifiers(&self) -> u8 {
+        (self.shift as u8) * SHIFT | (self.control as u8) * CONTROL
+    }
+
+    /// shift and control lines in KBCODE format, typed or last pressed key may force them
+    fn modifiers(&self) -> u8 {
+        match (self.typed, self.held.last()) {
+            (Some(code), _) | (None, Some((_, code, true))) => code & (SHIFT | CONTROL),
+            _ => self.host_modifiers(),
+        }
+    }
+
+    pub fn shift_line(&self) -> bool {
+        self.modifiers() & SHIFT > 0
+    }
+
+    pub fn is_held(&self, key: KeyCode) -> bool {
+        self.held.iter().any(|(k, _, _)| *k == key)
+    }
+
+    /// presses Atari key mapped to host key, with Alt F1 - F4 are 1200XL function keys
+    pub fn press(&mut self, key: KeyCode, is_alt: bool, config: &EmulatorConfig) {
+        if self.is_held(key) {
+            return;
+        }
+        if let Some(kbcode) = host_key(key, self.shift, self.control, is_alt, config) {
+            self.press_code(key, kbcode);
+        }
+    }
+
+    /// presses Atari key given as KBCODE value, its shift and control bits
+    /// are forced while it is the last pressed key
+    fn press_code(&mut self, key: KeyCode, kbcode: u8) {
+        if !self.is_held(key) {
+            let forced = kbcode & (SHIFT | CONTROL) != self.host_modifiers();
+            self.held.push((key, kbcode, forced));
+        }
+    }
+
+    /// presses Atari key bound to host key in the input mapping,
+    /// shift and control bits of `kbcode` are added to the host ones
+    pub fn press_mapped(&mut self, key: KeyCode, kbcode: u8) {
+        self.press_code(key, kbcode | self.host_modifiers());
+    }
+
+    pub fn release(&mut self, key: KeyCode) {
+        self.held.retain(|(k, _, _)| *k != key);
+    }
+
+    /// presses (or releases with `None`) Atari key typed as text
+    pub fn set_typed(&mut self, kbcode: Option<u8>) {
+        self.typed = kbcode;
+    }
+
+    pub fn release_all(&mut self) {
+        self.held.clear();
+        self.typed = None;
+        self.break_key = false;
+    }
+
+    /// true if the scan reports a held key, SKSTAT bit 2 is cleared then
+    pub fn key_down(&self) -> bool {
+        matches!(self.state, ScanState::Held | ScanState::Released)
+    }
+
+    /// resets scan counter and debounce logic, done while POKEY is in reset
+    pub fn reset_scan(&mut self) {
+        self.counter = 0;
+        self.state = ScanState::Idle;
+    }
+
+    /// Scans next key of the matrix, returns KBCODE value of a newly pressed key.
+    /// Without debounce every held key found by the scan is reported.
+    pub fn scan(&mut self, debounce: bool) -> Option<u8> {
+        let code = self.counter;
+        self.counter = (self.counter + 1) & 0x3f;
+        let pressed = self.held.iter().any(|(_, k, _)| k & 0x3f == code)
+            || self.typed.map_or(false, |k| k & 0x3f == code);
+        let kbcode = code | self.modifiers();
+        if !debounce {
+            if pressed {
+                self.latch = code;
+                self.state = ScanState::Held;
+                return Some(kbcode);
+            } else if code == self.latch {
+                self.state = ScanState::Idle;
+            }
+            return None;
+        }
+        if self.state != ScanState::Idle && code != self.latch {
+            return None;
+        }
+        let (state, reported) = match (self.state, pressed) {
+            (ScanState::Idle, true) => {
+                self.latch = code;
+                (ScanState::Detected, None)
+            }
+            (ScanState::Detected, true) => (ScanState::Held, Some(kbcode)),
+            (ScanState::Held, false) => (ScanState::Released, None),
+            (ScanState::Released, true) => (ScanState::Held, None),
+            (ScanState::Detected | ScanState::Released, false) => (ScanState::Idle, None),
+            (state, _) => (state, None),
+        };
+        self.state = state;
+        reported
+    }
+}
+
+impl SaveState for Keyboard {
+    fn save_state(&self, writer: &mut StateWriter) {
+        writer.u8(self.counter);
+        writer.u8(self.latch);
+        writer.u8(self.state as u8);
+    }
+
+    fn load_state(&mut self, reader: &mut StateReader) -> Result<(), String> {
+        self.counter = reader.u8()? & 0x3f;
+        self.latch = reader.u8()? & 0x3f;
+        self.state = match reader.u8()? {
+            0 => ScanState::Idle,
+            1 => ScanState::Detected,
+            2 => ScanState::Held,
+            3 => ScanState::Released,
+            v => return Err(format!("invalid keyboard scan state: {}", v)),
+        };
+        // held keys are pressed again by the host keyboard
+        self.release_all();
+        Ok(())
+    }
+}
+
+/// KBCODE value (with shift and control bits) of host key, assuming US layout
+fn host_key(
+    event: KeyCode,
+    is_shift: bool,
+    is_ctl: bool,
+    is_alt: bool,
+    config: &EmulatorConfig,
+) -> Option<u8> {
+    let mut is_ctl = is_ctl;
+    let mut is_shift = is_shift;
+    let has_help = config.machine != MachineProfile::Atari800;
+
+    let kbcode = match event {
+        KeyCode::Key1 => 0x1f,
+        KeyCode::Key2 => {
+            if is_shift {
+                0x35
+            } else {
+                0x1e
+            }
+        }
+        KeyCode::Key3 => 0x1a,
+        KeyCode::Key4 => 0x18,
+        KeyCode::Key5 => 0x1d,
+        KeyCode::Key6 => {
+            if is_shift {
+                0x07
+            } else {
+                0x1b
+            }
+        }
+        KeyCode::Key7 => {
+            if is_shift {
+                0x1b
+            } else {
+                0x33
+            }
+        }
+        KeyCode::Key8 => {
+            if is_shift {
+                is_shift = false;
+                0x7
+            } else {
+                0x35
+            }
+        }
+        KeyCode::Key9 => 0x30,
+        KeyCode::Key0 => 0x32,
+        KeyCode::A => 0x3f,
+        KeyCode::B => 0x15,
+        KeyCode::C => 0x12,
+        KeyCode::D => 0x3a,
+        KeyCode::E => 0x2a,
+        KeyCode::F => 0x38,
+        KeyCode::G => 0x3d,
+        KeyCode::H => 0x39,
+        KeyCode::I => 0x0d,
+        KeyCode::J => 0x01,
+        KeyCode::K => 0x05,
+        KeyCode::L => 0x00,
+        KeyCode::M => 0x25,
+        KeyCode::N => 0x23,
+        KeyCode::O => 0x08,
+        KeyCode::P => 0x0a,
+        KeyCode::Q => 0x2f,
+        KeyCode::R => 0x28,
+        KeyCode::S => 0x3e,
+        KeyCode::T => 0x2d,
+        KeyCode::U => 0x0b,
+        KeyCode::V => 0x10,
+        KeyCode::W => 0x2e,
+        KeyCode::X => 0x16,
+        KeyCode::Y => 0x2b,
+        KeyCode::Z => 0x17,
+        KeyCode::Escape => 0x1c,
+        KeyCode::Capital => 0x3c,
+        KeyCode::Back => 0x34,
+        KeyCode::Return => 0x0c,
+        KeyCode::Space => 0x21,
+        KeyCode::Asterisk => 0x07,
+        KeyCode::Plus => 0x06,
+        KeyCode::Colon => 0x02,
+        KeyCode::Comma => {
+            if is_shift {
+                is_shift = false;
+                0x36
+            } else {
+                0x20
+            }
+        }
+        KeyCode::Period => {
+            if is_shift {
+                is_shift = false;
+                0x37
+            } else {
+                0x22
+            }
+        }
+        KeyCode::Semicolon => 0x02,
+        KeyCode::Slash => 0x26,
+        KeyCode::Tab => 0x2c,
+        KeyCode::Minus => 0x0e,
+        KeyCode::LBracket => {
+            is_shift = true;
+            0x20
+        }
+        KeyCode::RBracket => {
+            is_shift = true;
+            0x22
+        }
+        KeyCode::Equals => {
+            if is_shift {
+                is_shift = false;
+                0x06
+            } else {
+                0x0f
+            }
+        }
+        KeyCode::Apostrophe => {
+            if is_shift {
+                0x1e
+            } else {
+                is_shift = true;
+                0x33
+            }
+        }
+        KeyCode::Backslash => {
+            if is_shift {
+                0x0f
+            } else {
+                is_shift = true;
+                0x06
+            }
+        }
+        KeyCode::F1 if is_alt && has_help => FUNCTION_KEYS[0],
+        KeyCode::F2 if is_alt && has_help => FUNCTION_KEYS[1],
+        KeyCode::F3 if is_alt && has_help => FUNCTION_KEYS[2],
+        KeyCode::F4 if is_alt && has_help => FUNCTION_KEYS[3],
+        KeyCode::F1 if has_help => HELP,
+        KeyCode::Up => {
+            is_ctl = (is_ctl || config.arrows_force_ctl) ^ config.arrows_neg_ctl;
+            0x0e
+        }
+        KeyCode::Down => {
+            is_ctl = (is_ctl || config.arrows_force_ctl) ^ config.arrows_neg_ctl;
+            0x0f
+        }
+        KeyCode::Left => {
+            is_ctl = (is_ctl || config.arrows_force_ctl) ^ config.arrows_neg_ctl;
+            0x06
+        }
+        KeyCode::Right => {
+            is_ctl = (is_ctl || config.arrows_force_ctl) ^ config.arrows_neg_ctl;
+            0x07
+        }
+        _ => return None,
+    };
+    Some(kbcode | (is_shift as u8) * SHIFT | (is_ctl as u8) * CONTROL)
+}
+
+/// Host keys typing characters, in symbolic keyboard mode
+/// characters are taken from text input instead
+pub fn is_symbol_key(key: KeyCode) -> bool {
+    matches!(
+        key,
+        KeyCode::A
+            | KeyCode::B
+            | KeyCode::C
+            | KeyCode::D
+            | KeyCode::E
+            | KeyCode::F
+            | KeyCode::G
+            | KeyCode::H
+            | KeyCode::I
+            | KeyCode::J
+            | KeyCode::K
+            | KeyCode::L
+            | KeyCode::M
+            | KeyCode::N
+            | KeyCode::O
+            | KeyCode::P
+            | KeyCode::Q
+            | KeyCode::R
+            | KeyCode::S
+            | KeyCode::T
+            | KeyCode::U
+            | KeyCode::V
+            | KeyCode::W
+            | KeyCode::X
+            | KeyCode::Y
+            | KeyCode::Z
+            | KeyCode::Key0
+            | KeyCode::Key1
+            | KeyCode::Key2
+            | KeyCode::Key3
+            | KeyCode::Key4
+            | KeyCode::Key5
+            | KeyCode::Key6
+            | KeyCode::Key7
+            | KeyCode::Key8
+            | KeyCode::Key9
+            | KeyCode::Space
+            | KeyCode::Apostrophe
+            | KeyCode::Asterisk
+            | KeyCode::Backslash
+            | KeyCode::Colon
+            | KeyCode::Comma
+            | KeyCode::Equals
+            | KeyCode::LBracket
+            | KeyCode::RBracket
+            | KeyCode::Minus
+            | KeyCode::Period
+            | KeyCode::Plus
+            | KeyCode::Semicolon
+            | KeyCode::Slash
+    )
+}
+
+/// KBCODE value (with shift and control bits) typing given character,
+/// letters are typed as uppercase with caps lock on
+pub fn char_to_kbcode(c: char) -> Option<u8> {
+    let shift = if c.is_ascii_uppercase() { SHIFT } else { 0 };
+    let kbcode = match c.to_ascii_lowercase() {
+        'a' => 0x3f,
+        'b' => 0x15,
+        'c' => 0x12,
+        'd' => 0x3a,
+        'e' => 0x2a,
+        'f' => 0x38,
+        'g' => 0x3d,
+        'h' => 0x39,
+        'i' => 0x0d,
+        'j' => 0x01,
+        'k' => 0x05,
+        'l' => 0x00,
+        'm' => 0x25,
+        'n' => 0x23,
+        'o' => 0x08,
+        'p' => 0x0a,
+        'q' => 0x2f,
+        'r' => 0x28,
+        's' => 0x3e,
+        't' => 0x2d,
+        'u' => 0x0b,
+        'v' => 0x10,
+        'w' => 0x2e,
+        'x' => 0x16,
+        'y' => 0x2b,
+        'z' => 0x17,
+        '1' => 0x1f,
+        '2' => 0x1e,
+        '3' => 0x1a,
+        '4' => 0x18,
+        '5' => 0x1d,
+        '6' => 0x1b,
+        '7' => 0x33,
+        '8' => 0x35,
+        '9' => 0x30,
+        '0' => 0x32,
+        '!' => 0x1f | SHIFT,
+        '"' => 0x1e | SHIFT,
+        '#' => 0x1a | SHIFT,
+        '$' => 0x18 | SHIFT,
+        '%' => 0x1d | SHIFT,
+        '&' => 0x1b | SHIFT,
+        '\'' => 0x33 | SHIFT,
+        '@' => 0x35 | SHIFT,
+        '(' => 0x30 | SHIFT,
+        ')' => 0x32 | SHIFT,
+        '<' => 0x36,
+        '>' => 0x37,
+        '-' => 0x0e,
+        '_' => 0x0e | SHIFT,
+        '=' => 0x0f,
+        '|' => 0x0f | SHIFT,
+        '+' => 0x06,
+        '\\' => 0x06 | SHIFT,
+        '*' => 0x07,
+        '^' => 0x07 | SHIFT,
+        ',' => 0x20,
+        '[' => 0x20 | SHIFT,
+        '.' => 0x22,
+        ']' => 0x22 | SHIFT,
+        ';' => 0x02,
+        ':' => 0x02 | SHIFT,
+        '/' => 0x26,
+        '?' => 0x26 | SHIFT,
+        ' ' => 0x21,
+        '\n' => 0x0c,
+        '\u{1b}' => 0x1c,
+        // caps lock
+        '\u{1f}' => 0x3c,
+        _ => return None,
+    };
+    Some(kbcode | shift)
+}
@@ -1,10 +1,17 @@
 use std::cell::RefCell;
 use std::sync::Arc;
 
+use crate::config::VideoStandard;
+use crate::state::{SaveState, StateReader, StateWriter};
 use crate::EmulatorConfig;
 pub use bevy::prelude::*;
-use rand::rngs::SmallRng;
-use rand::{Rng, SeedableRng};
+mod keyboard;
+pub use keyboard::{char_to_kbcode, is_symbol_key, Keyboard, CONTROL, FUNCTION_KEYS, HELP, SHIFT};
+// audio filters are used by native output only
+#[cfg_attr(target_arch = "wasm32", allow(dead_code))]
+mod utils;
+use utils::{Poly17, Poly9, PolyGenerator};
+
 #[cfg(target_arch = "wasm32")]
 #[path = "web.rs"]
 mod audio;
@@ -13,8 +20,14 @@ mod audio;
 #[path = "native.rs"]
 mod audio;
 
+const ALLPOT: usize = 0x08;
 const RANDOM: usize = 0x0A;
 const KBCODE: usize = 0x09;
+const STIMER: usize = 0x09;
+const SKRES: usize = 0x0a;
+const POTGO: usize = 0x0b;
+const SERIN: usize = 0x0d;
+const SEROUT: usize = 0x0d;
 const SKCTL: usize = 0x0f;
 const SKSTAT: usize = 0x0f;
 const IRQST: usize = 0x0e;
@@ -24,6 +37,20 @@ pub const CLOCK_177: f32 = 1778400.0;
 pub const DIVIDER_64K: u32 = 28;
 pub const DIVIDER_15K: u32 = 114;
 
+/// POT value of released paddle / centered 5200 joystick axis
+pub const POT_MAX: u8 = 228;
+pub const POT_5200_MIN: u8 = 6;
+pub const POT_5200_CENTER: u8 = 114;
+pub const POT_5200_MAX: u8 = 220;
+
+/// byte (start bit, 8 data bits, stop bit) at standard 19200 baud SIO rate
+pub const SIO_BYTE_CYCLES: u64 = 20 * (0x28 + 7);
+// delay of SEROUT IRQ after the byte is moved to the output shift register
+const SEROUT_IRQ_DELAY: u64 = 20;
+
+// interrupts of channels 1, 2 and 4
+const TIMER_IRQS: [IRQ; 3] = [IRQ::T1, IRQ::T2, IRQ::T4];
+
 bitflags! {
     #[derive(Default)]
     pub struct AUDCTL: u8 {
@@ -66,13 +93,25 @@ pub struct PokeyRegWrite {
     timestamp: u64,
 }
 
-#[derive(Default)]
 pub struct PokeyRegQueue {
     pub stereo: bool,
+    // used to convert register write timestamps to seconds
+    pub cycles_per_second: f64,
     queue: [Vec<PokeyRegWrite>; 2],
     pub total_cycles: u64,
 }
 
+impl Default for PokeyRegQueue {
+    fn default() -> Self {
+        Self {
+            stereo: false,
+            cycles_per_second: VideoStandard::Pal.cycles_per_second(),
+            queue: Default::default(),
+            total_cycles: 0,
+        }
+    }
+}
+
 impl PokeyRegQueue {
     pub fn write(&mut self, index: u8, value: u8) {
         let pokey_index = if !self.stereo {
@@ -86,59 +125,175 @@ impl PokeyRegQueue {
             timestamp: self.total_cycles,
         })
     }
+
+    pub fn clear(&mut self) {
+        self.queue[0].clear();
+        self.queue[1].clear();
+    }
 }
 pub struct Pokey {
-    audio_context: audio::Context,
+    audio_context: Option<audio::Context>,
     muted: bool,
     kbcode: u8,
     skstat: u8,
+    pub keyboard: Keyboard,
     irqst: u8,
     pub irqen: IRQ,
-    rng: SmallRng,
+    // pot positions, latched in POTx registers during pot scan
+    pots: [u8; 8],
+    // POTx values of pots with bit set in `pots_latched`
+    pot_values: [u8; 8],
+    pots_latched: u8,
+    pot_scan_start: u64,
+    skctl: u8,
+    serin: u8,
+    // SEROUT holding register and output shift register
+    serout: Option<u8>,
+    shift_out: Option<u8>,
+    shift_out_done_at: u64,
+    serout_irq_at: u64,
+    // next underflow of timers 1, 2 and 4, u64::MAX if not running
+    timer_at: [u64; 3],
+    // last values written to AUDFx / AUDCx / AUDCTL of both POKEYs
+    audio_regs: [u8; 0x20],
+    // cycle when poly counters were released from reset by SKCTL
+    poly_start: u64,
+    // output bits of 9 and 17 bit poly counters
+    poly9: Vec<bool>,
+    poly17: Vec<bool>,
     pub pokey_reg_queue: Arc<RefCell<PokeyRegQueue>>,
     pub delta_t: f64,
 }
 
 impl Default for Pokey {
     fn default() -> Self {
-        let rng = SmallRng::from_seed([0; if cfg!(target_arch = "wasm32") { 16 } else { 32 }]);
+        Pokey::new(Some(Default::default()))
+    }
+}
+unsafe impl Send for Pokey {}
+unsafe impl Sync for Pokey {}
 
+impl Pokey {
+    fn new(audio_context: Option<audio::Context>) -> Self {
         Self {
             muted: false,
-            rng,
+            poly_start: 0,
+            poly9: Poly9::as_vec(),
+            poly17: Poly17::as_vec(),
             kbcode: 0xff,
             skstat: 0xff,
+            keyboard: Keyboard::default(),
             irqst: 0xff,
             irqen: IRQ::from_bits_truncate(0xff),
+            pots: [POT_MAX; 8],
+            pot_values: [POT_MAX; 8],
+            pots_latched: 0,
+            pot_scan_start: 0,
+            skctl: 0,
+            serin: 0xff,
+            serout: None,
+            shift_out: None,
+            shift_out_done_at: u64::MAX,
+            serout_irq_at: u64::MAX,
+            timer_at: [u64::MAX; 3],
+            audio_regs: [0; 0x20],
             pokey_reg_queue: Default::default(),
             delta_t: 0.0,
-            audio_context: Default::default(),
+            audio_context,
         }
     }
-}
-unsafe impl Send for Pokey {}
-unsafe impl Sync for Pokey {}
 
-impl Pokey {
+    /// POKEY without audio output, register writes are discarded
+    pub fn headless() -> Self {
+        Pokey::new(None)
+    }
+
+    /// Upper 8 bits of the poly counter shift register, it is shifted every cycle.
+    /// Counters are held in reset while SKCTL bits 0-1 are cleared.
+    fn random(&self) -> u8 {
+        if self.skctl & 3 == 0 {
+            return 0xff;
+        }
+        let poly9 = AUDCTL::from_bits_truncate(self.audio_regs[8]).contains(AUDCTL::POLY_9BIT);
+        let (bits, n_bits) = if poly9 {
+            (&self.poly9, Poly9::n_bits() as usize)
+        } else {
+            (&self.poly17, Poly17::n_bits() as usize)
+        };
+        let step = self.now().wrapping_sub(self.poly_start) as usize;
+        (0..8).fold(0, |value, i| {
+            let bit = bits[(step + n_bits - 8 + i) % bits.len()];
+            value | ((bit as u8) << i)
+        })
+    }
+
     pub fn read(&mut self, addr: usize) -> u8 {
         let addr = addr & 0xf;
         let value = match addr {
-            RANDOM => self.rng.gen(),
+            0..=7 => {
+                self.latch_pots();
+                if self.pots_latched & (1 << addr) > 0 {
+                    self.pot_values[addr]
+                } else {
+                    self.pot_counter()
+                }
+            }
+            ALLPOT => {
+                self.latch_pots();
+                !self.pots_latched
+            }
+            RANDOM => self.random(),
             KBCODE => self.kbcode,
-            IRQST => self.irqst,
+            SERIN => self.serin,
+            IRQST => self.irqst & !((self.is_xmtdone() as u8) << 3),
             SKSTAT => self.skstat,
             _ => 0xff,
         };
         // warn!("POKEY read: {:02x}: {:02x}", addr, value);
         value
     }
+    pub fn set_pot(&mut self, index: usize, value: u8) {
+        // pots passed with the previous position keep their values
+        self.latch_pots();
+        self.pots[index] = value.min(POT_MAX);
+    }
+
+    /// latches POTx of pots passed by the scan counter, they keep values until POTGO
+    fn latch_pots(&mut self) {
+        let counter = self.pot_counter();
+        for (i, pot) in self.pots.iter().enumerate() {
+            if self.pots_latched & (1 << i) == 0 && *pot <= counter {
+                self.pot_values[i] = *pot;
+                self.pots_latched |= 1 << i;
+            }
+        }
+    }
+
+    /// pot scan counter, incremented every scanline or every cycle in fast pot mode
+    fn pot_counter(&self) -> u8 {
+        let elapsed = self.now().saturating_sub(self.pot_scan_start);
+        let counter = if self.skctl & 0x04 > 0 {
+            elapsed
+        } else {
+            elapsed / DIVIDER_15K as u64
+        };
+        counter.min(POT_MAX as u64) as u8
+    }
+
     pub fn mute(&mut self, muted: bool) {
         self.muted = muted;
     }
     // const IDLE_DELAY: usize = 2;
 
     pub fn send_regs(&mut self) {
-        if self.muted || !self.audio_context.is_running() {
+        let audio_context = match self.audio_context.as_mut() {
+            Some(audio_context) => audio_context,
+            None => {
+                self.pokey_reg_queue.borrow_mut().clear();
+                return;
+            }
+        };
+        if self.muted || !audio_context.is_running() {
             // skipping writes this way may lead to bad pokey state
             // for example some channels may still generate sound
             // or we may have wrong audctl value
@@ -151,9 +306,10 @@ impl Pokey {
         }
         let mut reg_queue = self.pokey_reg_queue.borrow_mut();
 
-        let audio_context_time = self.audio_context.current_time();
+        let audio_context_time = audio_context.current_time();
 
-        let atari_time = reg_queue.total_cycles as f64 / (312.0 * 114.0 * 50.0);
+        let cycles_per_second = reg_queue.cycles_per_second;
+        let atari_time = reg_queue.total_cycles as f64 / cycles_per_second;
 
         let time_diff = atari_time - self.delta_t - audio_context_time;
         if time_diff.abs() >= 0.05 {
@@ -167,206 +323,394 @@ impl Pokey {
             &reg_queue.queue[..1]
         };
 
-        self.audio_context.send_regs(reg_queues, self.delta_t);
-        reg_queue.queue[0].clear();
-        reg_queue.queue[1].clear();
+        audio_context.send_regs(reg_queues, self.delta_t, cycles_per_second);
+        reg_queue.clear();
     }
 
-    pub fn scanline_tick(&mut self, _scanline: usize) {}
+    /// keyboard scan, one key of the matrix every scanline
+    pub fn scanline_tick(&mut self, _scanline: usize) {
+        if self.skctl & 0x02 == 0 {
+            return;
+        }
+        let key_down = self.keyboard.key_down();
+        if let Some(kbcode) = self.keyboard.scan(self.skctl & 0x01 > 0) {
+            self.kbcode = kbcode;
+            self.raise_irq(IRQ::KEY);
+        }
+        // 5200 keypad sets SKSTAT directly, keep it unless the scan changes
+        if self.keyboard.key_down() != key_down {
+            self.skstat = self.skstat & !0x04 | ((!self.keyboard.key_down() as u8) << 2);
+        }
+    }
 
     pub fn write(&mut self, addr: usize, value: u8) {
         if addr & 0xf <= 8 {
-            self.pokey_reg_queue.borrow_mut().write(addr as u8, value)
+            self.pokey_reg_queue.borrow_mut().write(addr as u8, value);
+            self.audio_regs[addr & 0x1f] = value;
         }
 
         let addr = addr & 0xf;
         match addr {
             SKCTL => {
+                if self.skctl & 3 == 0 && value & 3 != 0 {
+                    self.poly_start = self.now();
+                }
+                self.skctl = value;
                 if value & 3 == 0 {
                     // info!("POKEY reset!");
+                    self.reset_serial();
+                    self.keyboard.reset_scan();
                 }
             }
+            SKRES => self.skstat |= 0xe0,
+            POTGO => {
+                self.pots_latched = 0;
+                self.pot_scan_start = self.now();
+            }
+            SEROUT => self.write_serout(value),
             IRQEN => {
                 self.irqen = IRQ::from_bits_truncate(value);
                 self.irqst |= !self.irqen.bits;
+                // disabled timers keep running until the next underflow,
+                // so acknowledging IRQ doesn't change timer phase
+                let now = self.now();
+                for (i, irq) in TIMER_IRQS.iter().enumerate() {
+                    if self.irqen.contains(*irq) && self.timer_at[i] == u64::MAX {
+                        self.timer_at[i] = now + self.timer_period(i);
+                    }
+                }
+            }
+            STIMER => {
+                let now = self.now();
+                for (i, irq) in TIMER_IRQS.iter().enumerate() {
+                    self.timer_at[i] = if self.irqen.contains(*irq) {
+                        now + self.timer_period(i)
+                    } else {
+                        u64::MAX
+                    };
+                }
             }
             _ => (),
         }
     }
 
+    fn now(&self) -> u64 {
+        self.pokey_reg_queue.borrow().total_cycles
+    }
+
+    fn raise_irq(&mut self, irq: IRQ) {
+        self.irqst &= !(irq & self.irqen).bits;
+    }
+
+    fn is_xmtdone(&self) -> bool {
+        self.shift_out.is_none() && self.irqen.contains(IRQ::SCMP)
+    }
+
+    /// true if any enabled interrupt is pending
+    pub fn irq_line(&self) -> bool {
+        !self.irqst & self.irqen.bits != 0 || self.is_xmtdone()
+    }
+
+    /// period of timer 1, 2 or 4 (index 0..3) in CPU cycles
+    fn timer_period(&self, timer: usize) -> u64 {
+        let audctl = AUDCTL::from_bits_truncate(self.audio_regs[8]);
+        let base = if audctl.contains(AUDCTL::CLOCK_15) {
+            DIVIDER_15K
+        } else {
+            DIVIDER_64K
+        } as u64;
+        let audf = |channel: usize| self.audio_regs[channel * 2] as u64;
+        let period = |n: u64, fast: bool, linked: bool| match (fast, linked) {
+            (true, false) => n + 4,
+            (true, true) => n + 7,
+            (false, _) => (n + 1) * base,
+        };
+        match timer {
+            0 => period(audf(0), audctl.contains(AUDCTL::CH1_FAST_CLOCK), false),
+            1 if audctl.contains(AUDCTL::CH12_LINKED_CNT) => period(
+                audf(0) + 256 * audf(1),
+                audctl.contains(AUDCTL::CH1_FAST_CLOCK),
+                true,
+            ),
+            1 => period(audf(1), false, false),
+            _ if audctl.contains(AUDCTL::CH34_LINKED_CNT) => period(
+                audf(2) + 256 * audf(3),
+                audctl.contains(AUDCTL::CH3_FAST_CLOCK),
+                true,
+            ),
+            _ => period(audf(3), false, false),
+        }
+    }
+
+    /// duration of serial byte transfer, clocked by channel 4 or external (SIO) clock
+    pub fn serial_byte_cycles(&self) -> u64 {
+        if self.skctl & 0x70 == 0 {
+            return SIO_BYTE_CYCLES;
+        }
+        let audctl = AUDCTL::from_bits_truncate(self.audio_regs[8]);
+        let base = if audctl.contains(AUDCTL::CLOCK_15) {
+            DIVIDER_15K
+        } else {
+            DIVIDER_64K
+        } as u64;
+        let (audf3, audf4) = (self.audio_regs[4] as u64, self.audio_regs[6] as u64);
+        let period = if audctl.contains(AUDCTL::CH34_LINKED_CNT) {
+            let n = audf3 + 256 * audf4;
+            if audctl.contains(AUDCTL::CH3_FAST_CLOCK) {
+                n + 7
+            } else {
+                (n + 1) * base
+            }
+        } else {
+            (audf4 + 1) * base
+        };
+        // two periods of channel 4 per bit, 10 bits
+        20 * period
+    }
+
+    fn reset_serial(&mut self) {
+        self.serout = None;
+        self.shift_out = None;
+        self.shift_out_done_at = u64::MAX;
+        self.serout_irq_at = u64::MAX;
+    }
+
+    /// stops timers, serial transfers and pot scan, they are scheduled by total cycles
+    pub fn reset_timers(&mut self) {
+        self.reset_serial();
+        self.timer_at = [u64::MAX; 3];
+        self.pot_scan_start = 0;
+    }
+
+    fn write_serout(&mut self, value: u8) {
+        if self.shift_out.is_none() {
+            let now = self.now();
+            self.shift_out = Some(value);
+            self.shift_out_done_at = now + self.serial_byte_cycles();
+            self.serout_irq_at = now + SEROUT_IRQ_DELAY;
+        } else {
+            self.serout = Some(value);
+        }
+    }
+
+    /// cycle of the next timer or serial output event
+    #[inline(always)]
+    pub fn next_event(&self) -> u64 {
+        let timer_at = self.timer_at[0].min(self.timer_at[1]).min(self.timer_at[2]);
+        self.shift_out_done_at.min(self.serout_irq_at).min(timer_at)
+    }
+
+    /// handles timer underflows and serial output,
+    /// returns byte sent to the serial bus, if transmission of one is finished
+    pub fn tick(&mut self, now: u64) -> Option<u8> {
+        for (i, irq) in TIMER_IRQS.iter().enumerate() {
+            if now >= self.timer_at[i] {
+                if self.irqen.contains(*irq) {
+                    self.raise_irq(*irq);
+                    self.timer_at[i] = (self.timer_at[i] + self.timer_period(i)).max(now + 1);
+                } else {
+                    self.timer_at[i] = u64::MAX;
+                }
+            }
+        }
+        if now >= self.serout_irq_at {
+            self.serout_irq_at = u64::MAX;
+            self.raise_irq(IRQ::SOUT);
+        }
+        if now < self.shift_out_done_at {
+            return None;
+        }
+        let byte = self.shift_out.take();
+        self.shift_out_done_at = u64::MAX;
+        if let Some(value) = self.serout.take() {
+            self.shift_out = Some(value);
+            self.shift_out_done_at = now + self.serial_byte_cycles();
+            self.raise_irq(IRQ::SOUT);
+        }
+        byte
+    }
+
+    /// byte received from the serial bus
+    pub fn serial_in(&mut self, value: u8) {
+        if self.irqst & IRQ::SIN.bits == 0 {
+            // previous byte not handled yet, serial input overrun
+            self.skstat &= !0x20;
+        }
+        self.serin = value;
+        self.raise_irq(IRQ::SIN);
+    }
+
+    /// Updates keyboard matrix with host key mapped with the built-in US layout.
+    /// Key IRQs are raised by the keyboard scan.
     pub fn key_press(
         &mut self,
         event: &KeyCode,
         is_pressed: bool,
-        is_shift: bool,
-        is_ctl: bool,
+        is_alt: bool,
         config: &EmulatorConfig,
-    ) -> bool {
-        let mut is_ctl = is_ctl;
-        let mut is_shift = is_shift;
+    ) {
+        if is_pressed {
+            self.keyboard.press(*event, is_alt, config);
+        } else {
+            self.keyboard.release(*event);
+        }
+        self.update_shift_line();
+    }
+
+    /// presses Atari key bound to host key in the input mapping
+    pub fn mapped_key_press(&mut self, event: &KeyCode, kbcode: u8) {
+        self.keyboard.press_mapped(*event, kbcode);
+        self.update_shift_line();
+    }
+
+    /// presses (or releases with `None`) Atari key typed as text
+    pub fn type_key(&mut self, kbcode: Option<u8>) {
+        self.keyboard.set_typed(kbcode);
+        self.update_shift_line();
+    }
 
+    /// BREAK key is not in the keyboard matrix, it raises IRQ when pressed
+    pub fn set_break_key(&mut self, is_pressed: bool) {
+        if is_pressed && !self.keyboard.break_key {
+            self.raise_irq(IRQ::BRK);
+        }
+        self.keyboard.break_key = is_pressed;
+    }
+
+    /// sets shift and control keys state of the host keyboard
+    pub fn set_modifiers(&mut self, is_shift: bool, is_ctl: bool) {
+        self.keyboard.set_modifiers(is_shift, is_ctl);
+        self.update_shift_line();
+    }
+
+    fn update_shift_line(&mut self) {
+        self.skstat = self.skstat & !0x08 | ((!self.keyboard.shift_line() as u8) << 3);
+    }
+
+    /// 5200 controller keypad, F2-F4 are START / PAUSE / RESET
+    pub fn keypad_press(&mut self, event: &KeyCode, is_pressed: bool) -> bool {
         let kbcode = match *event {
-            KeyCode::Key1 => 0x1f,
-            KeyCode::Key2 => {
-                if is_shift {
-                    0x35
-                } else {
-                    0x1e
-                }
-            }
-            KeyCode::Key3 => 0x1a,
-            KeyCode::Key4 => 0x18,
-            KeyCode::Key5 => 0x1d,
-            KeyCode::Key6 => {
-                if is_shift {
-                    0x07
-                } else {
-                    0x1b
-                }
-            }
-            KeyCode::Key7 => {
-                if is_shift {
-                    0x1b
-                } else {
-                    0x33
-                }
-            }
-            KeyCode::Key8 => {
-                if is_shift {
-                    is_shift = false;
-                    0x7
-                } else {
-                    0x35
-                }
-            }
-            KeyCode::Key9 => 0x30,
-            KeyCode::Key0 => 0x32,
-            KeyCode::A => 0x3f,
-            KeyCode::B => 0x15,
-            KeyCode::C => 0x12,
-            KeyCode::D => 0x3a,
-            KeyCode::E => 0x2a,
-            KeyCode::F => 0x38,
-            KeyCode::G => 0x3d,
-            KeyCode::H => 0x39,
-            KeyCode::I => 0x0d,
-            KeyCode::J => 0x01,
-            KeyCode::K => 0x05,
-            KeyCode::L => 0x00,
-            KeyCode::M => 0x25,
-            KeyCode::N => 0x23,
-            KeyCode::O => 0x08,
-            KeyCode::P => 0x0a,
-            KeyCode::Q => 0x2f,
-            KeyCode::R => 0x28,
-            KeyCode::S => 0x3e,
-            KeyCode::T => 0x2d,
-            KeyCode::U => 0x0b,
-            KeyCode::V => 0x10,
-            KeyCode::W => 0x2e,
-            KeyCode::X => 0x16,
-            KeyCode::Y => 0x2b,
-            KeyCode::Z => 0x17,
-            KeyCode::Escape => 0x1c,
-            KeyCode::Capital => 0x3c,
-            // KeyCode::LControl => {}
-            // KeyCode::LShift => {}
-            // KeyCode::RControl => {}
-            // KeyCode::RShift => {}
-            KeyCode::Back => 0x34,
-            KeyCode::Return => 0x0c,
-            KeyCode::Space => 0x21,
-            KeyCode::Asterisk => 0x07,
-            KeyCode::Plus => 0x06,
-            KeyCode::Colon => 0x02,
-            KeyCode::Comma => {
-                if is_shift {
-                    is_shift = false;
-                    0x36
-                } else {
-                    0x20
-                }
-            }
-            KeyCode::Period => {
-                if is_shift {
-                    is_shift = false;
-                    0x37
-                } else {
-                    0x22
-                }
-            }
-            KeyCode::Semicolon => 0x02,
-            KeyCode::Slash => 0x26,
-            KeyCode::Tab => 0x2c,
-            KeyCode::Minus => 0x0e,
-            KeyCode::LBracket => {
-                is_shift = true;
-                0x20
-            }
-            KeyCode::RBracket => {
-                is_shift = true;
-                0x22
-            }
-            KeyCode::Equals => {
-                if is_shift {
-                    is_shift = false;
-                    0x06
-                } else {
-                    0x0f
-                }
-            }
-            KeyCode::Apostrophe => {
-                if is_shift {
-                    0x1e
-                } else {
-                    is_shift = true;
-                    0x33
-                }
-            }
-            KeyCode::Backslash => {
-                if is_shift {
-                    0x0f
-                } else {
-                    is_shift = true;
-                    0x06
-                }
-            }
-            KeyCode::F1 => 0x11,
-            KeyCode::F7 => {
-                // break
-                if is_pressed {
-                    self.irqst &= !0x80;
-                    self.skstat = 0xff;
-                    self.kbcode = 0xff;
-                }
-                return is_pressed;
-            }
-            // KeyCode::Capital => 0x3c,
-            KeyCode::Up => {
-                is_ctl = (is_ctl || config.arrows_force_ctl) ^ config.arrows_neg_ctl;
-                0x0e
-            }
-            KeyCode::Down => {
-                is_ctl = (is_ctl || config.arrows_force_ctl) ^ config.arrows_neg_ctl;
-                0x0f
-            }
-            KeyCode::Left => {
-                is_ctl = (is_ctl || config.arrows_force_ctl) ^ config.arrows_neg_ctl;
-                0x06
-            }
-            KeyCode::Right => {
-                is_ctl = (is_ctl || config.arrows_force_ctl) ^ config.arrows_neg_ctl;
-                0x07
-            }
+            KeyCode::Key0 => 0x25,
+            KeyCode::Key1 => 0x3f,
+            KeyCode::Key2 => 0x3d,
+            KeyCode::Key3 => 0x3b,
+            KeyCode::Key4 => 0x37,
+            KeyCode::Key5 => 0x35,
+            KeyCode::Key6 => 0x33,
+            KeyCode::Key7 => 0x2f,
+            KeyCode::Key8 => 0x2d,
+            KeyCode::Key9 => 0x2b,
+            KeyCode::Minus => 0x27,  // *
+            KeyCode::Equals => 0x23, // #
+            KeyCode::F2 => 0x39,
+            KeyCode::F3 => 0x31,
+            KeyCode::F4 => 0x29,
             _ => return false,
         };
-        self.kbcode = self.kbcode & 0x3f | ((is_shift as u8) << 6) | ((is_ctl as u8) << 7);
-        self.skstat = self.skstat & !(0xc) | ((!is_shift as u8) << 3) | ((!is_pressed as u8) << 2);
+        self.skstat = self.skstat & !0x04 | ((!is_pressed as u8) << 2);
         if is_pressed {
-            self.kbcode = self.kbcode & !0x3f | kbcode & 0x3f;
-            self.irqst &= !0x40;
-        };
-        // info!("kbcode: {:?}, is_pressed: {:?}", self.kbcode, is_pressed);
+            self.kbcode = kbcode;
+            self.raise_irq(IRQ::KEY);
+        }
         is_pressed
     }
 }
+
+impl SaveState for Pokey {
+    fn save_state(&self, writer: &mut StateWriter) {
+        writer.u8(self.kbcode);
+        writer.u8(self.skstat);
+        writer.u8(self.irqst);
+        writer.u8(self.irqen.bits());
+        writer.bytes(&self.audio_regs);
+        writer.u8(self.skctl);
+        writer.u8(self.serin);
+        for reg in [self.serout, self.shift_out] {
+            writer.bool(reg.is_some());
+            writer.u8(reg.unwrap_or(0));
+        }
+        writer.u64(self.shift_out_done_at);
+        writer.u64(self.serout_irq_at);
+        for timer_at in self.timer_at {
+            writer.u64(timer_at);
+        }
+        writer.bytes(&self.pot_values);
+        writer.u8(self.pots_latched);
+        writer.u64(self.pot_scan_start);
+        writer.u64(self.poly_start);
+        self.keyboard.save_state(writer);
+    }
+
+    fn load_state(&mut self, reader: &mut StateReader) -> Result<(), String> {
+        self.kbcode = reader.u8()?;
+        self.skstat = reader.u8()?;
+        self.irqst = reader.u8()?;
+        self.irqen = IRQ::from_bits_truncate(reader.u8()?);
+        reader.bytes(&mut self.audio_regs)?;
+        self.skctl = reader.u8()?;
+        self.serin = reader.u8()?;
+        for reg in [&mut self.serout, &mut self.shift_out] {
+            let is_some = reader.bool()?;
+            let value = reader.u8()?;
+            *reg = is_some.then(|| value);
+        }
+        self.shift_out_done_at = reader.u64()?;
+        self.serout_irq_at = reader.u64()?;
+        for timer_at in self.timer_at.iter_mut() {
+            *timer_at = reader.u64()?;
+        }
+        reader.bytes(&mut self.pot_values)?;
+        self.pots_latched = reader.u8()?;
+        self.pot_scan_start = reader.u64()?;
+        self.poly_start = reader.u64()?;
+        self.keyboard.load_state(reader)?;
+        let mut reg_queue = self.pokey_reg_queue.borrow_mut();
+        for (index, value) in self.audio_regs.iter().enumerate() {
+            if index & 0xf <= 8 {
+                reg_queue.write(index as u8, *value);
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_keypad_irq() {
+        let mut pokey = Pokey::headless();
+        // keypad IRQ is masked by IRQEN
+        pokey.write(IRQEN, 0x00);
+        assert!(pokey.keypad_press(&KeyCode::Key1, true));
+        assert_eq!(pokey.read(IRQST) & 0x40, 0x40);
+        assert_eq!(pokey.read(KBCODE), 0x3f);
+        pokey.write(IRQEN, 0x40);
+        pokey.keypad_press(&KeyCode::Key2, true);
+        assert_eq!(pokey.read(IRQST) & 0x40, 0x00);
+        assert_eq!(pokey.read(KBCODE), 0x3d);
+    }
+
+    #[test]
+    fn test_random() {
+        let mut pokey = Pokey::headless();
+        // poly counters are held in reset
+        pokey.write(SKCTL, 0x00);
+        assert_eq!(pokey.read(RANDOM), 0xff);
+        pokey.write(SKCTL, 0x03);
+        let random_at = |pokey: &mut Pokey, cycle: u64| {
+            pokey.pokey_reg_queue.borrow_mut().total_cycles = cycle;
+            pokey.read(RANDOM)
+        };
+        // shift register is shifted right every cycle
+        let a = random_at(&mut pokey, 100);
+        assert_eq!(random_at(&mut pokey, 101) & 0x7f, a >> 1);
+        assert_eq!(random_at(&mut pokey, 100), a);
+        // AUDCTL: 9 bit poly counter
+        pokey.write(0x08, 0x80);
+        let a = random_at(&mut pokey, 100);
+        assert_eq!(random_at(&mut pokey, 101) & 0x7f, a >> 1);
+        assert_eq!(random_at(&mut pokey, 100 + 511), a);
+    }
+}
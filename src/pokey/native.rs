@@ -1,8 +1,11 @@
-use bevy::prelude::info;
-mod utils;
-use utils::{
+use super::utils::{
     FIRFilter, Filter, FilterCascade40_1, Poly17, Poly4, Poly5, Poly9, PolyGenerator, FIR_37_TO_1,
 };
+use bevy::prelude::info;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+
+use crate::config::VideoStandard;
 
 use web_audio_api::context::{
     AudioContext, AudioContextOptions, AudioContextRegistration, AudioParamId, BaseAudioContext,
@@ -31,15 +34,24 @@ impl Context {
         }
     }
 
-    pub fn send_regs(&mut self, regs: &[Vec<super::PokeyRegWrite>], delta_t: f64) {
+    pub fn send_regs(
+        &mut self,
+        regs: &[Vec<super::PokeyRegWrite>],
+        delta_t: f64,
+        cycles_per_second: f64,
+    ) {
         for r in &regs[0] {
             let index = r.index & 0xf;
             if index > 9 {
                 continue;
             }
-            let t = r.timestamp as f64 / (312.0 * 114.0 * 50.0) - delta_t + Self::LATENCY;
+            let t = r.timestamp as f64 / cycles_per_second - delta_t + Self::LATENCY;
             self.pokey_node.regs[index as usize].set_value_at_time(r.value as f32, t);
         }
+        // POKEY is clocked by the CPU clock of the video standard
+        self.pokey_node
+            .clock
+            .store(cycles_per_second.to_bits(), Ordering::Relaxed);
     }
 }
 
@@ -67,6 +79,8 @@ struct PokeyNode {
     /// channel configuration (for up/down-mixing of inputs), required for all audio nodes
     channel_config: ChannelConfig,
     pub regs: Vec<AudioParam>,
+    /// POKEY clock in Hz, as f64 bits, shared with the render thread
+    clock: Arc<AtomicU64>,
 }
 
 // implement required methods for AudioNode trait
@@ -113,9 +127,13 @@ impl PokeyNode {
                 param_ids.push(param_id);
             }
 
+            let clock = VideoStandard::Pal.cycles_per_second();
+            let clock = Arc::new(AtomicU64::new(clock.to_bits()));
+
             // setup the processor, this will run in the render thread
             let render = PokeyProcessor {
                 regs: param_ids,
+                clock: clock.clone(),
                 pokey: Pokey::new(context.sample_rate() as usize),
             };
 
@@ -124,6 +142,7 @@ impl PokeyNode {
                 registration,
                 channel_config: ChannelConfigOptions::default().into(),
                 regs: params,
+                clock,
             };
 
             (node, Box::new(render))
@@ -138,6 +157,7 @@ impl PokeyNode {
 
 struct PokeyProcessor {
     regs: Vec<AudioParamId>,
+    clock: Arc<AtomicU64>,
     pokey: Pokey,
 }
 
@@ -153,6 +173,8 @@ impl AudioProcessor for PokeyProcessor {
         // single output node
         let output = &mut outputs[0];
         let buf = output.channel_data_mut(0);
+        self.pokey
+            .set_clock(f64::from_bits(self.clock.load(Ordering::Relaxed)));
 
         let regs = (0..9)
             .map(|i| params.get(&self.regs[i]))
@@ -171,7 +193,11 @@ impl AudioProcessor for PokeyProcessor {
 }
 
 struct Pokey {
-    divider: usize,
+    sample_rate: usize,
+    /// POKEY cycles per output sample, the filter is designed for its integer part
+    cycles_per_sample: f64,
+    // fraction of a cycle carried to the next sample
+    cycle_frac: f64,
     filter: Box<dyn Filter + Send>,
     clock_cnt: isize,
     cycle_cnt: usize,
@@ -198,14 +224,16 @@ struct Pokey {
 
 impl Pokey {
     fn new(sample_rate: usize) -> Self {
-        let (divider, filter): (usize, Box<dyn Filter + Send>) = match sample_rate {
-            44100 => (40, Box::new(FilterCascade40_1::default())),
-            48000 => (37, Box::new(FIRFilter::new(FIR_37_TO_1))),
+        let filter: Box<dyn Filter + Send> = match sample_rate {
+            44100 => Box::new(FilterCascade40_1::default()),
+            48000 => Box::new(FIRFilter::new(FIR_37_TO_1)),
             // 56000 => 32,
             _ => panic!("sample rate {} is not supported", sample_rate),
         };
         let mut pokey = Self {
-            divider,
+            sample_rate,
+            cycles_per_sample: 0.0,
+            cycle_frac: 0.0,
             filter,
             clock_cnt: Default::default(),
             cycle_cnt: Default::default(),
@@ -230,11 +258,16 @@ impl Pokey {
             poly_17: Poly17::as_vec(),
         };
         pokey.set_audctl(0);
+        pokey.set_clock(VideoStandard::Pal.cycles_per_second());
         pokey
     }
 }
 
 impl Pokey {
+    fn set_clock(&mut self, clock: f64) {
+        self.cycles_per_sample = clock / self.sample_rate as f64;
+    }
+
     fn set_audctl(&mut self, value: u8) {
         self.audctl = value;
         self.fast_1 = (value & 0x40) > 0;
@@ -305,7 +338,10 @@ impl Pokey {
     }
 
     fn get(&mut self) -> f32 {
-        for _ in 0..self.divider {
+        self.cycle_frac += self.cycles_per_sample;
+        let cycles = self.cycle_frac as usize;
+        self.cycle_frac -= cycles as f64;
+        for _ in 0..cycles {
             self.clock_cnt -= 1;
             let clock_underflow = self.clock_cnt < 0;
             if clock_underflow {
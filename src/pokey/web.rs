@@ -4,6 +4,8 @@ use wasm_bindgen::{JsCast, JsValue};
 
 pub struct Context {
     audio_context: Option<web_sys::AudioContext>,
+    /// POKEY clock in Hz last passed to the worklet
+    clock: f64,
 }
 
 impl Context {
@@ -23,7 +25,12 @@ impl Context {
         }
     }
 
-    pub fn send_regs(&mut self, regs: &[Vec<super::PokeyRegWrite>], delta_t: f64) {
+    pub fn send_regs(
+        &mut self,
+        regs: &[Vec<super::PokeyRegWrite>],
+        delta_t: f64,
+        cycles_per_second: f64,
+    ) {
         let js_arr = regs
             .iter()
             .map(|reg_writes| {
@@ -33,7 +40,7 @@ impl Context {
                         [
                             r.index as f64,
                             r.value as f64,
-                            r.timestamp as f64 / (312.0 * 114.0 * 50.0) - delta_t + Self::LATENCY,
+                            r.timestamp as f64 / cycles_per_second - delta_t + Self::LATENCY,
                         ]
                     })
                     .map(|f| JsValue::from_f64(f))
@@ -41,8 +48,13 @@ impl Context {
                 JsValue::from(js_arr)
             })
             .collect::<js_sys::Array>();
+        // POKEY is clocked by the CPU clock of the video standard
+        if self.clock != cycles_per_second {
+            self.clock = cycles_per_second;
+            crate::js_imports::pokey_set_clock(cycles_per_second);
+        }
         let js_value = JsValue::from(js_arr);
-        crate::js_api::pokey_post_message(&js_value)
+        crate::js_imports::pokey_post_message(&js_value)
     }
 }
 
@@ -55,6 +67,9 @@ impl Default for Context {
                 .dyn_into::<web_sys::AudioContext>()
                 .ok()
         };
-        Self { audio_context }
+        Self {
+            audio_context,
+            clock: 0.0,
+        }
     }
 }
@@ -0,0 +1,155 @@
+use std::collections::VecDeque;
+
+use bevy::prelude::Component;
+
+/// Ring buffer of per-frame machine snapshots.
+///
+/// Only the newest snapshot is kept in full, older ones are stored as
+/// deltas against their successors, so dropping the oldest frame
+/// doesn't require any recompression.
+#[derive(Component, Default)]
+pub struct Rewind {
+    capacity: usize,
+    last: Option<Vec<u8>>,
+    // deltas[i] transforms snapshot i + 1 into snapshot i
+    deltas: VecDeque<Vec<u8>>,
+}
+
+impl Rewind {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            ..Default::default()
+        }
+    }
+
+    pub fn set_capacity(&mut self, capacity: usize) {
+        self.capacity = capacity;
+        if capacity == 0 {
+            self.clear();
+        }
+        while self.deltas.len() + 1 > capacity.max(1) {
+            self.deltas.pop_front();
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.last = None;
+        self.deltas.clear();
+    }
+
+    /// number of stored snapshots
+    pub fn len(&self) -> usize {
+        self.last
+            .as_ref()
+            .map(|_| self.deltas.len() + 1)
+            .unwrap_or(0)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.last.is_none()
+    }
+
+    pub fn push(&mut self, snapshot: Vec<u8>) {
+        if self.capacity == 0 {
+            return;
+        }
+        if let Some(last) = self.last.take() {
+            self.deltas.push_back(encode_delta(&snapshot, &last));
+            if self.deltas.len() >= self.capacity {
+                self.deltas.pop_front();
+            }
+        }
+        self.last = Some(snapshot);
+    }
+
+    /// Drops the newest snapshot and returns the previous one,
+    /// which becomes the newest
+    pub fn rewind(&mut self) -> Option<&[u8]> {
+        let delta = self.deltas.pop_back()?;
+        let last = self.last.as_ref()?;
+        self.last = Some(apply_delta(last, &delta));
+        self.last.as_deref()
+    }
+}
+
+fn write_varint(data: &mut Vec<u8>, mut value: usize) {
+    while value >= 0x80 {
+        data.push((value & 0x7f) as u8 | 0x80);
+        value >>= 7;
+    }
+    data.push(value as u8);
+}
+
+fn read_varint(data: &[u8], pos: &mut usize) -> usize {
+    let mut value = 0;
+    let mut shift = 0;
+    loop {
+        let b = data[*pos];
+        *pos += 1;
+        value |= ((b & 0x7f) as usize) << shift;
+        if b & 0x80 == 0 {
+            return value;
+        }
+        shift += 7;
+    }
+}
+
+/// Encodes `to` as run-length compressed XOR against `from`
+fn encode_delta(from: &[u8], to: &[u8]) -> Vec<u8> {
+    let diff = |i: usize| to[i] ^ from.get(i).copied().unwrap_or(0);
+    let mut delta = Vec::new();
+    write_varint(&mut delta, to.len());
+    let mut i = 0;
+    while i < to.len() {
+        let skip_start = i;
+        while i < to.len() && diff(i) == 0 {
+            i += 1;
+        }
+        let start = i;
+        while i < to.len() && diff(i) != 0 {
+            i += 1;
+        }
+        write_varint(&mut delta, start - skip_start);
+        write_varint(&mut delta, i - start);
+        delta.extend((start..i).map(diff));
+    }
+    delta
+}
+
+fn apply_delta(from: &[u8], delta: &[u8]) -> Vec<u8> {
+    let mut pos = 0;
+    let len = read_varint(delta, &mut pos);
+    let mut to = from[..from.len().min(len)].to_vec();
+    to.resize(len, 0);
+    let mut i = 0;
+    while pos < delta.len() {
+        i += read_varint(delta, &mut pos);
+        let n = read_varint(delta, &mut pos);
+        for (dst, diff) in to[i..i + n].iter_mut().zip(&delta[pos..pos + n]) {
+            *dst ^= diff;
+        }
+        i += n;
+        pos += n;
+    }
+    to
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rewind() {
+        let mut rewind = Rewind::new(3);
+        rewind.push(vec![1, 2, 3, 4]);
+        rewind.push(vec![1, 2, 5, 4, 6]);
+        rewind.push(vec![0, 2, 5]);
+        rewind.push(vec![0, 2, 5, 7]);
+        assert_eq!(rewind.len(), 3);
+        assert_eq!(rewind.rewind(), Some(&[0, 2, 5][..]));
+        assert_eq!(rewind.rewind(), Some(&[1, 2, 5, 4, 6][..]));
+        assert_eq!(rewind.rewind(), None);
+        assert_eq!(rewind.len(), 1);
+    }
+}
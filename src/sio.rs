@@ -0,0 +1,286 @@
+use std::collections::VecDeque;
+
+use bevy::prelude::*;
+
+use crate::atr::ATR;
+use crate::state::{SaveState, StateReader, StateWriter};
+
+pub const ACK: u8 = 0x41;
+pub const NAK: u8 = 0x4e;
+pub const COMPLETE: u8 = 0x43;
+pub const ERROR: u8 = 0x45;
+
+/// first disk drive device id, D1: - D4: are 0x31 - 0x34
+pub const DISK_DEVICE_ID: u8 = 0x31;
+
+// delays of device responses, in CPU cycles
+const ACK_DELAY: u64 = 1000;
+const COMPLETE_DELAY: u64 = 2000;
+
+pub enum Reply {
+    Nak,
+    /// data frame sent to the computer after COMPLETE / ERROR
+    Read {
+        status: u8,
+        data: Vec<u8>,
+    },
+    /// data frame of given length expected from the computer
+    Write {
+        len: usize,
+    },
+}
+
+/// Device attached to the serial bus
+pub trait SioDevice {
+    fn command(&mut self, command: u8, aux: u16) -> Reply;
+    /// handles data frame of write command, returns status
+    fn write(&mut self, command: u8, aux: u16, data: &[u8]) -> u8;
+}
+
+impl SioDevice for ATR {
+    fn command(&mut self, command: u8, aux: u16) -> Reply {
+        let sector = aux as usize;
+        match command {
+            0x53 => {
+                let mut data = vec![0; 4];
+                let status = self.get_status(&mut data);
+                Reply::Read { status, data }
+            }
+            0x52 if sector > 0 => {
+                let mut data = vec![0; self.sector_len(sector)];
+                let status = self.get_sector(sector, &mut data);
+                Reply::Read { status, data }
+            }
+            0x50 | 0x57 if sector > 0 => Reply::Write {
+                len: self.sector_len(sector),
+            },
+            _ => {
+                warn!("unsupported SIO command: {:02x}", command);
+                Reply::Nak
+            }
+        }
+    }
+
+    fn write(&mut self, _command: u8, aux: u16, data: &[u8]) -> u8 {
+        self.put_sector(aux as usize, data)
+    }
+}
+
+/// sum of bytes with end-around carry
+pub fn checksum(data: &[u8]) -> u8 {
+    data.iter().fold(0, |sum: u8, v| {
+        let (sum, carry) = sum.overflowing_add(*v);
+        sum + carry as u8
+    })
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum State {
+    Idle,
+    /// receiving command frame, command line asserted
+    Command,
+    /// receiving data frame of write command
+    Data {
+        device: u8,
+        command: u8,
+        len: usize,
+    },
+}
+
+/// Serial bus as seen by devices: command frames, ACK / COMPLETE and data frames
+pub struct SioBus {
+    state: State,
+    command_line: bool,
+    frame: Vec<u8>,
+    // bytes sent to the computer, with delay before each one
+    output: VecDeque<(u64, u8)>,
+    pub next_byte_at: u64,
+}
+
+impl Default for SioBus {
+    fn default() -> Self {
+        Self {
+            state: State::Idle,
+            command_line: false,
+            frame: Vec::new(),
+            output: VecDeque::new(),
+            next_byte_at: u64::MAX,
+        }
+    }
+}
+
+impl SioBus {
+    /// PIA CB2, asserted low during command frame
+    pub fn set_command_line(&mut self, asserted: bool, now: u64, byte_cycles: u64) {
+        if asserted == self.command_line {
+            return;
+        }
+        self.command_line = asserted;
+        if asserted {
+            self.state = State::Command;
+            self.frame.clear();
+            self.output.clear();
+            self.next_byte_at = u64::MAX;
+        } else {
+            if self.state == State::Command {
+                // incomplete command frame
+                self.state = State::Idle;
+            }
+            // device responds after command line release
+            self.schedule(now, byte_cycles);
+        }
+    }
+
+    /// byte sent by the computer
+    pub fn receive<D: SioDevice>(
+        &mut self,
+        byte: u8,
+        now: u64,
+        byte_cycles: u64,
+        devices: &mut [Option<D>],
+    ) {
+        match self.state {
+            State::Idle => (),
+            State::Command => {
+                self.frame.push(byte);
+                if self.frame.len() == 5 {
+                    self.state = State::Idle;
+                    if checksum(&self.frame[..4]) == self.frame[4] {
+                        self.command(devices);
+                    }
+                }
+            }
+            State::Data {
+                device,
+                command,
+                len,
+            } => {
+                self.frame.push(byte);
+                if self.frame.len() == 5 + len + 1 {
+                    self.state = State::Idle;
+                    let aux = self.aux();
+                    let frame = std::mem::take(&mut self.frame);
+                    let data = &frame[5..5 + len];
+                    if checksum(data) != frame[5 + len] {
+                        self.send(ACK_DELAY, NAK);
+                    } else if let Some(dev) = Self::device(devices, device) {
+                        let status = dev.write(command, aux, data);
+                        self.send(ACK_DELAY, ACK);
+                        let status = if status == 0x01 { COMPLETE } else { ERROR };
+                        self.send(COMPLETE_DELAY, status);
+                    }
+                    self.schedule(now, byte_cycles);
+                }
+            }
+        }
+    }
+
+    fn device<D: SioDevice>(devices: &mut [Option<D>], id: u8) -> Option<&mut D> {
+        let index = id.checked_sub(DISK_DEVICE_ID)? as usize;
+        devices.get_mut(index)?.as_mut()
+    }
+
+    fn aux(&self) -> u16 {
+        self.frame[2] as u16 + 256 * self.frame[3] as u16
+    }
+
+    fn command<D: SioDevice>(&mut self, devices: &mut [Option<D>]) {
+        let (device, command, aux) = (self.frame[0], self.frame[1], self.aux());
+        // not existing devices don't respond
+        let dev = match Self::device(devices, device) {
+            Some(dev) => dev,
+            None => return,
+        };
+        match dev.command(command, aux) {
+            Reply::Nak => self.send(ACK_DELAY, NAK),
+            Reply::Read { status, data } => {
+                self.send(ACK_DELAY, ACK);
+                let status = if status == 0x01 { COMPLETE } else { ERROR };
+                self.send(COMPLETE_DELAY, status);
+                for v in &data {
+                    self.send(0, *v);
+                }
+                self.send(0, checksum(&data));
+            }
+            Reply::Write { len } => {
+                self.send(ACK_DELAY, ACK);
+                self.frame.truncate(5);
+                self.state = State::Data {
+                    device,
+                    command,
+                    len,
+                };
+            }
+        }
+    }
+
+    fn send(&mut self, delay: u64, byte: u8) {
+        self.output.push_back((delay, byte));
+    }
+
+    fn schedule(&mut self, now: u64, byte_cycles: u64) {
+        self.next_byte_at = match self.output.front() {
+            Some((delay, _)) => now + delay + byte_cycles,
+            None => u64::MAX,
+        };
+    }
+
+    /// byte sent by the device, if transmission of one is finished
+    pub fn transmit(&mut self, now: u64, byte_cycles: u64) -> Option<u8> {
+        if now < self.next_byte_at {
+            return None;
+        }
+        let (_, byte) = self.output.pop_front()?;
+        self.schedule(now, byte_cycles);
+        Some(byte)
+    }
+}
+
+impl SaveState for SioBus {
+    fn save_state(&self, writer: &mut StateWriter) {
+        match self.state {
+            State::Idle => writer.u8(0),
+            State::Command => writer.u8(1),
+            State::Data {
+                device,
+                command,
+                len,
+            } => {
+                writer.u8(2);
+                writer.u8(device);
+                writer.u8(command);
+                writer.usize(len);
+            }
+        }
+        writer.bool(self.command_line);
+        writer.blob(&self.frame);
+        writer.u32(self.output.len() as u32);
+        for (delay, byte) in &self.output {
+            writer.u64(*delay);
+            writer.u8(*byte);
+        }
+        writer.u64(self.next_byte_at);
+    }
+
+    fn load_state(&mut self, reader: &mut StateReader) -> Result<(), String> {
+        self.state = match reader.u8()? {
+            0 => State::Idle,
+            1 => State::Command,
+            2 => State::Data {
+                device: reader.u8()?,
+                command: reader.u8()?,
+                len: reader.usize()?,
+            },
+            v => return Err(format!("invalid SIO state: {}", v)),
+        };
+        self.command_line = reader.bool()?;
+        self.frame = reader.blob()?;
+        self.output.clear();
+        for _ in 0..reader.u32()? {
+            let delay = reader.u64()?;
+            self.output.push_back((delay, reader.u8()?));
+        }
+        self.next_byte_at = reader.u64()?;
+        Ok(())
+    }
+}
@@ -0,0 +1,87 @@
+use bevy_atari_antic::{GTIARegs, ModeLineDescr};
+
+use super::{decode_playfield, draw_objects, BAK, HIRES, PF0, PF2, WIDTH};
+
+/// CPU implementation of GTIA collision detection.
+///
+/// Keeps data of the current mode line and registers latched for the
+/// current scanline, collisions are computed when the scanline is complete.
+/// Used when the display doesn't provide collisions read back from GPU.
+#[derive(Default)]
+pub struct CollisionDetector {
+    mode_line: Option<ModeLineDescr>,
+    // video memory followed by charset of the mode line
+    memory: Vec<u8>,
+    regs: GTIARegs,
+}
+
+impl CollisionDetector {
+    /// Sets mode line starting on the current scanline, `copy` fills
+    /// buffer with ANTIC memory at given address
+    pub fn set_mode_line(
+        &mut self,
+        mode_line: &ModeLineDescr,
+        copy: &mut dyn FnMut(u16, &mut [u8]),
+    ) {
+        let mut mode_line = mode_line.clone();
+        let (n_bytes, charset_size) = (mode_line.n_bytes, mode_line.charset_size());
+        self.memory.resize(n_bytes + charset_size, 0);
+        copy(mode_line.data_offset as u16, &mut self.memory[..n_bytes]);
+        copy(mode_line.chbase as u16 * 256, &mut self.memory[n_bytes..]);
+        mode_line.video_memory_offset = 0;
+        mode_line.charset_memory_offset = n_bytes;
+        self.mode_line = Some(mode_line);
+    }
+
+    pub fn set_gtia_regs(&mut self, regs: &GTIARegs) {
+        self.regs = regs.clone();
+    }
+
+    /// Collisions of the completed scanline, in format of GPU collision data
+    pub fn collisions(&self, scan_line: usize) -> u64 {
+        let regs = &self.regs;
+        if regs.grafp.iter().all(|v| *v == 0) && regs.grafm == 0 {
+            return 0;
+        }
+        let mut playfield = [BAK; WIDTH];
+        let mut hires = false;
+        if let Some(mode_line) = &self.mode_line {
+            if scan_line >= mode_line.scan_line
+                && scan_line < mode_line.scan_line + mode_line.height
+            {
+                decode_playfield(mode_line, &self.memory, scan_line, &mut playfield);
+                hires = matches!(mode_line.mode, 0x2 | 0x3 | 0xf);
+            }
+        }
+        let objects = draw_objects(regs);
+        // [MxPF, PxPF, MxPL, PxPL]
+        let mut data = [[0u8; 4]; 4];
+        for (pf, objects) in playfield.iter().zip(objects.iter()) {
+            if *objects == 0 {
+                continue;
+            }
+            // only lit pixels of hires modes collide, as PF2
+            let pf_bits = match *pf {
+                HIRES => 1 << (PF2 - PF0),
+                PF2 if hires => 0,
+                BAK => 0,
+                pf => 1 << (pf - PF0),
+            };
+            let players = objects & 0xf;
+            for i in 0..4 {
+                if objects & (0x10 << i) > 0 {
+                    data[0][i] |= pf_bits;
+                    data[2][i] |= players;
+                }
+                if players & (1 << i) > 0 {
+                    data[1][i] |= pf_bits;
+                    data[3][i] |= players & !(1 << i);
+                }
+            }
+        }
+        data.iter()
+            .flatten()
+            .rev()
+            .fold(0, |data, v| (data << 4) | *v as u64)
+    }
+}
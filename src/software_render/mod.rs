@@ -0,0 +1,486 @@
+use std::io::Write;
+
+use bevy::prelude::Component;
+use bevy::render::render_resource::{Extent3d, TextureDimension, TextureFormat};
+use bevy::render::texture::Image;
+use bevy_atari_antic::{GTIARegs, ModeLineDescr};
+
+use crate::antic::AnticDisplay;
+
+mod collisions;
+mod palette;
+pub use collisions::CollisionDetector;
+pub use palette::Palette;
+
+pub const WIDTH: usize = 384;
+pub const HEIGHT: usize = 240;
+
+// playfield pixel codes
+const BAK: u8 = 0;
+const PF0: u8 = 1;
+const PF1: u8 = 2;
+const PF2: u8 = 3;
+const PF3: u8 = 4;
+// hires pixel of modes 2, 3 and F: PF2 with luminance of PF1
+const HIRES: u8 = 5;
+
+// indices of GTIARegs::col
+const COL_PM0: usize = 0;
+const COL_PF0: usize = 4;
+const COL_PF1: usize = 5;
+const COL_BAK: usize = 8;
+
+/// CPU implementation of ANTIC / GTIA video output.
+///
+/// Consumes the same mode lines and per-scanline GTIA registers as the GPU
+/// renderer and produces 384x240 RGBA frame. Scanline is rendered when
+/// registers of the next one are latched, `frame` renders remaining ones.
+#[derive(Component)]
+pub struct SoftwareRenderer {
+    palette: [[u8; 4]; 256],
+    memory: Vec<u8>,
+    mode_lines: Vec<ModeLineDescr>,
+    gtia_regs: Vec<GTIARegs>,
+    // mid-scanline register changes: (color clock, regs)
+    gtia_changes: Vec<Vec<(usize, GTIARegs)>>,
+    // number of scanlines with GTIA registers set in current frame
+    regs_lines: usize,
+    // first not rendered scanline
+    next_line: usize,
+    frame: Vec<u8>,
+}
+
+impl Default for SoftwareRenderer {
+    fn default() -> Self {
+        SoftwareRenderer::new(Palette::default())
+    }
+}
+
+impl SoftwareRenderer {
+    pub fn new(palette: Palette) -> Self {
+        let mut frame = vec![0; WIDTH * HEIGHT * 4];
+        frame.chunks_mut(4).for_each(|pixel| pixel[3] = 255);
+        Self {
+            palette: palette.rgba(),
+            memory: Vec::with_capacity(0x10000),
+            mode_lines: Vec::with_capacity(HEIGHT),
+            gtia_regs: vec![GTIARegs::default(); HEIGHT],
+            gtia_changes: vec![Vec::new(); HEIGHT],
+            regs_lines: 0,
+            next_line: 0,
+            frame,
+        }
+    }
+
+    pub fn set_palette(&mut self, palette: Palette) {
+        self.palette = palette.rgba();
+    }
+
+    /// RGBA data of the current frame
+    pub fn frame(&mut self) -> &[u8] {
+        self.render_lines(self.regs_lines);
+        &self.frame
+    }
+
+    /// Writes the current frame as binary PPM image
+    pub fn write_ppm<W: Write>(&mut self, mut writer: W) -> std::io::Result<()> {
+        write!(writer, "P6\n{} {}\n255\n", WIDTH, HEIGHT)?;
+        let rgb = self
+            .frame()
+            .chunks(4)
+            .flat_map(|pixel| pixel[..3].iter().copied())
+            .collect::<Vec<_>>();
+        writer.write_all(&rgb)
+    }
+
+    fn mode_line(&self, line: usize) -> Option<&ModeLineDescr> {
+        let scan_line = line + 8;
+        self.mode_lines
+            .iter()
+            .rev()
+            .find(|m| m.scan_line <= scan_line && scan_line < m.scan_line + m.height)
+    }
+
+    fn render_lines(&mut self, end: usize) {
+        while self.next_line < end.min(self.regs_lines) {
+            self.render_line(self.next_line);
+            self.next_line += 1;
+        }
+    }
+
+    fn render_line(&mut self, line: usize) {
+        let mut playfield = [BAK; WIDTH];
+        if let Some(mode_line) = self.mode_line(line) {
+            decode_playfield(mode_line, &self.memory, line + 8, &mut playfield);
+        }
+        let regs = &self.gtia_regs[line];
+        let offset = line * WIDTH * 4;
+        let rgba = &mut self.frame[offset..offset + WIDTH * 4];
+        compose_line(regs, &playfield, &self.palette, rgba, 0);
+        for (hpos, regs) in &self.gtia_changes[line] {
+            // frame starts at color clock 32
+            let start = (hpos * 2).saturating_sub(64);
+            compose_line(regs, &playfield, &self.palette, rgba, start);
+        }
+    }
+}
+
+impl AnticDisplay for SoftwareRenderer {
+    fn clear(&mut self) {
+        self.render_lines(HEIGHT);
+        self.memory.clear();
+        self.mode_lines.clear();
+        self.regs_lines = 0;
+        self.next_line = 0;
+    }
+
+    fn set_gtia_regs(&mut self, index: usize, regs: &GTIARegs) {
+        self.render_lines(index);
+        self.gtia_regs[index] = regs.clone();
+        self.gtia_changes[index].clear();
+        self.regs_lines = index + 1;
+    }
+
+    fn change_gtia_regs(&mut self, index: usize, hpos: usize, regs: &GTIARegs) {
+        self.gtia_changes[index].push((hpos, regs.clone()));
+    }
+
+    fn gtia_changes(&self) -> bool {
+        true
+    }
+
+    fn reserve_antic_memory(&mut self, len: usize, f: &mut dyn FnMut(&mut [u8])) -> usize {
+        let offset = self.memory.len();
+        self.memory.resize(offset + len, 0);
+        f(&mut self.memory[offset..]);
+        offset
+    }
+
+    fn insert_mode_line(&mut self, mode_line: &ModeLineDescr) {
+        self.mode_lines.push(mode_line.clone());
+    }
+}
+
+/// Creates texture for the software renderer output
+pub fn create_image() -> Image {
+    Image::new_fill(
+        Extent3d {
+            width: WIDTH as u32,
+            height: HEIGHT as u32,
+            depth_or_array_layers: 1,
+        },
+        TextureDimension::D2,
+        &[0, 0, 0, 255],
+        TextureFormat::Rgba8UnormSrgb,
+    )
+}
+
+#[inline(always)]
+fn get(data: &[u8], index: usize) -> u8 {
+    data.get(index).copied().unwrap_or(0)
+}
+
+fn decode_playfield(
+    mode_line: &ModeLineDescr,
+    memory: &[u8],
+    scan_line: usize,
+    playfield: &mut [u8; WIDTH],
+) {
+    let mode = mode_line.mode;
+    if mode < 2 || mode_line.width == 0 {
+        return;
+    }
+    let row = mode_line.line_voffset + scan_line - mode_line.scan_line;
+    let data = &memory[mode_line.video_memory_offset..][..mode_line.n_bytes];
+    let charset = &memory[mode_line.charset_memory_offset..][..mode_line.charset_size()];
+    let left = (WIDTH - mode_line.width) / 2;
+    let hscrol = mode_line.hscrol as usize;
+
+    for (x, pixel) in playfield[left..left + mode_line.width]
+        .iter_mut()
+        .enumerate()
+    {
+        *pixel = playfield_pixel(mode, data, charset, x + hscrol, row);
+    }
+}
+
+/// Returns playfield code of pixel `x` (in hires pixels from the start of fetched data)
+fn playfield_pixel(mode: u8, data: &[u8], charset: &[u8], x: usize, row: usize) -> u8 {
+    match mode {
+        0x2 | 0x3 => {
+            let c = get(data, x / 8);
+            let row = if mode == 2 {
+                Some(row & 7)
+            } else {
+                // lowercase characters are shifted down by two scanlines
+                match (c & 0x60 == 0x60, row) {
+                    (false, 0..=7) => Some(row),
+                    (true, 2..=7) => Some(row),
+                    (true, 8..=9) => Some(row - 8),
+                    _ => None,
+                }
+            };
+            let mut bits = row
+                .map(|row| get(charset, (c & 0x7f) as usize * 8 + row))
+                .unwrap_or(0);
+            if c & 0x80 > 0 {
+                bits ^= 0xff;
+            }
+            if (bits >> (7 - x % 8)) & 1 > 0 {
+                HIRES
+            } else {
+                PF2
+            }
+        }
+        0x4 | 0x5 => {
+            let c = get(data, x / 8);
+            let row = if mode == 4 { row } else { row / 2 } & 7;
+            let bits = get(charset, (c & 0x7f) as usize * 8 + row);
+            match (bits >> (6 - (x % 8) / 2 * 2)) & 3 {
+                0 => BAK,
+                1 => PF0,
+                2 => PF1,
+                _ if c & 0x80 > 0 => PF3,
+                _ => PF2,
+            }
+        }
+        0x6 | 0x7 => {
+            let c = get(data, x / 16);
+            let row = if mode == 6 { row } else { row / 2 } & 7;
+            let bits = get(charset, (c & 0x3f) as usize * 8 + row);
+            if (bits >> (7 - (x % 16) / 2)) & 1 > 0 {
+                PF0 + (c >> 6)
+            } else {
+                BAK
+            }
+        }
+        _ => {
+            // (bits per pixel, pixel width in hires pixels)
+            let (bpp, width) = match mode {
+                0x8 => (2, 8),
+                0x9 => (1, 4),
+                0xa => (2, 4),
+                0xb | 0xc => (1, 2),
+                0xd | 0xe => (2, 2),
+                _ => (1, 1),
+            };
+            let n = x / width;
+            let per_byte = 8 / bpp;
+            let shift = (per_byte - 1 - n % per_byte) * bpp;
+            let value = (get(data, n / per_byte) >> shift) & ((1 << bpp) - 1);
+            match (mode, value) {
+                (0xf, 0) => PF2,
+                (0xf, _) => HIRES,
+                (_, 0) => BAK,
+                (_, value) => PF0 + value - 1,
+            }
+        }
+    }
+}
+
+/// Returns 4-bit GTIA mode pixel covering hires pixel `x`.
+///
+/// GTIA groups hires bits of ANTIC output by four, mode 10 pixels are
+/// delayed by one color clock.
+fn gtia_nibble(playfield: &[u8; WIDTH], x: usize, delay: usize) -> u8 {
+    // first pixel of the group, shifted by 4 to not underflow
+    let start = (x + 4 - delay) / 4 * 4 + delay;
+    (start..start + 4).fold(0, |nibble, x| {
+        let hires = x >= 4 && get(playfield, x - 4) == HIRES;
+        (nibble << 1) | hires as u8
+    })
+}
+
+/// Playfield code used for priority and color of GTIA mode pixel
+fn gtia_pixel(mode: u8, nibble: u8, col: &[u8]) -> (u8, u8) {
+    match mode {
+        // 16 luminances of COLBK hue
+        1 => (BAK, (col[COL_BAK] & 0xf0) | nibble),
+        // 9 colors: COLPM0-3, COLPF0-3, COLBK, values 12-15 mirror COLPF0-3
+        2 => match nibble {
+            0..=3 => (BAK, col[COL_PM0 + nibble as usize]),
+            4..=7 | 12..=15 => (PF0 + (nibble & 3), col[COL_PF0 + (nibble & 3) as usize]),
+            _ => (BAK, col[COL_BAK]),
+        },
+        // 16 hues with COLBK luminance, background is black
+        _ => match nibble {
+            0 => (BAK, col[COL_BAK] & 0xf0),
+            _ => (BAK, (nibble << 4) | (col[COL_BAK] & 0x0f)),
+        },
+    }
+}
+
+/// Marks pixels covered by player or missile with `mask`
+fn draw_object(
+    objects: &mut [u8; WIDTH],
+    graf: u8,
+    n_bits: usize,
+    hpos: u8,
+    size: usize,
+    mask: u8,
+) {
+    // size 0, 1, 2 means 1, 2, 4 color clocks per bit
+    let bit_width = 2 << size;
+    for bit in 0..n_bits {
+        if (graf >> (n_bits - 1 - bit)) & 1 == 0 {
+            continue;
+        }
+        // frame starts at color clock 32
+        let start = hpos as usize * 2 + bit * bit_width;
+        let end = (start + bit_width).min(WIDTH + 64);
+        for pixel in objects
+            .iter_mut()
+            .take(end.saturating_sub(64))
+            .skip(start.saturating_sub(64))
+        {
+            *pixel |= mask;
+        }
+    }
+}
+
+/// Players and missiles of the line, bits 0-3: players, bits 4-7: missiles
+fn draw_objects(regs: &GTIARegs) -> [u8; WIDTH] {
+    let mut objects = [0u8; WIDTH];
+    for i in 0..4 {
+        draw_object(
+            &mut objects,
+            regs.grafp[i],
+            8,
+            regs.hposp[i],
+            regs.sizep[i] as usize,
+            1 << i,
+        );
+        draw_object(
+            &mut objects,
+            (regs.grafm >> (i * 2)) & 3,
+            2,
+            regs.hposm[i],
+            (regs.sizem as usize >> (i * 2)) & 3,
+            0x10 << i,
+        );
+    }
+    objects
+}
+
+/// Renders pixels of the line starting at `start`
+fn compose_line(
+    regs: &GTIARegs,
+    playfield: &[u8; WIDTH],
+    palette: &[[u8; 4]; 256],
+    rgba: &mut [u8],
+    start: usize,
+) {
+    let objects = draw_objects(regs);
+    let fifth_player = regs.prior & 0x10 > 0;
+    let multicolor = regs.prior & 0x20 > 0;
+    // 0: normal, 1-3: GTIA modes 9, 10 and 11
+    let gtia_mode = regs.prior >> 6;
+    let col = &regs.col;
+
+    for (x, pixel) in rgba.chunks_mut(4).enumerate().skip(start) {
+        let (mut pf, hires) = match playfield[x] {
+            _ if gtia_mode > 0 => (BAK, false),
+            HIRES => (PF2, true),
+            pf => (pf, false),
+        };
+        let mut pf_color = match pf {
+            BAK => col[COL_BAK],
+            pf => col[COL_PF0 + (pf - PF0) as usize],
+        };
+        if gtia_mode > 0 {
+            let delay = if gtia_mode == 2 { 2 } else { 0 };
+            let nibble = gtia_nibble(playfield, x, delay);
+            let (gtia_pf, gtia_color) = gtia_pixel(gtia_mode, nibble, col);
+            pf = gtia_pf;
+            pf_color = gtia_color;
+        }
+        let mut players = objects[x] & 0xf;
+        if fifth_player && objects[x] & 0xf0 > 0 {
+            pf = PF3;
+            pf_color = col[COL_PF0 + 3];
+        } else {
+            players |= objects[x] >> 4;
+        }
+        let pair_color = |bits: u8, i: usize| {
+            if multicolor && bits == 3 {
+                col[i] | col[i + 1]
+            } else if bits & 1 > 0 {
+                col[i]
+            } else {
+                col[i + 1]
+            }
+        };
+        let is_pf01 = pf == PF0 || pf == PF1;
+        let (p01_over_pf, p23_over_pf) = match regs.prior & 0xf {
+            _ if pf == BAK => (true, true),
+            prior if prior & 1 > 0 => (true, true),
+            prior if prior & 2 > 0 => (true, false),
+            prior if prior & 4 > 0 => (false, false),
+            prior if prior & 8 > 0 => (!is_pf01, !is_pf01),
+            _ => (true, true),
+        };
+        let mut color = if players & 3 > 0 && p01_over_pf {
+            pair_color(players & 3, 0)
+        } else if players & 0xc > 0 && p23_over_pf {
+            pair_color(players >> 2, 2)
+        } else {
+            pf_color
+        };
+        if hires {
+            color = (color & 0xf0) | (col[COL_PF1] & 0x0f);
+        }
+        pixel.copy_from_slice(&palette[color as usize]);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::machine::Machine;
+
+    #[test]
+    fn test_background() {
+        let mut machine = Machine::new();
+        // LDA #$34; STA COLBK; JMP $2005
+        machine.copy_from_slice(0x2000, &[0xa9, 0x34, 0x8d, 0x1a, 0xd0, 0x4c, 0x05, 0x20]);
+        machine.cpu.set_program_counter(0x2000);
+        let mut renderer = SoftwareRenderer::default();
+        machine.run_frames(&mut renderer, 2);
+        let expected = Palette::default().rgba()[0x34];
+        let frame = renderer.frame();
+        assert_eq!(frame[..4], expected);
+        assert_eq!(frame[frame.len() - 4..], expected);
+    }
+
+    #[test]
+    fn test_mid_line_change() {
+        let palette = Palette::default().rgba();
+        let mut renderer = SoftwareRenderer::default();
+        let mut regs = GTIARegs::default();
+        renderer.set_gtia_regs(0, &regs);
+        regs.col[COL_BAK] = 0x34;
+        renderer.change_gtia_regs(0, 128, &regs);
+        let frame = renderer.frame();
+        assert_eq!(frame[191 * 4..192 * 4], palette[0]);
+        assert_eq!(frame[192 * 4..193 * 4], palette[0x34]);
+    }
+
+    #[test]
+    fn test_gtia_modes() {
+        let palette = Palette::default().rgba();
+        let mut playfield = [PF2; WIDTH];
+        // 0b0001 at pixels 0-3, 0b0110 at pixels 2-5 (mode 10)
+        playfield[3] = HIRES;
+        playfield[4] = HIRES;
+        let mut regs = GTIARegs::default();
+        regs.col[COL_BAK] = 0x40;
+        regs.col[COL_PF0 + 2] = 0x84;
+        let mut rgba = vec![0; WIDTH * 4];
+        let expected = [(0x40, 0, 0x41), (0x80, 2, 0x84), (0xc0, 0, 0x10)];
+        for (prior, x, color) in expected {
+            regs.prior = prior;
+            compose_line(&regs, &playfield, &palette, &mut rgba, 0);
+            assert_eq!(rgba[x * 4..x * 4 + 4], palette[color]);
+        }
+    }
+}
@@ -0,0 +1,41 @@
+use serde::{Deserialize, Serialize};
+
+#[derive(Clone, Copy, Serialize, Deserialize, Debug, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Palette {
+    Default,
+    Real,
+    XFormer,
+    Jakub,
+    Gray,
+    Ntsc,
+}
+
+impl Default for Palette {
+    fn default() -> Self {
+        Palette::Default
+    }
+}
+
+impl Palette {
+    /// 256 RGB entries in `.act` format
+    pub fn data(&self) -> &'static [u8; 768] {
+        match self {
+            Palette::Default => include_bytes!("../../act/default.act"),
+            Palette::Real => include_bytes!("../../act/Real.act"),
+            Palette::XFormer => include_bytes!("../../act/XFormer.act"),
+            Palette::Jakub => include_bytes!("../../act/jakub.act"),
+            Palette::Gray => include_bytes!("../../act/gray.act"),
+            Palette::Ntsc => include_bytes!("../../act/ntsc.act"),
+        }
+    }
+
+    pub fn rgba(&self) -> [[u8; 4]; 256] {
+        let data = self.data();
+        let mut rgba = [[0, 0, 0, 255]; 256];
+        for (i, color) in rgba.iter_mut().enumerate() {
+            color[..3].copy_from_slice(&data[i * 3..i * 3 + 3]);
+        }
+        rgba
+    }
+}
@@ -0,0 +1,213 @@
+use std::io::{Read, Write};
+
+use emulator_6502::MOS6502;
+use flate2::{read::GzDecoder, write::GzEncoder, Compression};
+
+use crate::system::AtariSystem;
+
+const MAGIC: &[u8; 4] = b"BA8S";
+pub const VERSION: u8 = 12;
+
+/// file used by quick save / load hotkeys
+#[cfg(target_arch = "wasm32")]
+pub const QUICK_STATE_PATH: &str = "/local/quick.state";
+#[cfg(not(target_arch = "wasm32"))]
+pub const QUICK_STATE_PATH: &str = "quick.state";
+
+pub fn gunzip(data: &[u8]) -> Result<Vec<u8>, String> {
+    let mut decoder = GzDecoder::new(data);
+    let mut result = Vec::new();
+    decoder
+        .read_to_end(&mut result)
+        .map_err(|e| format!("invalid gzip data: {}", e))?;
+    Ok(result)
+}
+
+pub fn gzip(data: &[u8]) -> Vec<u8> {
+    let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+    encoder.write_all(data).unwrap();
+    encoder.finish().unwrap()
+}
+
+#[derive(Default)]
+pub struct StateWriter {
+    data: Vec<u8>,
+}
+
+impl StateWriter {
+    pub fn u8(&mut self, value: u8) {
+        self.data.push(value);
+    }
+    pub fn bool(&mut self, value: bool) {
+        self.data.push(value as u8);
+    }
+    pub fn u16(&mut self, value: u16) {
+        self.data.extend_from_slice(&value.to_le_bytes());
+    }
+    pub fn u32(&mut self, value: u32) {
+        self.data.extend_from_slice(&value.to_le_bytes());
+    }
+    pub fn u64(&mut self, value: u64) {
+        self.data.extend_from_slice(&value.to_le_bytes());
+    }
+    pub fn usize(&mut self, value: usize) {
+        self.u64(value as u64);
+    }
+    /// fixed size data, length has to be known when reading
+    pub fn bytes(&mut self, data: &[u8]) {
+        self.data.extend_from_slice(data);
+    }
+    /// variable size data
+    pub fn blob(&mut self, data: &[u8]) {
+        self.u32(data.len() as u32);
+        self.bytes(data);
+    }
+    pub fn str(&mut self, value: &str) {
+        self.blob(value.as_bytes());
+    }
+    pub fn into_inner(self) -> Vec<u8> {
+        self.data
+    }
+}
+
+pub struct StateReader<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> StateReader<'a> {
+    pub fn new(data: &'a [u8]) -> Self {
+        Self { data }
+    }
+    fn take(&mut self, len: usize) -> Result<&'a [u8], String> {
+        if self.data.len() < len {
+            return Err("truncated state data".to_string());
+        }
+        let (head, tail) = self.data.split_at(len);
+        self.data = tail;
+        Ok(head)
+    }
+    pub fn u8(&mut self) -> Result<u8, String> {
+        Ok(self.take(1)?[0])
+    }
+    pub fn bool(&mut self) -> Result<bool, String> {
+        Ok(self.u8()? > 0)
+    }
+    pub fn u16(&mut self) -> Result<u16, String> {
+        Ok(u16::from_le_bytes(self.take(2)?.try_into().unwrap()))
+    }
+    pub fn u32(&mut self) -> Result<u32, String> {
+        Ok(u32::from_le_bytes(self.take(4)?.try_into().unwrap()))
+    }
+    pub fn u64(&mut self) -> Result<u64, String> {
+        Ok(u64::from_le_bytes(self.take(8)?.try_into().unwrap()))
+    }
+    pub fn usize(&mut self) -> Result<usize, String> {
+        Ok(self.u64()? as usize)
+    }
+    pub fn bytes(&mut self, data: &mut [u8]) -> Result<(), String> {
+        data.copy_from_slice(self.take(data.len())?);
+        Ok(())
+    }
+    pub fn blob(&mut self) -> Result<Vec<u8>, String> {
+        let len = self.u32()? as usize;
+        Ok(self.take(len)?.to_vec())
+    }
+    pub fn str(&mut self) -> Result<String, String> {
+        String::from_utf8(self.blob()?).map_err(|e| e.to_string())
+    }
+}
+
+/// Component which can be stored in the native save state
+pub trait SaveState {
+    fn save_state(&self, writer: &mut StateWriter);
+    fn load_state(&mut self, reader: &mut StateReader) -> Result<(), String>;
+}
+
+impl SaveState for MOS6502 {
+    fn save_state(&self, writer: &mut StateWriter) {
+        writer.u16(self.get_program_counter());
+        writer.u8(self.get_accumulator());
+        writer.u8(self.get_x_register());
+        writer.u8(self.get_y_register());
+        writer.u8(self.get_stack_pointer());
+        writer.u8(self.get_status_register());
+    }
+
+    fn load_state(&mut self, reader: &mut StateReader) -> Result<(), String> {
+        self.set_program_counter(reader.u16()?);
+        self.set_accumulator(reader.u8()?);
+        self.set_x_register(reader.u8()?);
+        self.set_y_register(reader.u8()?);
+        self.set_stack_pointer(reader.u8()?);
+        self.set_status_register(reader.u8()?);
+        Ok(())
+    }
+}
+
+pub fn is_native_state(data: &[u8]) -> bool {
+    data.starts_with(MAGIC)
+}
+
+/// Creates uncompressed snapshot of the whole machine.
+///
+/// Includes cycles left of the current CPU instruction and pending interrupts.
+/// ROMs are not stored, machine profile and memory model have to match on load.
+pub fn snapshot(atari_system: &AtariSystem, cpu: &MOS6502) -> Vec<u8> {
+    let mut writer = StateWriter::default();
+    writer.bytes(MAGIC);
+    writer.u8(VERSION);
+    cpu.save_state(&mut writer);
+    writer.u8(atari_system.cpu_remaining_cycles(cpu));
+    atari_system.save_state(&mut writer);
+    writer.into_inner()
+}
+
+/// Creates gzipped snapshot of the whole machine
+pub fn save(atari_system: &AtariSystem, cpu: &MOS6502) -> Vec<u8> {
+    gzip(&snapshot(atari_system, cpu))
+}
+
+/// Restores snapshot created by `save` or `snapshot`.
+///
+/// Machine is left unchanged when the snapshot is invalid.
+pub fn load(atari_system: &mut AtariSystem, cpu: &mut MOS6502, data: &[u8]) -> Result<(), String> {
+    let data = if is_native_state(data) {
+        data.to_vec()
+    } else {
+        gunzip(data)?
+    };
+    let mut reader = StateReader::new(&data);
+    let mut magic = [0; 4];
+    reader.bytes(&mut magic)?;
+    if &magic != MAGIC {
+        return Err("not a bevy-atari state".to_string());
+    }
+    let version = reader.u8()?;
+    if version != VERSION {
+        return Err(format!("unsupported state version: {}", version));
+    }
+    // truncated or corrupt state is detected after part of it is loaded,
+    // so it is loaded into scratch machine first
+    let state = &data[MAGIC.len() + 1..];
+    let mut scratch = atari_system.scratch();
+    load_machine(
+        &mut scratch,
+        &mut MOS6502::default(),
+        &mut StateReader::new(state),
+    )?;
+    load_machine(atari_system, cpu, &mut StateReader::new(state))
+}
+
+fn load_machine(
+    atari_system: &mut AtariSystem,
+    cpu: &mut MOS6502,
+    reader: &mut StateReader,
+) -> Result<(), String> {
+    // fresh CPU, without pending interrupts of the current one
+    *cpu = MOS6502::default();
+    cpu.load_state(reader)?;
+    let remaining_cycles = reader.u8()?;
+    atari_system.load_state(reader)?;
+    atari_system.restore_cpu(cpu, remaining_cycles);
+    Ok(())
+}
@@ -1,14 +1,23 @@
+use crate::antic::CharsetCache;
 use crate::atr::ATR;
 use crate::cartridge::Cartridge;
+use crate::config::{MachineProfile, MemoryModel, VideoStandard};
+use crate::keystrokes::{self, Stroke};
+use crate::mapping::{Controls, Direction, InputMapping, Target};
+use crate::movie::{self, InputEvent, MovieState};
 use crate::multiplexer::Multiplexer;
 use crate::platform::FileSystem;
 use crate::pokey::{PokeyRegQueue, PokeyRegWrite};
+use crate::sio::SioBus;
+use crate::software_render::CollisionDetector;
+use crate::state::{SaveState, StateReader, StateWriter};
 use crate::EmulatorConfig;
 pub use crate::{antic, gtia};
 pub use crate::{antic::Antic, gtia::Gtia, pia::PIA, pokey::Pokey};
 use crate::{atari800_state::Atari800State, pokey};
 pub use bevy::prelude::*;
 pub use emulator_6502::{Interface6502, MOS6502};
+use std::collections::{BTreeMap, VecDeque};
 use std::sync::Arc;
 pub use std::{cell::RefCell, rc::Rc};
 
@@ -21,6 +30,7 @@ bitflags! {
         const BASIC_DISABLED = 0x02;
         const SELFTEST_DISABLED = 0x80;
         const UNUSED = 0x40;
+        const GAME_DISABLED = 0x40; // XEGS only
         const ANITC_SELECT_NEG = 0x20;
         const CPU_SELECT_NEG = 0x10;
         const BANK_MASK = 0b1100;
@@ -28,55 +38,123 @@ bitflags! {
     }
 }
 
+/// chip decoded at given memory page
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum IoChip {
+    None,
+    Unmapped,
+    Gtia,
+    Pokey,
+    Pia,
+    Antic,
+    CartCtl,
+}
+
+fn io_map(machine: MachineProfile) -> [IoChip; 256] {
+    let mut map = [IoChip::None; 256];
+    if machine == MachineProfile::Atari5200 {
+        // no PIA, GTIA and POKEY are mirrored over the whole 4k / 2k area
+        map[0xc0..=0xcf].fill(IoChip::Gtia);
+        map[0xd4..=0xd5].fill(IoChip::Antic);
+        map[0xe8..=0xef].fill(IoChip::Pokey);
+    } else {
+        map[0xd0..=0xd7].fill(IoChip::Unmapped);
+        map[0xd0] = IoChip::Gtia;
+        map[0xd2] = IoChip::Pokey;
+        map[0xd3] = IoChip::Pia;
+        map[0xd4] = IoChip::Antic;
+        map[0xd5] = IoChip::CartCtl;
+    }
+    map
+}
+
 #[derive(Component)]
 pub struct AtariSystem {
     consol: Multiplexer<u8>,
+    // inputs: gamepad, Machine API, keyboard arrows, light pen trigger
     joystick: [Multiplexer<u8>; 2],
+    // position and trigger of paddles 0-3
+    paddles: [(u8, bool); 4],
     pub read_banks: [*const MemBank; 32],
+    antic_read_banks: [*const MemBank; 32],
     write_banks: [*mut MemBank; 32],
+    io_map: [IoChip; 256],
     rom_write_bank: Vec<u8>,
+    // unmapped memory, reads as 0xff
+    empty_bank: Vec<u8>,
     ram: Vec<u8>,
     ram_copy: Vec<u8>,
     ram_mask: Vec<u8>,
     pub osrom: Vec<u8>,
+    bios5200: Vec<u8>,
     basic: Option<Vec<u8>>,
-    ext_mem_bank_mask: Option<usize>,
+    // XEGS built-in game
+    game: Option<Vec<u8>>,
+    memory_model: MemoryModel,
+    machine: MachineProfile,
+    // last machine profile / keyboard_detached values seen in config
+    configured_machine: Option<(MachineProfile, bool)>,
+    // 400/800 has no PORTB bit for BASIC, it is enabled on reset
+    basic_enabled: bool,
+    keyboard_detached: bool,
     pub antic: Antic,
+    pub charset_cache: CharsetCache,
     pub gtia: Gtia,
+    // collisions computed on CPU if display doesn't provide them
+    pub collision_detector: CollisionDetector,
     pub pokey: Pokey,
     pub pia: PIA,
     pub disks: [Option<ATR>; 4],
+    pub sio: SioBus,
+    // handle SIOV calls with the hook instead of serial transfer through POKEY
+    pub sio_accelerator: bool,
     ticks: usize,
+    // CPU state not available through MOS6502 API, kept for save states:
+    // interrupts requested after the current instruction started
+    irq_requested: bool,
+    nmi_requested: bool,
+    // cycles of the CPU instruction left when the state was loaded
+    cpu_stall: u8,
     pub cart: Option<Box<dyn Cartridge>>,
-    pub keycodes: Vec<Option<(KeyCode, bool)>>,
+    // typed text, one stroke per frame
+    strokes: VecDeque<Stroke>,
+    // console and break keys pressed by typed text
+    typed_controls: Controls,
+    pub input_mapping: InputMapping,
     pub pokey_reg_queue: Arc<RefCell<PokeyRegQueue>>,
+    pub movie: MovieState,
+    // hashes of loaded media, by `set_binary` key
+    pub media: BTreeMap<String, u64>,
 }
 unsafe impl Send for AtariSystem {}
 unsafe impl Sync for AtariSystem {}
 
 type MemBank = [u8; 2048];
 
-#[allow(dead_code)]
-const MEM_64: Option<usize> = None;
-#[allow(dead_code)]
-const MEM_128: Option<usize> = Some(3);
-#[allow(dead_code)]
-const MEM_320: Option<usize> = Some(15);
-
 impl AtariSystem {
     pub fn new() -> AtariSystem {
+        AtariSystem::with_pokey(Pokey::default())
+    }
+
+    /// AtariSystem without audio output
+    pub fn new_headless() -> AtariSystem {
+        AtariSystem::with_pokey(Pokey::headless())
+    }
+
+    fn with_pokey(mut pokey: Pokey) -> AtariSystem {
         // initialize RAM with all 0xFFs
+        let memory_model = MemoryModel::default();
         let mut ram: Vec<u8> = Vec::new();
-        ram.resize_with(320 * 1024, || 0);
-        let rom_write_bank = vec![0; 0x800];
+        ram.resize_with(memory_model.ram_size(), || 0);
+        // spans whole 8k bank, see setup_memory_banks
+        let rom_write_bank = vec![0; 0x2000];
         let osrom = vec![0; 0x4000];
         let basic = None;
         let antic = Antic::default();
-        let mut pokey = Pokey::default();
         let mut gtia = Gtia::default();
         let pia = PIA::default();
         let consol = Multiplexer::new(2);
-        let joystick = [Multiplexer::new(3), Multiplexer::new(3)];
+        let joystick = [Multiplexer::new(4), Multiplexer::new(4)];
 
         let read_banks = [0 as *const MemBank; 32];
         let write_banks = [0 as *mut MemBank; 32];
@@ -87,37 +165,118 @@ impl AtariSystem {
         // gtia.pokey_reg_queue = pokey_reg_queue.clone();
         let mut atari_system = AtariSystem {
             pokey_reg_queue: pokey_reg_queue,
-            ext_mem_bank_mask: MEM_320,
+            memory_model,
+            machine: MachineProfile::default(),
+            configured_machine: None,
+            basic_enabled: true,
+            keyboard_detached: false,
             consol,
             joystick,
+            paddles: [(pokey::POT_MAX, false); 4],
             ram,
             read_banks,
+            antic_read_banks: read_banks,
             write_banks,
+            io_map: io_map(MachineProfile::default()),
             rom_write_bank,
+            empty_bank: vec![0xff; 0x800],
             ram_copy: Vec::new(),
             ram_mask: Vec::new(),
             osrom,
+            bios5200: vec![0; 0x800],
             basic,
+            game: None,
             antic,
+            charset_cache: CharsetCache::default(),
             gtia,
+            collision_detector: CollisionDetector::default(),
             pokey,
             pia,
             disks: Default::default(),
+            sio: SioBus::default(),
+            sio_accelerator: true,
             ticks: 0,
+            irq_requested: false,
+            nmi_requested: false,
+            cpu_stall: 0,
             cart: None,
-            keycodes: Vec::new(),
+            strokes: VecDeque::new(),
+            typed_controls: Controls::default(),
+            input_mapping: InputMapping::default(),
+            movie: MovieState::Idle,
+            media: BTreeMap::new(),
         };
         atari_system.setup_memory_banks();
         atari_system
     }
 
     pub fn configure(&mut self, config: &EmulatorConfig) {
-        self.pokey_reg_queue.borrow_mut().stereo = config.stereo;
+        let ntsc = config.video_standard == VideoStandard::Ntsc;
+        self.antic.ntsc = ntsc;
+        self.gtia.ntsc = ntsc;
+        self.sio_accelerator = config.sio_accelerator;
+        let mut reg_queue = self.pokey_reg_queue.borrow_mut();
+        reg_queue.stereo = config.stereo;
+        reg_queue.cycles_per_second = config.video_standard.cycles_per_second();
+        drop(reg_queue);
+        if config.memory != self.memory_model {
+            self.set_memory_model(config.memory);
+        }
+        // profile may be also changed by loaded state, so apply only config changes
+        let machine = (config.machine, config.keyboard_detached);
+        if self.configured_machine != Some(machine) {
+            self.configured_machine = Some(machine);
+            self.set_machine_profile(config.machine, config.keyboard_detached);
+        }
+    }
+
+    pub fn machine_profile(&self) -> MachineProfile {
+        self.machine
+    }
+
+    pub fn set_machine_profile(&mut self, machine: MachineProfile, keyboard_detached: bool) {
+        info!(
+            "machine profile: {:?}, keyboard detached: {:?}",
+            machine, keyboard_detached
+        );
+        self.machine = machine;
+        self.io_map = io_map(machine);
+        self.keyboard_detached = machine == MachineProfile::XEGS && keyboard_detached;
+        self.pokey.keyboard.release_all();
+        // XEGS senses keyboard presence on TRIG2
+        self.gtia.trig[2] = !self.keyboard_detached as u8;
+        let pot = if machine == MachineProfile::Atari5200 {
+            pokey::POT_5200_CENTER
+        } else {
+            pokey::POT_MAX
+        };
+        for i in 0..8 {
+            self.pokey.set_pot(i, pot);
+        }
+        self.setup_memory_banks();
+    }
+
+    /// Headless machine with the same profile and memory model, used to validate states before loading them
+    pub fn scratch(&self) -> AtariSystem {
+        let mut scratch = AtariSystem::new_headless();
+        scratch.memory_model = self.memory_model;
+        scratch.ram.resize(self.ram.len(), 0);
+        scratch.machine = self.machine;
+        scratch
+    }
+
+    pub fn set_memory_model(&mut self, memory_model: MemoryModel) {
+        info!("memory model: {:?}", memory_model);
+        self.memory_model = memory_model;
+        self.ram.resize(memory_model.ram_size(), 0);
+        self.setup_memory_banks();
     }
 
     pub fn set_cart(&mut self, cart: Option<Box<dyn Cartridge>>) {
         self.cart = cart;
-        self.gtia.trig[3] = if self.cart.is_some() { 1 } else { 0 };
+        if self.machine != MachineProfile::Atari5200 {
+            self.gtia.trig[3] = if self.cart.is_some() { 1 } else { 0 };
+        }
         self.setup_memory_banks();
     }
 
@@ -144,17 +303,22 @@ impl AtariSystem {
         cnt
     }
     fn setup_memory_banks(&mut self) {
+        // charsets may be in banks switched out
+        self.charset_cache.clear();
         // reduce cost of calling of _bank_ptr ~4x
         for i in 0..32 {
-            if (i & 3) == 0 || i == (0x5000 >> 11) {
+            // 5200 has mirrored 2k / 4k areas, so compute every block
+            if (i & 3) == 0 || i == (0x5000 >> 11) || self.machine == MachineProfile::Atari5200 {
                 // assume 8kB (2kB * 4) banks except of self test one
                 // compute address of first block in the bank
                 self.read_banks[i] = self._bank_ptr(i << 11, false, false);
+                self.antic_read_banks[i] = self._bank_ptr(i << 11, true, false);
                 self.write_banks[i] = self._bank_ptr(i << 11, false, true) as *mut MemBank;
             } else {
                 // for non-first blocks of 8k bank we computing address using first one
                 unsafe {
                     self.read_banks[i] = self.read_banks[i & !3].add(i & 3);
+                    self.antic_read_banks[i] = self.antic_read_banks[i & !3].add(i & 3);
                     self.write_banks[i] = self.write_banks[i & !3].add(i & 3);
                 }
             }
@@ -162,14 +326,18 @@ impl AtariSystem {
     }
 
     fn bank_offset(&self, addr: usize, antic: bool) -> usize {
-        let ext_mem_req = !antic && !self.pia.portb_out().contains(PORTB::CPU_SELECT_NEG)
-            || antic && !self.pia.portb_out().contains(PORTB::ANITC_SELECT_NEG);
-
-        if let (true, Some(mask)) = (ext_mem_req, self.ext_mem_bank_mask) {
-            let portb = self.pia.portb_out().bits;
-            let bank_nr = (((portb & 0b1100) + ((portb & 0xc0) >> 2)) as usize) >> 2;
-            let bank_nr = bank_nr & mask;
-            // let bank_nr = (portb & 0b1100) as usize >> 2;
+        if !self.machine.has_portb_memory_control() {
+            return addr;
+        }
+        let portb = self.pia.portb_out();
+        let ext_mem_req = if antic && self.memory_model.separate_antic_access() {
+            !portb.contains(PORTB::ANITC_SELECT_NEG)
+        } else {
+            !portb.contains(PORTB::CPU_SELECT_NEG)
+        };
+
+        if ext_mem_req && self.memory_model.ext_banks() > 0 {
+            let bank_nr = self.memory_model.bank(portb.bits);
             (addr & 0x3fff) + 0x10000 + (bank_nr * 16384)
         } else {
             addr
@@ -177,30 +345,38 @@ impl AtariSystem {
     }
 
     #[inline(always)]
-    fn _io_read(&mut self, addr: usize, _antic: bool) -> u8 {
-        let addr = usize::from(addr);
-        match addr >> 8 {
-            0xD0 => self.gtia.read(addr),
-            0xD1 => 0xff,
-            0xD2 => self.pokey.read(addr),
-            0xD3 => self.pia.read(addr),
-            0xD4 => self.antic.read(addr),
-            _ => 0xff, // panic!("wrong io read address!"),
+    fn _io_read(&mut self, chip: IoChip, addr: usize, _antic: bool) -> u8 {
+        match chip {
+            IoChip::Gtia => self.gtia.read(addr),
+            IoChip::Pokey => self.pokey.read(addr),
+            IoChip::Pia => self.pia.read(addr),
+            IoChip::Antic => self.antic.read(addr),
+            _ => 0xff,
         }
     }
     #[inline(always)]
-    fn _io_write(&mut self, addr: usize, value: u8, _antic: bool) {
-        match addr >> 8 {
-            0xD0 => self.gtia.write(addr, value),
-            0xD2 => self.pokey.write(addr, value),
-            0xD3 => {
+    fn _io_write(&mut self, chip: IoChip, addr: usize, value: u8, _antic: bool) {
+        match chip {
+            IoChip::Gtia => {
+                self.gtia.write(addr, value);
+                let hpos = self.antic.cycle * 2;
+                self.gtia.record_change(addr, self.antic.scan_line, hpos);
+            }
+            IoChip::Pokey => self.pokey.write(addr, value),
+            IoChip::Pia => {
                 self.pia.write(addr, value);
-                if (addr & 3) == 1 {
-                    self.setup_memory_banks();
-                };
+                match addr & 3 {
+                    1 => self.setup_memory_banks(),
+                    3 => self.sio.set_command_line(
+                        self.pia.sio_command(),
+                        self.antic.total_cycles,
+                        self.pokey.serial_byte_cycles(),
+                    ),
+                    _ => (),
+                }
             }
-            0xD4 => self.antic.write(addr, value),
-            0xD5 => match &mut self.cart {
+            IoChip::Antic => self.antic.write(addr, value),
+            IoChip::CartCtl => match &mut self.cart {
                 Some(cart) => {
                     cart.write(addr, value);
                     self.setup_memory_banks();
@@ -211,11 +387,56 @@ impl AtariSystem {
         }
     }
 
+    /// XL/XE or 400/800 OS ROM, used by SIO hook
     pub fn is_rom_enabled(&self) -> bool {
-        self.pia.portb_out().contains(PORTB::OSROM_ENABLED)
+        if self.machine == MachineProfile::Atari5200 {
+            return false;
+        }
+        !self.machine.has_portb_memory_control()
+            || self.pia.portb_out().contains(PORTB::OSROM_ENABLED)
+    }
+
+    fn is_basic_enabled(&self) -> bool {
+        if self.machine.has_portb_memory_control() {
+            !self.pia.portb_out().contains(PORTB::BASIC_DISABLED)
+        } else {
+            self.basic_enabled
+        }
+    }
+
+    fn is_selftest_enabled(&self) -> bool {
+        let portb = self.pia.portb_out();
+        self.machine.has_portb_memory_control()
+            && portb.contains(PORTB::OSROM_ENABLED)
+            && !portb.contains(PORTB::SELFTEST_DISABLED)
+            && (portb & PORTB::BANK_SELECT_NEG) == PORTB::BANK_SELECT_NEG
+    }
+
+    fn is_game_enabled(&self) -> bool {
+        self.machine == MachineProfile::XEGS && !self.pia.portb_out().contains(PORTB::GAME_DISABLED)
+    }
+
+    fn _bank_ptr_5200(&self, addr: usize, write: bool) -> *const MemBank {
+        // 0x00..0x3f - RAM
+        // 0x40..0xbf - CART
+        // 0xf0..0xff - BIOS, mirrored
+        let mem_ref = match addr >> 8 {
+            0x00..=0x3f => &self.ram[addr],
+            _ if write => &self.rom_write_bank[0],
+            0x40..=0xbf => match &self.cart {
+                Some(cart) => cart.read(addr),
+                None => &self.empty_bank[0],
+            },
+            0xf0..=0xff => &self.bios5200[addr & 0x7ff],
+            _ => &self.empty_bank[0],
+        };
+        mem_ref as *const u8 as *const MemBank
     }
 
     fn _bank_ptr(&mut self, addr: usize, antic: bool, write: bool) -> *const MemBank {
+        if self.machine == MachineProfile::Atari5200 {
+            return self._bank_ptr_5200(addr, write);
+        }
         // 0x00..0x3f - RAM
         // 0x40..0x7f - RAM / EXT_RAM / SELFTEST / ANTIC
         // 0x80..0x9f - RAM
@@ -225,11 +446,7 @@ impl AtariSystem {
         // 0xC0..0xff - RAM / ROM (without D0..D7)
         let mem_ref = match addr >> 8 {
             0x50..=0x57 => {
-                let portb = self.pia.portb_out();
-                if portb.contains(PORTB::OSROM_ENABLED)
-                    && !portb.contains(PORTB::SELFTEST_DISABLED)
-                    && (portb & PORTB::BANK_SELECT_NEG) == PORTB::BANK_SELECT_NEG
-                {
+                if self.is_selftest_enabled() {
                     if !write {
                         &self.osrom[0x1000 + (addr & 0x7ff)]
                     } else {
@@ -252,23 +469,26 @@ impl AtariSystem {
                         // info!("no cart at {:04x}", addr);
                     }
                 }
-                if !self.pia.portb_out().contains(PORTB::BASIC_DISABLED) {
-                    match &self.basic {
-                        Some(basic) => {
-                            if !write {
-                                &basic[addr & 0x1fff]
-                            } else {
-                                &self.rom_write_bank[0]
-                            }
+                let rom = if self.is_basic_enabled() {
+                    self.basic.as_ref()
+                } else if self.is_game_enabled() {
+                    self.game.as_ref()
+                } else {
+                    None
+                };
+                match rom {
+                    Some(rom) => {
+                        if !write {
+                            &rom[addr & 0x1fff]
+                        } else {
+                            &self.rom_write_bank[0]
                         }
-                        None => &self.ram[addr],
                     }
-                } else {
-                    &self.ram[addr]
+                    None => &self.ram[addr],
                 }
             }
             0xC0..=0xFF => {
-                if self.pia.portb_out().contains(PORTB::OSROM_ENABLED) {
+                if self.is_rom_enabled() {
                     if !write {
                         &self.osrom[addr & 0x3fff]
                     } else {
@@ -287,27 +507,61 @@ impl AtariSystem {
     #[inline(always)]
     fn _read(&mut self, addr: u16, antic: bool) -> u8 {
         let addr = addr as usize;
-        match addr >> 8 {
-            0xd0..=0xd7 => self._io_read(addr, antic),
-            _ => unsafe { (*self.read_banks[addr >> 11])[addr & 2047] },
+        match self.io_map[addr >> 8] {
+            IoChip::None if antic => unsafe { (*self.antic_read_banks[addr >> 11])[addr & 2047] },
+            IoChip::None => unsafe { (*self.read_banks[addr >> 11])[addr & 2047] },
+            chip => self._io_read(chip, addr, antic),
         }
     }
     #[inline(always)]
     fn _write(&mut self, addr: u16, value: u8, antic: bool) {
         let addr = addr as usize;
-        match addr >> 8 {
-            0xd0..=0xd7 => self._io_write(addr, value, antic),
-            _ => unsafe { (*self.write_banks[addr >> 11])[addr & 2047] = value },
+        match self.io_map[addr >> 8] {
+            IoChip::None => {
+                self.charset_cache.write(addr);
+                unsafe { (*self.write_banks[addr >> 11])[addr & 2047] = value }
+            }
+            chip => self._io_write(chip, addr, value, antic),
         }
     }
 
+    /// Loads 16k XL/XE OS or 10k 400/800 OS, mapped at $D800
     pub fn set_osrom(&mut self, data: Option<&[u8]>) {
         let data: &[u8] = if let Some(data) = data {
             data
         } else {
             &[0; 0x4000]
         };
-        self.osrom.copy_from_slice(data);
+        match data.len() {
+            0x4000 => self.osrom.copy_from_slice(data),
+            0x2800 => {
+                self.osrom[..0x1800].fill(0xff);
+                self.osrom[0x1800..].copy_from_slice(data);
+            }
+            len => warn!("invalid OS ROM size: {}", len),
+        }
+        if self.machine != MachineProfile::Atari5200 && data.len() != self.machine.osrom_size() {
+            warn!("OS ROM size doesn't match {:?} machine", self.machine);
+        }
+    }
+
+    /// Loads 2k 5200 BIOS, mapped at $F800 and mirrored at $F000
+    pub fn set_bios5200(&mut self, data: Option<&[u8]>) {
+        match data {
+            Some(data) if data.len() == 0x800 => self.bios5200.copy_from_slice(data),
+            Some(data) => warn!("invalid 5200 BIOS size: {}", data.len()),
+            None => self.bios5200.fill(0),
+        }
+        self.setup_memory_banks();
+    }
+
+    pub fn set_game(&mut self, data: Option<&[u8]>) {
+        match data {
+            Some(data) if data.len() == 0x2000 => self.game = Some(data.to_vec()),
+            Some(data) => warn!("invalid XEGS game size: {}", data.len()),
+            None => self.game = None,
+        }
+        self.setup_memory_banks();
     }
 
     pub fn set_basic(&mut self, data: Option<&[u8]>) {
@@ -328,6 +582,10 @@ impl AtariSystem {
             *b = self.read(offs.wrapping_add(i as u16));
         }
     }
+    #[inline(always)]
+    pub fn antic_read(&mut self, addr: u16) -> u8 {
+        self._read(addr, true)
+    }
     pub fn antic_copy_to_slice(&mut self, offs: u16, data: &mut [u8]) {
         for (i, b) in data.iter_mut().enumerate() {
             *b = self._read(offs.wrapping_add(i as u16), true);
@@ -339,8 +597,19 @@ impl AtariSystem {
     }
 
     pub fn load_atari800_state(&mut self, atari800_state: &Atari800State) {
+        let header = atari800_state.atari800;
+        let machine = header.machine_profile(atari800_state.memory.num_xe_banks);
+        self.set_machine_profile(machine, header.keyboard_detached());
+        if header.is_pal() != !self.antic.ntsc {
+            warn!("state video standard differs from the configured one");
+        }
+        if header.builtin_game() {
+            self.set_game(Some(atari800_state.memory.xegame));
+        }
+        self.basic_enabled = header.builtin_basic();
         self.pia.set_portb_out(atari800_state.memory.portb);
         self.ram[0..0x10000].copy_from_slice(atari800_state.memory.data);
+        self.charset_cache.clear();
         // self.ram2.copy_from_slice(atari800_state.memory.under_atarixl_os);
         self.osrom.copy_from_slice(atari800_state.memory.os);
         self.basic = Some(vec![0; 8192]);
@@ -421,7 +690,57 @@ impl AtariSystem {
         );
     }
 
+    /// Requests IRQ, serviced after the current CPU instruction
+    pub fn request_irq(&mut self, cpu: &mut MOS6502) {
+        self.irq_requested = true;
+        cpu.interrupt_request();
+    }
+
+    /// Requests NMI, serviced after the current CPU instruction
+    pub fn request_nmi(&mut self, cpu: &mut MOS6502) {
+        self.nmi_requested = true;
+        cpu.non_maskable_interrupt_request();
+    }
+
+    /// Executes single CPU cycle
+    #[inline(always)]
+    pub fn cpu_cycle(&mut self, cpu: &mut MOS6502) {
+        if self.cpu_stall > 0 {
+            // instruction was executed before the state was saved
+            self.cpu_stall -= 1;
+            return;
+        }
+        if cpu.get_remaining_cycles() == 0 {
+            // requested interrupts are serviced before the next instruction
+            self.irq_requested = false;
+            self.nmi_requested = false;
+        }
+        cpu.cycle(self);
+    }
+
+    /// Cycles left to finish the current CPU instruction
+    #[inline(always)]
+    pub fn cpu_remaining_cycles(&self, cpu: &MOS6502) -> u8 {
+        self.cpu_stall.max(cpu.get_remaining_cycles())
+    }
+
+    /// Restores CPU state not kept by MOS6502, called after state is loaded
+    pub fn restore_cpu(&mut self, cpu: &mut MOS6502, remaining_cycles: u8) {
+        self.cpu_stall = remaining_cycles;
+        if self.irq_requested {
+            cpu.interrupt_request();
+        }
+        if self.nmi_requested {
+            cpu.non_maskable_interrupt_request();
+        }
+    }
+
     pub fn reset(&mut self, cpu: &mut MOS6502, cold: bool, disable_basic: bool) {
+        if !cold && self.machine == MachineProfile::Atari800 {
+            // RESET key of 400/800 raises NMI instead of resetting CPU
+            self.antic.press_reset_key();
+            return;
+        }
         let disable_basic = disable_basic || self.basic.is_none();
         self.write(0xd301, 0xff); // turn on osrom
         info!(
@@ -431,121 +750,309 @@ impl AtariSystem {
         if cold {
             self.write(0x244, 255);
         }
+        let (ntsc, light_pen) = (self.antic.ntsc, self.antic.light_pen);
         self.antic = Antic::default();
+        self.antic.ntsc = ntsc;
+        self.antic.light_pen = light_pen;
+        // timer underflows and serial transfers are scheduled by total_cycles
+        self.pokey.reset_timers();
+        self.sio = SioBus::default();
         cpu.reset(self);
+        self.irq_requested = false;
+        self.nmi_requested = false;
+        self.cpu_stall = 0;
         self.ticks = 0;
         self.gtia.consol_force_mask = if disable_basic { 0x03 } else { 0x07 };
+        self.basic_enabled = !disable_basic;
+        self.setup_memory_banks();
         if let Some(cart) = &mut self.cart {
             cart.reset();
         }
     }
 
     pub fn update_consol(&mut self, index: usize, value: u8) {
+        if self.movie.is_playing() {
+            return;
+        }
+        if self.consol.get_input(index) != value {
+            let event = InputEvent::Consol {
+                input: index,
+                value,
+            };
+            self.movie.record(self.antic.total_cycles, event);
+        }
+        self.set_consol_input(index, value);
+    }
+
+    pub fn set_consol_input(&mut self, index: usize, value: u8) {
         self.consol.set_input(index, value);
         self.gtia.consol = !self.consol.get_output() & 7;
     }
 
+    /// Types text, see `keystrokes::parse` for the syntax
     pub fn keystrokes(&mut self, text: &str) {
-        for c in text.chars() {
-            if (c as u32) < 10 {
-                for _ in 0..(c as u32 + 1) * 50 {
-                    self.keycodes.push(None);
-                }
-                continue;
-            }
-            let mut codes = char_to_keycodes(c).to_owned();
-            if c.is_ascii_uppercase() {
-                codes.insert(0, KeyCode::LShift);
-            }
-            for c in &codes {
-                self.keycodes.push(Some((*c, true)));
-            }
-            for c in &codes {
-                self.keycodes.push(Some((*c, false)));
-                self.keycodes.push(None);
-                self.keycodes.push(None);
-            }
-            if c == '\n' {
-                for _ in 0..16 {
-                    self.keycodes.push(None);
-                }
+        if self.movie.is_playing() {
+            return;
+        }
+        if let Err(err) = self.push_keystrokes(text) {
+            warn!("invalid keystrokes: {}", err);
+            return;
+        }
+        let event = InputEvent::KeyStrokes(text.to_string());
+        self.movie.record(self.antic.total_cycles, event);
+    }
+
+    pub fn push_keystrokes(&mut self, text: &str) -> Result<(), String> {
+        self.strokes.extend(keystrokes::parse(text)?);
+        Ok(())
+    }
+
+    /// Drops pending keystrokes, releasing keys pressed by them
+    pub fn clear_keystrokes(&mut self) {
+        self.strokes.clear();
+        self.typed_controls = Controls::default();
+        self.pokey.type_key(None);
+    }
+
+    /// OS completed cold start (COLDST) and keyboard buffer (CH) is empty
+    fn is_ready_for_keystrokes(&mut self) -> bool {
+        self.read(0x244) == 0 && self.read(0x2fc) == 0xff
+    }
+
+    fn type_keystrokes(&mut self, fps: usize) {
+        // typing starts ~2 seconds after reset
+        let mut stroke = match self.strokes.front() {
+            Some(stroke) if self.ticks >= 15600 * 2 => *stroke,
+            _ => return,
+        };
+        if let Stroke::WaitMs(ms) = stroke {
+            stroke = Stroke::Wait((ms as usize * fps + 500) / 1000);
+            self.strokes[0] = stroke;
+        }
+        match stroke {
+            Stroke::Wait(frames) if frames > 1 => {
+                self.strokes[0] = Stroke::Wait(frames - 1);
+                return;
             }
+            Stroke::UntilReady if !self.is_ready_for_keystrokes() => return,
+            _ => (),
+        }
+        self.strokes.pop_front();
+        match stroke {
+            Stroke::Key(kbcode) => self.pokey.type_key(kbcode),
+            Stroke::Consol(consol) => self.typed_controls.consol = consol,
+            Stroke::Break(pressed) => self.typed_controls.break_key = pressed,
+            Stroke::Wait(_) | Stroke::WaitMs(_) | Stroke::UntilReady => (),
         }
     }
 
     pub fn handle_keyboard(
         &mut self,
-        keyboard: &mut ResMut<Input<KeyCode>>,
+        keyboard: &mut Input<KeyCode>,
         cpu: &mut MOS6502,
         config: &EmulatorConfig,
     ) -> bool {
-        if !self.keycodes.is_empty() && self.ticks >= 15600 * 2 {
-            keyboard.clear();
-            if let Some((keycode, pressed)) = self.keycodes.remove(0) {
-                if pressed {
-                    keyboard.press(keycode);
-                } else {
-                    keyboard.release(keycode);
-                }
-            }
-        }
+        movie::handle_keyboard(self, keyboard, |atari_system, keyboard| {
+            atari_system.scan_keyboard(keyboard, cpu, config)
+        })
+    }
 
-        let mut irq = false;
-        let start = keyboard.pressed(KeyCode::F2);
-        let select = keyboard.pressed(KeyCode::F3);
-        let option = keyboard.pressed(KeyCode::F4);
-        self.update_consol(0, (start as u8) | (select as u8) << 1 | (option as u8) << 2);
+    fn scan_keyboard(
+        &mut self,
+        keyboard: &mut Input<KeyCode>,
+        _cpu: &mut MOS6502,
+        config: &EmulatorConfig,
+    ) -> bool {
+        self.type_keystrokes(config.video_standard.fps());
+
+        if self.machine == MachineProfile::Atari5200 {
+            return self.scan_keypad(keyboard, config);
+        }
 
+        let is_alt = keyboard.pressed(KeyCode::LAlt) || keyboard.pressed(KeyCode::RAlt);
         let is_shift = keyboard.pressed(KeyCode::LShift) || keyboard.pressed(KeyCode::RShift);
         let is_ctl = keyboard.pressed(KeyCode::LControl) || keyboard.pressed(KeyCode::RControl);
-        let mut joy_changed = false;
-        for ev in keyboard.get_just_pressed() {
-            if config.arrows_joystick {
-                joy_changed = joy_changed
-                    || *ev == KeyCode::LShift
-                    || *ev == KeyCode::RShift
-                    || *ev == KeyCode::Up
-                    || *ev == KeyCode::Down
-                    || *ev == KeyCode::Left
-                    || *ev == KeyCode::Right;
-            }
-            if !joy_changed {
-                irq = irq || self.pokey.key_press(ev, true, is_shift, is_ctl, config);
+        self.pokey.set_modifiers(is_shift, is_ctl);
+        for ev in keyboard.get_just_released() {
+            self.pokey.key_press(ev, false, is_alt, config);
+        }
+
+        let mut controls = Controls::default();
+        // keys held since the state was loaded are pressed too
+        for ev in keyboard.get_pressed() {
+            let target = match self.input_mapping.keys.get(ev) {
+                Some(target) => *target,
+                None => match default_target(*ev, is_alt, config) {
+                    Some(target) => target,
+                    None => {
+                        // characters of symbolic keyboard come from text input,
+                        // except of control combinations (AltGr may be reported as Ctrl+Alt)
+                        let typed = config.symbolic_keyboard
+                            && !(is_ctl && !is_alt)
+                            && pokey::is_symbol_key(*ev);
+                        if !self.keyboard_detached && !typed {
+                            self.pokey.key_press(ev, true, is_alt, config);
+                        }
+                        continue;
+                    }
+                },
+            };
+            match target {
+                Target::Key(kbcode) if !self.keyboard_detached => {
+                    self.pokey.mapped_key_press(ev, kbcode);
+                }
+                target => controls.apply(target),
             }
         }
+        let break_key = controls.break_key && !self.keyboard_detached;
+        self.pokey
+            .set_break_key(break_key || self.typed_controls.break_key);
+        self.set_consol_input(0, controls.consol | self.typed_controls.consol);
+        for port in 0..2 {
+            self.set_joystick_input(2, port, controls.joystick(port));
+        }
+        // key IRQs are raised by POKEY keyboard scan
+        false
+    }
 
+    fn scan_keypad(&mut self, keyboard: &mut Input<KeyCode>, config: &EmulatorConfig) -> bool {
+        let mut irq = false;
+        for ev in keyboard.get_just_pressed() {
+            irq = self.pokey.keypad_press(ev, true) || irq;
+        }
         for ev in keyboard.get_just_released() {
-            if config.arrows_joystick {
-                joy_changed = joy_changed
-                    || *ev == KeyCode::LShift
-                    || *ev == KeyCode::RShift
-                    || *ev == KeyCode::Up
-                    || *ev == KeyCode::Down
-                    || *ev == KeyCode::Left
-                    || *ev == KeyCode::Right;
-            }
-            if !joy_changed {
-                self.pokey.key_press(ev, false, is_shift, is_ctl, config);
-            }
+            self.pokey.keypad_press(ev, false);
         }
-        if joy_changed {
+        if config.arrows_joystick {
             let fire = keyboard.pressed(KeyCode::LShift) || keyboard.pressed(KeyCode::RShift);
             let up = keyboard.pressed(KeyCode::Up) as u8;
             let down = keyboard.pressed(KeyCode::Down) as u8 * 2;
             let left = keyboard.pressed(KeyCode::Left) as u8 * 4;
             let right = keyboard.pressed(KeyCode::Right) as u8 * 8;
-            self.set_joystick(2, 0, up | down | left | right, fire);
-            irq = false;
+            self.set_joystick_input(2, 0, up | down | left | right | (fire as u8) << 4);
         }
-        return irq && self.pokey.irqen.contains(pokey::IRQ::KEY);
+        irq && self.pokey.irqen.contains(pokey::IRQ::KEY)
     }
 
     pub fn set_joystick(&mut self, input: usize, port: usize, dirs: u8, fire: bool) {
-        self.joystick[port].set_input(input, dirs | (fire as u8) << 4);
+        if self.movie.is_playing() {
+            return;
+        }
+        let value = dirs | (fire as u8) << 4;
+        if self.joystick[port].get_input(input) != value {
+            let event = InputEvent::Joystick { input, port, value };
+            self.movie.record(self.antic.total_cycles, event);
+        }
+        self.set_joystick_input(input, port, value);
+    }
+
+    pub fn set_joystick_input(&mut self, input: usize, port: usize, value: u8) {
+        self.joystick[port].set_input(input, value);
+        self.update_porta();
         let ports = [self.joystick[0].get_output(), self.joystick[1].get_output()];
-        self.pia
-            .set_port_a_input(0, (ports[0] & 0xf | (ports[1] & 0xf) << 4) ^ 0xff);
         self.gtia.set_trig(port, (ports[port] & 0x10) > 0);
+        if self.machine == MachineProfile::Atari5200 {
+            // digital joystick emulated on analog 5200 stick pots
+            let axis = |neg: bool, pos: bool| match (neg, pos) {
+                (true, false) => pokey::POT_5200_MIN,
+                (false, true) => pokey::POT_5200_MAX,
+                _ => pokey::POT_5200_CENTER,
+            };
+            let dirs = ports[port];
+            self.pokey
+                .set_pot(port * 2, axis(dirs & 4 > 0, dirs & 8 > 0));
+            self.pokey
+                .set_pot(port * 2 + 1, axis(dirs & 1 > 0, dirs & 2 > 0));
+        }
+    }
+
+    pub fn set_paddle(&mut self, index: usize, value: u8, fire: bool) {
+        if self.movie.is_playing() {
+            return;
+        }
+        if self.paddles[index] != (value, fire) {
+            let event = InputEvent::Paddle { index, value, fire };
+            self.movie.record(self.antic.total_cycles, event);
+        }
+        self.set_paddle_input(index, value, fire);
+    }
+
+    pub fn set_paddle_input(&mut self, index: usize, value: u8, fire: bool) {
+        self.paddles[index] = (value, fire);
+        // 5200 pots are driven by joysticks
+        if self.machine != MachineProfile::Atari5200 {
+            self.pokey.set_pot(index, value);
+        }
+        self.update_porta();
+    }
+
+    /// light pen / light gun position as ANTIC color clock and scanline
+    pub fn set_light_pen(&mut self, position: Option<(u8, usize)>) {
+        if self.movie.is_playing() {
+            return;
+        }
+        if self.antic.light_pen != position {
+            let event = InputEvent::LightPen(position);
+            self.movie.record(self.antic.total_cycles, event);
+        }
+        self.set_light_pen_input(position);
+    }
+
+    pub fn set_light_pen_input(&mut self, position: Option<(u8, usize)>) {
+        self.antic.light_pen = position;
+    }
+
+    fn update_porta(&mut self) {
+        let ports = [self.joystick[0].get_output(), self.joystick[1].get_output()];
+        // paddle triggers share PORTA lines with joystick left / right
+        let triggers = self
+            .paddles
+            .iter()
+            .zip([0x04, 0x08, 0x40, 0x80])
+            .fold(0, |v, ((_, fire), bit)| v | if *fire { bit } else { 0 });
+        let dirs = ports[0] & 0xf | (ports[1] & 0xf) << 4;
+        self.pia.set_port_a_input(0, (dirs | triggers) ^ 0xff);
+    }
+
+    /// current state of all joystick, paddle and console inputs
+    pub fn input_events(&self) -> impl Iterator<Item = InputEvent> + '_ {
+        let joystick = self.joystick.iter().enumerate().flat_map(|(port, mux)| {
+            (0..mux.size()).map(move |input| InputEvent::Joystick {
+                input,
+                port,
+                value: mux.get_input(input),
+            })
+        });
+        let consol = (0..self.consol.size()).map(move |input| InputEvent::Consol {
+            input,
+            value: self.consol.get_input(input),
+        });
+        let paddles = self
+            .paddles
+            .iter()
+            .enumerate()
+            .map(|(index, (value, fire))| InputEvent::Paddle {
+                index,
+                value: *value,
+                fire: *fire,
+            });
+        let light_pen = std::iter::once(InputEvent::LightPen(self.antic.light_pen));
+        joystick.chain(consol).chain(paddles).chain(light_pen)
+    }
+
+    pub fn reset_inputs(&mut self) {
+        let events = self.input_events().collect::<Vec<_>>();
+        for event in events {
+            match event {
+                InputEvent::Joystick { input, port, .. } => self.set_joystick_input(input, port, 0),
+                InputEvent::Consol { input, .. } => self.set_consol_input(input, 0),
+                InputEvent::Paddle { index, .. } => {
+                    self.set_paddle_input(index, pokey::POT_MAX, false)
+                }
+                InputEvent::LightPen(_) => self.set_light_pen_input(None),
+                _ => (),
+            }
+        }
     }
 
     #[inline(always)]
@@ -560,7 +1067,21 @@ impl AtariSystem {
     #[inline(always)]
     pub fn inc_cycle(&mut self) {
         self.antic.inc_cycle();
-        self.pokey_reg_queue.borrow_mut().total_cycles = self.antic.total_cycles;
+        let now = self.antic.total_cycles;
+        self.pokey_reg_queue.borrow_mut().total_cycles = now;
+        if now >= self.pokey.next_event() || now >= self.sio.next_byte_at {
+            self.pokey_tick(now);
+        }
+    }
+
+    fn pokey_tick(&mut self, now: u64) {
+        let byte_cycles = self.pokey.serial_byte_cycles();
+        if let Some(byte) = self.pokey.tick(now) {
+            self.sio.receive(byte, now, byte_cycles, &mut self.disks);
+        }
+        if let Some(byte) = self.sio.transmit(now, byte_cycles) {
+            self.pokey.serial_in(byte);
+        }
     }
     pub fn set_disk(&mut self, drive: usize, atr: Option<ATR>) {
         info!("set_disk #{}: {:?}", drive, atr.is_some());
@@ -611,6 +1132,86 @@ impl AtariSystem {
     }
 }
 
+impl SaveState for AtariSystem {
+    fn save_state(&self, writer: &mut StateWriter) {
+        writer.str(&format!("{:?}", self.machine));
+        writer.str(&format!("{:?}", self.memory_model));
+        writer.blob(&self.ram);
+        self.pia.save_state(writer);
+        self.antic.save_state(writer);
+        self.gtia.save_state(writer);
+        self.pokey.save_state(writer);
+        self.sio.save_state(writer);
+        writer.usize(self.ticks);
+        writer.bool(self.irq_requested);
+        writer.bool(self.nmi_requested);
+        writer.usize(self.cart.as_ref().map(|cart| cart.bank()).unwrap_or(0));
+        // only modified disks are stored, unmodified ones are expected to be attached
+        for disk in self.disks.iter() {
+            match disk {
+                Some(atr) if atr.is_modified() => {
+                    writer.bool(true);
+                    writer.str(atr.path());
+                    writer.blob(atr.data());
+                }
+                _ => writer.bool(false),
+            }
+        }
+    }
+
+    fn load_state(&mut self, reader: &mut StateReader) -> Result<(), String> {
+        // ROMs and memory layout are not stored, so they have to match
+        let machine = reader.str()?;
+        if machine != format!("{:?}", self.machine) {
+            return Err(format!(
+                "state of {} machine, configured {:?}",
+                machine, self.machine
+            ));
+        }
+        let memory_model = reader.str()?;
+        if memory_model != format!("{:?}", self.memory_model) {
+            return Err(format!(
+                "state of {} memory model, configured {:?}",
+                memory_model, self.memory_model
+            ));
+        }
+        let ram = reader.blob()?;
+        if ram.len() != self.ram.len() {
+            return Err(format!(
+                "invalid RAM size: {}, expected {} for {:?} memory model",
+                ram.len(),
+                self.ram.len(),
+                self.memory_model
+            ));
+        }
+        self.ram.copy_from_slice(&ram);
+        self.pia.load_state(reader)?;
+        self.antic.load_state(reader)?;
+        self.gtia.load_state(reader)?;
+        self.pokey.load_state(reader)?;
+        self.sio.load_state(reader)?;
+        self.ticks = reader.usize()?;
+        self.irq_requested = reader.bool()?;
+        self.nmi_requested = reader.bool()?;
+        let cart_bank = reader.usize()?;
+        if let Some(cart) = &mut self.cart {
+            cart.set_bank(cart_bank);
+        }
+        for disk in self.disks.iter_mut() {
+            if reader.bool()? {
+                let path = reader.str()?;
+                let data = reader.blob()?;
+                let mut atr = ATR::new(&path, &data)?;
+                atr.set_modified(true);
+                *disk = Some(atr);
+            }
+        }
+        self.pokey_reg_queue.borrow_mut().total_cycles = self.antic.total_cycles;
+        self.setup_memory_banks();
+        Ok(())
+    }
+}
+
 impl Default for AtariSystem {
     fn default() -> Self {
         AtariSystem::new()
@@ -628,77 +1229,21 @@ impl Interface6502 for AtariSystem {
     }
 }
 
-fn char_to_keycodes(c: char) -> &'static [KeyCode] {
-    let c = c.to_ascii_uppercase();
-    match c {
-        '\u{1f}' => &[KeyCode::Capital],
-        'A' => &[KeyCode::A],
-        'B' => &[KeyCode::B],
-        'C' => &[KeyCode::C],
-        'D' => &[KeyCode::D],
-        'E' => &[KeyCode::E],
-        'F' => &[KeyCode::F],
-        'G' => &[KeyCode::G],
-        'H' => &[KeyCode::H],
-        'I' => &[KeyCode::I],
-        'J' => &[KeyCode::J],
-        'K' => &[KeyCode::K],
-        'L' => &[KeyCode::L],
-        'M' => &[KeyCode::M],
-        'N' => &[KeyCode::N],
-        'O' => &[KeyCode::O],
-        'P' => &[KeyCode::P],
-        'Q' => &[KeyCode::Q],
-        'R' => &[KeyCode::R],
-        'S' => &[KeyCode::S],
-        'T' => &[KeyCode::T],
-        'U' => &[KeyCode::U],
-        'V' => &[KeyCode::V],
-        'W' => &[KeyCode::W],
-        'X' => &[KeyCode::X],
-        'Y' => &[KeyCode::Y],
-        'Z' => &[KeyCode::Z],
-        '0' => &[KeyCode::Key0],
-        '1' => &[KeyCode::Key1],
-        '2' => &[KeyCode::Key2],
-        '3' => &[KeyCode::Key3],
-        '4' => &[KeyCode::Key4],
-        '5' => &[KeyCode::Key5],
-        '6' => &[KeyCode::Key6],
-        '7' => &[KeyCode::Key7],
-        '8' => &[KeyCode::Key8],
-        '9' => &[KeyCode::Key9],
-        '\'' => &[KeyCode::Apostrophe],
-        '.' => &[KeyCode::Period],
-        ',' => &[KeyCode::Comma],
-        '*' => &[KeyCode::Asterisk],
-        '\n' => &[KeyCode::Return],
-        ';' => &[KeyCode::Colon],
-        '[' => &[KeyCode::LBracket],
-        ']' => &[KeyCode::RBracket],
-        ' ' => &[KeyCode::Space],
-        '+' => &[KeyCode::Plus],
-        '-' => &[KeyCode::Minus],
-        '_' => &[KeyCode::Underline],
-        '=' => &[KeyCode::Equals],
-        '/' => &[KeyCode::Slash],
-        '\\' => &[KeyCode::Backslash],
-        '<' => return &[KeyCode::LShift, KeyCode::Comma],
-        '>' => return &[KeyCode::LShift, KeyCode::Period],
-        '|' => return &[KeyCode::LShift, KeyCode::Backslash],
-        '!' => return &[KeyCode::LShift, KeyCode::Key1],
-        '@' => return &[KeyCode::LShift, KeyCode::Key2],
-        '#' => return &[KeyCode::LShift, KeyCode::Key3],
-        '$' => return &[KeyCode::LShift, KeyCode::Key4],
-        '%' => return &[KeyCode::LShift, KeyCode::Key5],
-        '^' => return &[KeyCode::LShift, KeyCode::Key6],
-        '&' => return &[KeyCode::LShift, KeyCode::Key7],
-        // '*' => return vec![KeyCode::LShift, KeyCode::Key8],
-        '(' => return &[KeyCode::LShift, KeyCode::Key9],
-        ')' => return &[KeyCode::LShift, KeyCode::Key0],
-        '?' => return &[KeyCode::LShift, KeyCode::Slash],
-        ':' => return &[KeyCode::LShift, KeyCode::Colon],
-        '"' => return &[KeyCode::LShift, KeyCode::Apostrophe],
-        _ => return &[],
-    }
+/// Built-in bindings of host keys to console keys, BREAK and joystick 1,
+/// used for keys not bound in the input mapping
+fn default_target(key: KeyCode, is_alt: bool, config: &EmulatorConfig) -> Option<Target> {
+    let joystick = config.arrows_joystick;
+    Some(match key {
+        // with Alt F2 - F4 are 1200XL function keys
+        KeyCode::F2 if !is_alt => Target::Start,
+        KeyCode::F3 if !is_alt => Target::Select,
+        KeyCode::F4 if !is_alt => Target::Option,
+        KeyCode::F7 => Target::Break,
+        KeyCode::Up if joystick => Target::Joystick(0, Direction::Up),
+        KeyCode::Down if joystick => Target::Joystick(0, Direction::Down),
+        KeyCode::Left if joystick => Target::Joystick(0, Direction::Left),
+        KeyCode::Right if joystick => Target::Joystick(0, Direction::Right),
+        KeyCode::LShift | KeyCode::RShift if joystick => Target::Fire(0),
+        _ => return None,
+    })
 }
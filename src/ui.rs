@@ -4,13 +4,15 @@ use bevy_egui::egui::InnerResponse;
 use bevy_egui::egui::RichText;
 use bevy_egui::EguiContext;
 
-use crate::config::{EmulatorConfig, GlobalEmulatorConfig};
-use crate::focus::Focused;
-use crate::resources::UIConfig;
 use crate::AtariSlot;
 use crate::Debugger;
 use crate::CPU;
-use crate::{system::AtariSystem, time_used_plugin::TimeUsedPlugin};
+use bevy_atari::config::{
+    EmulatorConfig, GlobalEmulatorConfig, MachineProfile, MemoryModel, VideoStandard,
+};
+use bevy_atari::focus::Focused;
+use bevy_atari::resources::UIConfig;
+use bevy_atari::{system::AtariSystem, time_used_plugin::TimeUsedPlugin};
 
 use bevy::{
     diagnostic::{Diagnostics, FrameTimeDiagnosticsPlugin},
@@ -50,10 +52,64 @@ fn show_config(
                     ui.checkbox(&mut emulator_config.arrows_force_ctl, "force Ctrl");
                     ui.checkbox(&mut emulator_config.arrows_neg_ctl, "negate Ctrl");
                 });
+                ui.group(|ui| {
+                    ui.label("Keyboard");
+                    ui.checkbox(
+                        &mut emulator_config.symbolic_keyboard,
+                        "symbolic (host layout)",
+                    );
+                });
+                ui.group(|ui| {
+                    ui.label("Controllers");
+                    ui.checkbox(&mut emulator_config.paddles, "paddles (mouse / gamepad)");
+                    ui.checkbox(&mut emulator_config.light_pen, "light pen / gun (mouse)");
+                });
+                ui.group(|ui| {
+                    ui.label("Video");
+                    let standard = &mut emulator_config.video_standard;
+                    ui.radio_value(standard, VideoStandard::Pal, "PAL");
+                    ui.radio_value(standard, VideoStandard::Ntsc, "NTSC");
+                });
+                ui.group(|ui| {
+                    ui.label("Machine");
+                    let machine = &mut emulator_config.machine;
+                    ui.radio_value(machine, MachineProfile::Atari800, "400/800");
+                    ui.radio_value(machine, MachineProfile::XL, "XL");
+                    ui.radio_value(machine, MachineProfile::XE, "XE");
+                    ui.radio_value(machine, MachineProfile::XEGS, "XEGS");
+                    ui.radio_value(machine, MachineProfile::Atari5200, "5200");
+                    if *machine == MachineProfile::XEGS {
+                        ui.checkbox(&mut emulator_config.keyboard_detached, "keyboard detached");
+                    }
+                });
+                ui.group(|ui| {
+                    ui.label("Memory");
+                    let memory = &mut emulator_config.memory;
+                    ui.radio_value(memory, MemoryModel::Mem64, "64k");
+                    ui.radio_value(memory, MemoryModel::Mem130XE, "130XE");
+                    ui.radio_value(memory, MemoryModel::Rambo320, "320k Rambo");
+                    ui.radio_value(memory, MemoryModel::Compy320, "320k Compy Shop");
+                    ui.radio_value(memory, MemoryModel::Mem576, "576k");
+                    ui.radio_value(memory, MemoryModel::Mem1088, "1088k");
+                });
+                ui.group(|ui| {
+                    ui.label("SIO");
+                    ui.checkbox(
+                        &mut emulator_config.sio_accelerator,
+                        "accelerated disk access",
+                    );
+                });
                 ui.group(|ui| {
                     ui.label("Sound");
                     ui.checkbox(&mut emulator_config.stereo, "stereo");
                 });
+                ui.group(|ui| {
+                    ui.label("Rewind (hold F6)");
+                    ui.add(
+                        egui::Slider::new(&mut emulator_config.rewind_seconds, 0..=60)
+                            .text("seconds"),
+                    );
+                });
             });
         })
 }
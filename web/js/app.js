@@ -4,7 +4,7 @@ import { SAPWriter } from './sap_writer.js'
 import { initFilesystem, mkdirs, readFile, writeFile, readDir, rm } from './fs.js'
 import { treeInit, treeShowPath } from './fs_tree.js'
 
-const BINARY_KEYS = ['disk_1', 'osrom', 'basic', 'car', 'xex'];
+const BINARY_KEYS = ['disk_1', 'osrom', 'bios5200', 'basic', 'game', 'car', 'xex'];
 const DEFAULT_OSROM_URL = "https://atarionline.pl/utils/9.%20ROM-y/Systemy%20operacyjne/Atari%20OS%20v2%2083.10.05.rom"
 const DEFAULT_BASIC_URL = "https://atarionline.pl/utils/9.%20ROM-y/Języki%20programowania/Atari%20BASIC/Atari%20Basic%20vB.rom"
 var sap_writer = null;
@@ -85,6 +85,11 @@ function pokey_post_message(msg) {
     sap_writer.handle_pokey_msg(msg)
 }
 
+function pokey_set_clock(clock) {
+  if (!pokeyNode) return;
+  pokeyNode.port.postMessage({clock: clock});
+}
+
 function parse_part(part) {
   let m = part.match("^(\\w+)(@(\\d+))?=(.*)");
   return m && [m[1], m[4], m[3]] || [null, part, null]
@@ -114,8 +119,10 @@ function set_binary(key, url, path, data, slot) {
   if (!key) {
     // guess type of binary
     if (ext == "rom" || ext == "bin") {
-      if (data.length == 0x4000) {
+      if (data.length == 0x4000 || data.length == 0x2800) {
         key = "osrom"
+      } else if (data.length == 0x800) {
+        key = "bios5200"
       } else if (data.length == 0x2000) {
         key = "basic"
       } else {
@@ -124,7 +131,7 @@ function set_binary(key, url, path, data, slot) {
       }
     } else if (ext == "state") {
       key = "state"
-    } else if (ext == "car") {
+    } else if (ext == "car" || ext == "a52") {
       key = "car"
     } else if (ext == "atr") {
       key = "disk_1"
@@ -249,6 +256,8 @@ async function reload_from_fragment() {
   for (let [key, url, slot] of parse_fragment()) {
     if(!key || BINARY_KEYS.indexOf(key) >= 0) {
       todo.push(fetch_binary_data(key, url, parseInt(slot)));
+    } else if(key == "mapping") {
+      todo.push(fetch_binary_data(key, url));
     } else if(key == "keystrokes") {
       keystrokes(decodeURIComponent(url))
     }
@@ -318,6 +327,7 @@ export async function run() {
     console.warn("audio_context.audioWorklet is undefined (serving through http?)");
   }
   window.pokey_post_message = pokey_post_message
+  window.pokey_set_clock = pokey_set_clock
   window.audio_context = audio_context
   window.cmd = cmd
 